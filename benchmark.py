@@ -77,18 +77,18 @@ def benchmark_mixed(iterations=1000):
     sock.close()
     return iterations / elapsed
 
-def benchmark_parallel(operations, thread_count=10):
+def benchmark_parallel(operations, thread_count=10, command=lambda thread, i: "PING"):
     """Run benchmarks in parallel threads"""
     latencies = []
     
-    def worker(ops_per_thread):
+    def worker(thread, ops_per_thread):
         sock = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
         sock.connect((HOST, PORT))
         
         local_latencies = []
         for i in range(ops_per_thread):
             start = time.time()
-            send_command(sock, f"PING")
+            send_command(sock, command(thread, i))
             elapsed = time.time() - start
             local_latencies.append(elapsed * 1000)  # Convert to ms
         
@@ -98,7 +98,7 @@ def benchmark_parallel(operations, thread_count=10):
     ops_per_thread = operations // thread_count
     
     with ThreadPoolExecutor(max_workers=thread_count) as executor:
-        futures = [executor.submit(worker, ops_per_thread) for _ in range(thread_count)]
+        futures = [executor.submit(worker, thread, ops_per_thread) for thread in range(thread_count)]
         for future in as_completed(futures):
             latencies.extend(future.result())
     
@@ -183,6 +183,21 @@ def main():
     except Exception as e:
         print(f"  Error: {e}")
     
+    # Independent keys land on different shards, so threads shouldn't queue
+    # behind each other
+    print("\nThroughput test (SET/GET on distinct keys, 10 threads, 1000 ops each)...")
+    
+    def set_or_get(thread, i):
+        key = f"benchmark:parallel:{thread}:{i // 2}"
+        return f"SET {key} value" if i % 2 == 0 else f"GET {key}"
+    
+    try:
+        start = time.time()
+        benchmark_parallel(10000, thread_count=10, command=set_or_get)
+        print(f"  Result: {10000 / (time.time() - start):,.0f} req/sec")
+    except Exception as e:
+        print(f"  Error: {e}")
+    
     # Cleanup
     print("\n" + "=" * 60)
     cleanup()
@@ -1,500 +1,7534 @@
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::time::{Instant, Duration, SystemTime, UNIX_EPOCH};
-use serde::{Serialize, Deserialize};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{Notify, mpsc, watch};
 
-// Serializable entry for persistence
-#[derive(Clone, Serialize, Deserialize)]
+// Entry as stored in JSON snapshots, which predate the binary format and are
+// still loaded
+#[derive(Deserialize)]
 struct SerializableEntry {
     value: SerializableValue,
     #[serde(with = "option_duration")]
     expires_in_secs: Option<u64>, // Store relative time instead of Instant
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Deserialize)]
 enum SerializableValue {
-    String(String),
-    List(Vec<String>),
+    String(StoredBytes),
+    List(Vec<StoredBytes>),
+    Hash(Vec<(StoredBytes, StoredBytes)>),
+    Set(Vec<StoredBytes>),
+    // Scores are kept as text so that +inf/-inf survive JSON
+    ZSet(Vec<(StoredBytes, String)>),
+}
+
+// JSON snapshots hold byte strings as strings when they are valid UTF-8 and
+// as arrays of bytes otherwise
+struct StoredBytes(Vec<u8>);
+
+impl<'de> Deserialize<'de> for StoredBytes {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct BytesVisitor;
+        
+        impl<'de> serde::de::Visitor<'de> for BytesVisitor {
+            type Value = StoredBytes;
+            
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a string or an array of bytes")
+            }
+            
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<StoredBytes, E> {
+                Ok(StoredBytes(v.as_bytes().to_vec()))
+            }
+            
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<StoredBytes, E> {
+                Ok(StoredBytes(v.to_vec()))
+            }
+            
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<StoredBytes, A::Error> {
+                let mut bytes = Vec::new();
+                while let Some(b) = seq.next_element::<u8>()? {
+                    bytes.push(b);
+                }
+                Ok(StoredBytes(bytes))
+            }
+        }
+        
+        d.deserialize_any(BytesVisitor)
+    }
 }
 
 #[derive(Clone)]
 enum Value {
-    String(String),
-    List(Vec<String>),
+    String(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+    ZSet(ZSet),
+    HyperLogLog(HyperLogLog),
+    Stream(Stream),
 }
 
-struct Entry {
-    value: Value,
-    expires_at: Option<Instant>,
+impl Value {
+    // The name TYPE reports and SCAN's TYPE option filters on
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            // Redis keeps HyperLogLogs in strings, so that is what TYPE says
+            Value::HyperLogLog(_) => "string",
+            Value::Stream(_) => "stream",
+        }
+    }
+    
+    // Number of elements in a collection; None for a string
+    fn elements(&self) -> Option<usize> {
+        match self {
+            Value::String(_) => None,
+            Value::List(items) => Some(items.len()),
+            Value::Hash(fields) => Some(fields.len()),
+            Value::Set(members) => Some(members.len()),
+            Value::ZSet(zset) => Some(zset.len()),
+            Value::HyperLogLog(_) => None,
+            Value::Stream(stream) => Some(stream.entries.len()),
+        }
+    }
+    
+    // The representation Redis would pick for this value, as OBJECT ENCODING
+    // reports it. Small collections of short elements would be packed into
+    // one listpack; past the thresholds they get a real data structure.
+    fn encoding(&self) -> &'static str {
+        fn compact<'a>(len: usize, mut elements: impl Iterator<Item = &'a Vec<u8>>) -> bool {
+            len <= COMPACT_MAX_ENTRIES && elements.all(|element| element.len() <= COMPACT_MAX_VALUE)
+        }
+        
+        match self {
+            Value::String(s) if s.len() <= 20 && is_canonical_integer(s) => "int",
+            Value::String(s) if s.len() <= 44 => "embstr",
+            Value::String(_) => "raw",
+            Value::List(items) if list_fits_listpack(items) => "listpack",
+            Value::List(_) => "quicklist",
+            Value::Hash(fields) if compact(fields.len(), fields.iter().flat_map(|(f, v)| [f, v])) => "listpack",
+            Value::Hash(_) => "hashtable",
+            Value::Set(members) if members.len() <= 512 && members.iter().all(|m| is_canonical_integer(m)) => "intset",
+            Value::Set(members) if compact(members.len(), members.iter()) => "listpack",
+            Value::Set(_) => "hashtable",
+            Value::ZSet(zset) if compact(zset.scores.len(), zset.scores.keys()) => "listpack",
+            Value::ZSet(_) => "skiplist",
+            Value::HyperLogLog(_) => "raw",
+            Value::Stream(_) => "stream",
+        }
+    }
+    
+    // References to the value, as OBJECT REFCOUNT reports them: shared
+    // integers are never freed, which Redis marks with INT_MAX
+    fn refcount(&self) -> i64 {
+        match self {
+            Value::String(s) if is_shared_integer(s) => i32::MAX as i64,
+            _ => 1,
+        }
+    }
 }
 
-type Store = Arc<Mutex<HashMap<String, Entry>>>;
+// Limits under which OBJECT ENCODING reports a hash, set or sorted set as
+// compact; lists follow list-max-listpack-size
+const COMPACT_MAX_ENTRIES: usize = 128;
+const COMPACT_MAX_VALUE: usize = 64;
 
-// Custom serialization for Option<Duration>
-mod option_duration {
-    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+// Whether a list would fit in the one listpack list-max-listpack-size
+// allows: a positive setting counts entries, -1 to -5 cap its bytes at 4 to
+// 64 KB. Each entry costs an encoding header, the data and a back length,
+// on top of the listpack's 7 bytes of header and terminator.
+fn list_fits_listpack(items: &[Vec<u8>]) -> bool {
+    let limit = config().list_max_listpack_size;
+    if limit > 0 {
+        return items.len() as u64 <= limit as u64;
+    }
+    let max_bytes = 4096usize << (-limit - 1);
+    let mut bytes = 7;
+    for item in items {
+        let header = match item.len() {
+            0..64 => 1,
+            64..4096 => 2,
+            _ => 5,
+        };
+        let entry = header + item.len();
+        bytes += entry + match entry {
+            0..128 => 1,
+            128..16384 => 2,
+            _ => 3,
+        };
+        if bytes > max_bytes {
+            return false;
+        }
+    }
+    true
+}
 
-    pub fn serialize<S: Serializer>(v: &Option<u64>, s: S) -> Result<S::Ok, S::Error> {
-        v.serialize(s)
+// Redis keeps a single copy of the integers 0..SHARED_INTEGERS and points
+// every string holding one at it, instead of allocating per key
+const SHARED_INTEGERS: i64 = 10000;
+
+fn is_shared_integer(bytes: &[u8]) -> bool {
+    bytes.len() <= 4 && is_canonical_integer(bytes) && parse_num::<i64>(bytes).is_some_and(|n| (0..SHARED_INTEGERS).contains(&n))
+}
+
+// Whether the bytes are an i64 written the way Redis would print it (no
+// sign or leading zeros it wouldn't produce), so it could be stored as one
+fn is_canonical_integer(bytes: &[u8]) -> bool {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .is_some_and(|n| n.to_string().as_bytes() == bytes)
+}
+
+// Total ordering for scores so they can key a BTreeSet
+#[derive(Clone, Copy, PartialEq)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
     }
+}
 
-    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
-        Option::<u64>::deserialize(d)
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0)
     }
 }
 
-fn main() {
-    let store: Store = Arc::new(Mutex::new(HashMap::new()));
+// Sorted set: member -> score lookups plus an index ordered by score, then
+// lexicographically by member
+#[derive(Clone, Default)]
+struct ZSet {
+    scores: HashMap<Vec<u8>, f64>,
+    ordered: BTreeSet<(Score, Vec<u8>)>,
+}
+
+impl ZSet {
+    // Returns true if the member is new
+    fn insert(&mut self, member: Vec<u8>, score: f64) -> bool {
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            self.ordered.remove(&(Score(old), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        old.is_none()
+    }
     
-    // Try to load existing data
-    load_data(&store, "redrust.rdb");
+    fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.ordered.remove(&(Score(score), member.to_vec()));
+                true
+            }
+            None => false,
+        }
+    }
     
-    // Cleanup thread for expired keys
-    let cleanup_store = Arc::clone(&store);
-    std::thread::spawn(move || {
-        loop {
-            std::thread::sleep(Duration::from_secs(1));
-            cleanup_expired(&cleanup_store);
+    fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+    
+    fn len(&self) -> usize {
+        self.scores.len()
+    }
+    
+    fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+    
+    // Members with their scores, lowest score first
+    fn iter(&self) -> impl DoubleEndedIterator<Item = (&Vec<u8>, f64)> {
+        self.ordered.iter().map(|(score, member)| (member, score.0))
+    }
+    
+    // Members whose score falls between the two bounds, lowest score first
+    fn range_by_score(&self, min: ScoreBound, max: ScoreBound) -> impl Iterator<Item = (&Vec<u8>, f64)> {
+        self.ordered
+            .range((Score(min.value), Vec::new())..)
+            .map(|(score, member)| (member, score.0))
+            .skip_while(move |(_, score)| !min.admits_from_below(*score))
+            .take_while(move |(_, score)| max.admits_from_above(*score))
+    }
+}
+
+// HyperLogLog with 2^14 one-byte registers, as in Redis: each element
+// hashes to a register, which keeps the longest run of zero bits seen after
+// the index bits. The estimate has a standard error of about 0.81%.
+#[derive(Clone)]
+struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+const HLL_P: u32 = 14;
+const HLL_REGISTERS: usize = 1 << HLL_P;
+// Hash bits left once the register index is taken
+const HLL_Q: usize = 64 - HLL_P as usize;
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog { registers: vec![0; HLL_REGISTERS] }
+    }
+}
+
+impl HyperLogLog {
+    // Returns true if a register changed, so the estimate may have too
+    fn add(&mut self, element: &[u8]) -> bool {
+        let hash = murmur_hash64a(element, 0xadc83b19);
+        let index = hash as usize & (HLL_REGISTERS - 1);
+        // The sentinel bit caps the run at HLL_Q zeros
+        let run = ((hash >> HLL_P) | 1 << HLL_Q).trailing_zeros() as u8 + 1;
+        if run > self.registers[index] {
+            self.registers[index] = run;
+            true
+        } else {
+            false
         }
-    });
+    }
     
-    let listener = TcpListener::bind("127.0.0.1:6379").expect("Failed to bind");
-    println!("🦀 RedRust listening on 127.0.0.1:6379");
-    println!("   Commands: SET, GET, DEL, KEYS, EXPIRE, TTL, TYPE, PING");
-    println!("   Lists: LPUSH, RPUSH, LPOP, RPOP, LLEN, LRANGE");
-    println!("   Persistence: SAVE, BGSAVE, LASTSAVE");
+    fn merge(&mut self, other: &HyperLogLog) {
+        for (register, &theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(theirs);
+        }
+    }
     
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let store = Arc::clone(&store);
-                std::thread::spawn(|| handle_client(stream, store));
-            }
-            Err(e) => eprintln!("Error: {}", e),
+    // Ertl's improved estimator ("New cardinality estimation algorithms for
+    // HyperLogLog sketches"), which Redis also uses. It corrects the bias at
+    // both ends of the range without empirical tables.
+    fn count(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let mut histogram = [0u32; HLL_Q + 2];
+        for &register in &self.registers {
+            histogram[register as usize] += 1;
+        }
+        let mut z = m * hll_tau((m - histogram[HLL_Q + 1] as f64) / m);
+        for k in (1..=HLL_Q).rev() {
+            z += histogram[k] as f64;
+            z *= 0.5;
         }
+        z += m * hll_sigma(histogram[0] as f64 / m);
+        (0.5 / std::f64::consts::LN_2 * m * m / z).round() as u64
     }
 }
 
-fn cleanup_expired(store: &Store) {
-    let mut db = store.lock().unwrap();
-    let now = Instant::now();
-    let expired: Vec<String> = db
-        .iter()
-        .filter(|(_, entry)| {
-            entry.expires_at.map(|exp| exp <= now).unwrap_or(false)
-        })
-        .map(|(key, _)| key.clone())
-        .collect();
+fn hll_sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if z == previous {
+            return z;
+        }
+    }
+}
+
+fn hll_tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z == previous {
+            return z / 3.0;
+        }
+    }
+}
+
+// MurmurHash64A, the hash Redis feeds its HyperLogLogs. It must not change
+// between builds, since registers computed with it are saved to disk.
+fn murmur_hash64a(data: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (data.len() as u64).wrapping_mul(M);
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, &byte) in rest.iter().enumerate() {
+            h ^= (byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+// A stream entry's ID: a millisecond timestamp and a sequence number that
+// orders entries added within the same millisecond
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct StreamId {
+    ms: u64,
+    seq: u64,
+}
+
+impl StreamId {
+    // "<ms>-<seq>", or a bare "<ms>" taking `default_seq`
+    fn parse(arg: &[u8], default_seq: u64) -> Option<StreamId> {
+        let text = std::str::from_utf8(arg).ok()?;
+        let (ms, seq) = match text.split_once('-') {
+            Some((ms, seq)) => (ms.parse().ok()?, seq.parse().ok()?),
+            None => (text.parse().ok()?, default_seq),
+        };
+        Some(StreamId { ms, seq })
+    }
     
-    for key in expired {
-        db.remove(&key);
+    // One end of an XRANGE interval. "-" and "+" are the smallest and the
+    // largest IDs, a bare "<ms>" reaches across that whole millisecond, and
+    // a "(" prefix leaves the ID itself out.
+    fn parse_bound(arg: &[u8], start: bool) -> Result<StreamId, &'static str> {
+        let (exclusive, arg) = match arg.strip_prefix(b"(") {
+            Some(rest) => (true, rest),
+            None => (false, arg),
+        };
+        let id = match arg {
+            b"-" => StreamId::default(),
+            b"+" => StreamId { ms: u64::MAX, seq: u64::MAX },
+            _ => StreamId::parse(arg, if start { 0 } else { u64::MAX })
+                .ok_or("ERR Invalid stream ID specified as stream command argument")?,
+        };
+        if !exclusive {
+            return Ok(id);
+        }
+        let next = if start { id.seq.checked_add(1) } else { id.seq.checked_sub(1) };
+        let id = match next {
+            Some(seq) => Some(StreamId { ms: id.ms, seq }),
+            None if start => id.ms.checked_add(1).map(|ms| StreamId { ms, seq: 0 }),
+            None => id.ms.checked_sub(1).map(|ms| StreamId { ms, seq: u64::MAX }),
+        };
+        match id {
+            Some(id) if !matches!(arg, b"-" | b"+") => Ok(id),
+            _ if start => Err("ERR invalid start ID for the interval"),
+            _ => Err("ERR invalid end ID for the interval"),
+        }
     }
 }
 
-fn is_expired(entry: &Entry) -> bool {
-    entry.expires_at.map(|exp| exp <= Instant::now()).unwrap_or(false)
+impl std::fmt::Display for StreamId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
 }
 
-fn save_data(store: &Store, filename: &str) -> Result<(), String> {
-    let db = store.lock().unwrap();
-    let now = Instant::now();
-    let now_secs = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
+// An entry's field/value pairs, in the order they were given
+type StreamFields = Vec<(Vec<u8>, Vec<u8>)>;
+
+// Append-only log of entries, ordered by ID. The last ID given out is kept
+// apart from the entries, so IDs never go back.
+#[derive(Clone, Default)]
+struct Stream {
+    entries: BTreeMap<StreamId, StreamFields>,
+    last_id: StreamId,
+}
+
+impl Stream {
+    // The ID XADD gives a new entry: `ms` is None for "*", and `seq` None
+    // for "<ms>-*", which both pick the next free sequence number
+    fn next_id(&self, ms: Option<u64>, seq: Option<u64>) -> Result<StreamId, &'static str> {
+        let smaller = "ERR The ID specified in XADD is equal or smaller than the target stream top item";
+        let last = self.last_id;
+        match (ms, seq) {
+            (None, _) => {
+                let now = unix_time().as_millis() as u64;
+                match (last.seq.checked_add(1), last.ms.checked_add(1)) {
+                    _ if now > last.ms => Ok(StreamId { ms: now, seq: 0 }),
+                    (Some(seq), _) => Ok(StreamId { ms: last.ms, seq }),
+                    (None, Some(ms)) => Ok(StreamId { ms, seq: 0 }),
+                    (None, None) => Err("ERR The stream has exhausted the last possible ID, unable to add more items"),
+                }
+            }
+            (Some(ms), None) if ms > last.ms => Ok(StreamId { ms, seq: 0 }),
+            (Some(ms), None) => match last.seq.checked_add(1) {
+                Some(seq) if ms == last.ms => Ok(StreamId { ms, seq }),
+                _ => Err(smaller),
+            },
+            (Some(ms), Some(seq)) => Some(StreamId { ms, seq }).filter(|&id| id > last).ok_or(smaller),
+        }
+    }
     
-    let serializable: HashMap<String, SerializableEntry> = db
-        .iter()
-        .filter(|(_, entry)| !is_expired(entry))
-        .map(|(key, entry)| {
-            let value = match &entry.value {
-                Value::String(s) => SerializableValue::String(s.clone()),
-                Value::List(l) => SerializableValue::List(l.clone()),
-            };
-            
-            let expires_in_secs = entry.expires_at.map(|exp| {
-                let remaining = exp.duration_since(now).as_secs();
-                now_secs + remaining
-            });
-            
-            (key.clone(), SerializableEntry { value, expires_in_secs })
-        })
-        .collect();
+    fn add(&mut self, id: StreamId, fields: StreamFields) {
+        self.entries.insert(id, fields);
+        self.last_id = id;
+    }
+}
+
+// Geo members live in sorted sets, scored by the 52-bit geohash of their
+// position: 26 bits each of longitude and latitude, interleaved. Latitudes
+// stop where the Web Mercator projection does, as in Redis.
+const GEO_LONG_MIN: f64 = -180.0;
+const GEO_LONG_MAX: f64 = 180.0;
+const GEO_LAT_MIN: f64 = -85.05112878;
+const GEO_LAT_MAX: f64 = 85.05112878;
+const GEO_STEP: u32 = 26;
+// The value Redis uses, so distances agree with it
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+
+// Latitude bits go in the even positions, longitude bits in the odd ones
+fn geohash_encode(longitude: f64, latitude: f64) -> u64 {
+    let cells = (1u64 << GEO_STEP) as f64;
+    let lat = ((latitude - GEO_LAT_MIN) / (GEO_LAT_MAX - GEO_LAT_MIN) * cells) as u64;
+    let lon = ((longitude - GEO_LONG_MIN) / (GEO_LONG_MAX - GEO_LONG_MIN) * cells) as u64;
+    (0..GEO_STEP).fold(0, |hash, bit| hash | (lat >> bit & 1) << (2 * bit) | (lon >> bit & 1) << (2 * bit + 1))
+}
+
+// The centre of the cell a geohash names, as (longitude, latitude)
+fn geohash_decode(hash: u64) -> (f64, f64) {
+    let (lat, lon) = (0..GEO_STEP).fold((0u64, 0u64), |(lat, lon), bit| {
+        (lat | (hash >> (2 * bit) & 1) << bit, lon | (hash >> (2 * bit + 1) & 1) << bit)
+    });
+    let centre = |cell: u64, min: f64, max: f64| {
+        let size = (max - min) / (1u64 << GEO_STEP) as f64;
+        (min + (cell as f64 + 0.5) * size).clamp(min, max)
+    };
+    (centre(lon, GEO_LONG_MIN, GEO_LONG_MAX), centre(lat, GEO_LAT_MIN, GEO_LAT_MAX))
+}
+
+// Great-circle distance in meters between two (longitude, latitude) points
+fn geo_distance((lon1, lat1): (f64, f64), (lon2, lat2): (f64, f64)) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let v = ((lon2 - lon1).to_radians() / 2.0).sin();
+    2.0 * EARTH_RADIUS_IN_METERS * (u * u + lat1.cos() * lat2.cos() * v * v).sqrt().asin()
+}
+
+// Meters in one of the units geo commands accept
+fn geo_unit(arg: &[u8]) -> Result<f64, Reply> {
+    match String::from_utf8_lossy(arg).to_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "mi" => Ok(1609.34),
+        "ft" => Ok(0.3048),
+        _ => Err(Reply::error("ERR unsupported unit provided. please use M, KM, FT, MI")),
+    }
+}
+
+// The area GEOSEARCH looks in, measured in meters
+#[derive(Clone, Copy)]
+enum GeoShape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+impl GeoShape {
+    // Distance in meters from `centre` to `point`, if the point is inside
+    fn distance_within(&self, centre: (f64, f64), point: (f64, f64)) -> Option<f64> {
+        match *self {
+            GeoShape::Radius(radius) => Some(geo_distance(centre, point)).filter(|&distance| distance <= radius),
+            GeoShape::Box { width, height } => {
+                // As in Redis: north-south along the meridian, east-west
+                // along the point's parallel
+                let north_south = EARTH_RADIUS_IN_METERS * (point.1 - centre.1).to_radians().abs();
+                let east_west = geo_distance((centre.0, point.1), point);
+                (north_south <= height / 2.0 && east_west <= width / 2.0).then(|| geo_distance(centre, point))
+            }
+        }
+    }
+}
+
+fn parse_coordinates(longitude: &[u8], latitude: &[u8]) -> Result<(f64, f64), Reply> {
+    let (Some(lon), Some(lat)) = (parse_num::<f64>(longitude), parse_num::<f64>(latitude)) else {
+        return Err(Reply::error("ERR value is not a valid float"));
+    };
+    if !(GEO_LONG_MIN..=GEO_LONG_MAX).contains(&lon) || !(GEO_LAT_MIN..=GEO_LAT_MAX).contains(&lat) {
+        return Err(Reply::error(format!("ERR invalid longitude,latitude pair {:.6},{:.6}", lon, lat)));
+    }
+    Ok((lon, lat))
+}
+
+// One end of a score range: "5" is inclusive, "(5" exclusive, and
+// "-inf"/"+inf" are unbounded
+#[derive(Clone, Copy)]
+struct ScoreBound {
+    value: f64,
+    exclusive: bool,
+}
+
+impl ScoreBound {
+    fn parse(arg: &[u8]) -> Option<ScoreBound> {
+        let (exclusive, number) = match arg.strip_prefix(b"(") {
+            Some(rest) => (true, rest),
+            None => (false, arg),
+        };
+        let value = parse_num::<f64>(number).filter(|v| !v.is_nan())?;
+        Some(ScoreBound { value, exclusive })
+    }
     
-    let json = serde_json::to_string_pretty(&serializable)
-        .map_err(|e| format!("Serialization error: {}", e))?;
+    fn admits_from_below(&self, score: f64) -> bool {
+        if self.exclusive { score > self.value } else { score >= self.value }
+    }
     
-    std::fs::write(filename, json)
-        .map_err(|e| format!("Write error: {}", e))?;
+    fn admits_from_above(&self, score: f64) -> bool {
+        if self.exclusive { score < self.value } else { score <= self.value }
+    }
+}
+
+struct Entry {
+    value: Value,
+    // Commands that change an existing value in place (APPEND, SETBIT, the
+    // INCR family, ...) must leave this alone; only those that replace the
+    // value outright (SET without KEEPTTL, GETSET, ...) reset it
+    expires_at: Option<Instant>,
+    // Changes whenever the entry may have been modified; WATCH compares it
+    version: u64,
+    // Unix time in ms of the last read or write, for LRU eviction. Atomic
+    // because readers only hold a shared lock.
+    last_access: AtomicU64,
+    // What the entry and its key counted for in the memory total when last
+    // measured
+    size: usize,
+}
+
+impl Entry {
+    fn new(value: Value, expires_at: Option<Instant>) -> Entry {
+        Entry { value, expires_at, version: 0, last_access: AtomicU64::new(lru_clock()), size: 0 }
+    }
     
-    Ok(())
+    fn touch(&self) {
+        self.last_access.store(lru_clock(), Ordering::Relaxed);
+    }
 }
 
-fn load_data(store: &Store, filename: &str) {
-    let json = match std::fs::read_to_string(filename) {
-        Ok(content) => content,
-        Err(_) => {
-            println!("No existing database found, starting fresh");
-            return;
+fn lru_clock() -> u64 {
+    unix_time().as_millis() as u64
+}
+
+// Seconds since the entry was last read or written, as OBJECT IDLETIME reports
+fn idle_seconds(entry: &Entry) -> u64 {
+    lru_clock().saturating_sub(entry.last_access.load(Ordering::Relaxed)) / 1000
+}
+
+// Fixed allowances for the allocations behind an entry and behind each
+// element of a collection, on top of the bytes themselves
+const ENTRY_OVERHEAD: usize = 64;
+const ELEMENT_OVERHEAD: usize = 32;
+
+// Rough number of bytes an entry's value takes; shared by every type so the
+// maxmemory accounting and MEMORY USAGE agree
+fn estimate_size(entry: &Entry) -> usize {
+    sampled_size(entry, 0)
+}
+
+// The same estimate, but measuring only the first `samples` elements of a
+// collection and scaling their average up to its length (0 measures all)
+fn sampled_size(entry: &Entry, samples: usize) -> usize {
+    fn scaled(len: usize, sizes: impl Iterator<Item = usize>, samples: usize) -> usize {
+        if samples == 0 || samples >= len {
+            sizes.sum()
+        } else {
+            sizes.take(samples).sum::<usize>() * len / samples
         }
-    };
+    }
     
-    let serializable: HashMap<String, SerializableEntry> = match serde_json::from_str(&json) {
-        Ok(data) => data,
-        Err(e) => {
-            eprintln!("Failed to load database: {}", e);
-            return;
+    let payload = match &entry.value {
+        // A shared integer costs the key nothing beyond its entry
+        Value::String(s) if is_shared_integer(s) => 0,
+        Value::String(s) => s.len(),
+        Value::List(items) => scaled(items.len(), items.iter().map(|item| item.len() + ELEMENT_OVERHEAD), samples),
+        Value::Hash(fields) => {
+            scaled(fields.len(), fields.iter().map(|(f, v)| f.len() + v.len() + 2 * ELEMENT_OVERHEAD), samples)
+        }
+        Value::Set(members) => {
+            scaled(members.len(), members.iter().map(|member| member.len() + ELEMENT_OVERHEAD), samples)
         }
+        // Members are held twice, by the score map and the ordered index
+        Value::ZSet(zset) => {
+            scaled(zset.scores.len(), zset.scores.keys().map(|member| 2 * (member.len() + ELEMENT_OVERHEAD) + 8), samples)
+        }
+        Value::HyperLogLog(hll) => hll.registers.len(),
+        Value::Stream(stream) => scaled(
+            stream.entries.len(),
+            stream.entries.values().map(|fields| {
+                16 + ELEMENT_OVERHEAD + fields.iter().map(|(f, v)| f.len() + v.len()).sum::<usize>()
+            }),
+            samples,
+        ),
     };
+    ENTRY_OVERHEAD + payload
+}
+
+// Estimated bytes held by every database, kept up to date by DbShard
+static USED_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+// Versions come from one counter, so an entry that is replaced, renamed or
+// swapped in from another database never repeats the version a client saw
+fn next_version() -> u64 {
+    static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+    NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+// One logical database's keys within a shard. Reads go straight to the map;
+// every way of getting mutable access stamps the entry with a new version.
+// Entries handed out mutably are remeasured, and their TTL rechecked, by
+// `settle` once the command is done with them.
+#[derive(Default)]
+struct DbShard {
+    entries: HashMap<Vec<u8>, Entry>,
+    // Sum of the entries' sizes, also counted in USED_MEMORY
+    used: usize,
+    resized: HashSet<Vec<u8>>,
+    // The keys with a TTL, for the expiry cycle to sample at random, and
+    // where each of them sits in that list
+    volatile: Vec<Vec<u8>>,
+    volatile_slots: HashMap<Vec<u8>, usize>,
+    // The version each recently removed key was removed at, so EXEC can tell
+    // that a key WATCHed while absent was created and deleted again. Past
+    // REMOVED_MAX keys the map is emptied, and removed_floor, the version
+    // at that point, stands in for every removal it forgot.
+    removed: HashMap<Vec<u8>, u64>,
+    removed_floor: u64,
+}
+
+const REMOVED_MAX: usize = 128;
+
+impl Drop for DbShard {
+    fn drop(&mut self) {
+        USED_MEMORY.fetch_sub(self.used, Ordering::Relaxed);
+    }
+}
+
+impl std::ops::Deref for DbShard {
+    type Target = HashMap<Vec<u8>, Entry>;
     
-    let now_secs = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
-    let now = Instant::now();
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
+impl DbShard {
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        let entry = self.entries.get_mut(key)?;
+        entry.version = next_version();
+        mark_dirty(1);
+        if !self.resized.contains(key) {
+            self.resized.insert(key.to_vec());
+        }
+        Some(entry)
+    }
     
-    let mut db = store.lock().unwrap();
-    for (key, entry) in serializable {
-        // Skip expired entries
-        if let Some(exp) = entry.expires_in_secs {
-            if exp <= now_secs {
-                continue;
+    // The entry at `key`, created with `default()` as its value if missing
+    fn get_or_insert(&mut self, key: Vec<u8>, default: impl FnOnce() -> Value) -> &mut Entry {
+        self.resized.insert(key.clone());
+        let entry = self.entries.entry(key).or_insert_with(|| Entry::new(default(), None));
+        entry.version = next_version();
+        entry.touch();
+        mark_dirty(1);
+        entry
+    }
+    
+    fn insert(&mut self, key: Vec<u8>, mut entry: Entry) -> Option<Entry> {
+        entry.version = next_version();
+        entry.size = key.len() + estimate_size(&entry);
+        self.resize(0, entry.size);
+        mark_dirty(1);
+        self.track_ttl(&key, entry.expires_at.is_some());
+        let old = self.entries.insert(key, entry);
+        if let Some(old) = &old {
+            self.resize(old.size, 0);
+        }
+        old
+    }
+    
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let removed = self.entries.remove(key);
+        if let Some(entry) = &removed {
+            self.resize(entry.size, 0);
+            self.track_ttl(key, false);
+            mark_dirty(1);
+            if self.removed.len() >= REMOVED_MAX {
+                self.removed.clear();
+                self.removed_floor = next_version();
             }
+            self.removed.insert(key.to_vec(), next_version());
         }
-        
-        let value = match entry.value {
-            SerializableValue::String(s) => Value::String(s),
-            SerializableValue::List(l) => Value::List(l),
-        };
-        
-        let expires_at = entry.expires_in_secs.map(|exp| {
-            let remaining = exp.saturating_sub(now_secs);
-            now + Duration::from_secs(remaining)
-        });
-        
-        db.insert(key, Entry { value, expires_at });
+        removed
     }
     
-    println!("Loaded {} keys from {}", db.len(), filename);
-}
-
-fn handle_client(mut stream: TcpStream, store: Store) {
-    let peer = stream.peer_addr().unwrap();
-    println!("Client connected: {}", peer);
+    // Whether `key` may have been removed after the version counter read `since`
+    fn removed_since(&self, key: &[u8], since: u64) -> bool {
+        self.removed_floor > since || self.removed.get(key).is_some_and(|&version| version > since)
+    }
     
-    let reader = BufReader::new(stream.try_clone().unwrap());
+    // Remeasure the entries handed out mutably since the last call
+    fn settle(&mut self) {
+        for key in std::mem::take(&mut self.resized) {
+            if let Some(entry) = self.entries.get_mut(&key) {
+                let size = key.len() + estimate_size(entry);
+                let old = std::mem::replace(&mut entry.size, size);
+                let has_ttl = entry.expires_at.is_some();
+                self.resize(old, size);
+                self.track_ttl(&key, has_ttl);
+            }
+        }
+    }
     
-    for line in reader.lines() {
-        match line {
-            Ok(command) => {
-                let response = process_command(&command, &store);
-                if stream.write_all(response.as_bytes()).is_err() {
-                    break;
+    // Keep `volatile` listing exactly the keys that have a TTL
+    fn track_ttl(&mut self, key: &[u8], has_ttl: bool) {
+        match (has_ttl, self.volatile_slots.get(key)) {
+            (true, None) => {
+                self.volatile_slots.insert(key.to_vec(), self.volatile.len());
+                self.volatile.push(key.to_vec());
+            }
+            (false, Some(&slot)) => {
+                self.volatile_slots.remove(key);
+                self.volatile.swap_remove(slot);
+                if let Some(moved) = self.volatile.get(slot) {
+                    self.volatile_slots.insert(moved.clone(), slot);
                 }
             }
-            Err(_) => break,
+            _ => {}
         }
     }
-    println!("Client disconnected: {}", peer);
-}
-
-fn process_command(command: &str, store: &Store) -> String {
-    let parts: Vec<&str> = command.trim().split_whitespace().collect();
     
-    if parts.is_empty() {
-        return "-ERR empty command\r\n".to_string();
+    // A random key with a TTL, if there is one
+    fn random_volatile(&self) -> Option<&Vec<u8>> {
+        (!self.volatile.is_empty()).then(|| &self.volatile[rng().below(self.volatile.len())])
     }
     
-    let cmd = parts[0].to_uppercase();
-    let mut db = store.lock().unwrap();
+    fn resize(&mut self, old: usize, new: usize) {
+        self.used = self.used + new - old;
+        USED_MEMORY.fetch_add(new, Ordering::Relaxed);
+        USED_MEMORY.fetch_sub(old, Ordering::Relaxed);
+    }
     
-    match cmd.as_str() {
-        // ========== STRING COMMANDS ==========
-        "SET" => {
+    // Empty the database, handing back what it held
+    fn take(&mut self) -> DbShard {
+        mark_dirty(self.entries.len() as u64);
+        let taken = std::mem::take(self);
+        // Every key just went, without a record of which
+        self.removed_floor = next_version();
+        taken
+    }
+}
+
+// Number of logical databases reachable with SELECT, as in Redis
+const DATABASES: usize = 16;
+
+// Number of shards the keyspace is split into by key hash
+const SHARDS: usize = 16;
+
+fn shard_of(key: &[u8]) -> usize {
+    let mut hasher = std::hash::DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % SHARDS as u64) as usize
+}
+
+// One logical database as a command sees it: its pieces in the shards the
+// command locked. Reaching for a key whose shard isn't locked means the
+// command's key spec is wrong, and writing through a shared lock means it is
+// wrongly listed as read-only; both panic.
+struct Db<'a> {
+    pieces: Vec<Option<Piece<'a>>>,
+    index: usize,
+}
+
+enum Piece<'a> {
+    Shared(&'a DbShard),
+    Exclusive(&'a mut DbShard),
+}
+
+impl Piece<'_> {
+    fn get(&self) -> &DbShard {
+        match self {
+            Piece::Shared(piece) => piece,
+            Piece::Exclusive(piece) => piece,
+        }
+    }
+    
+    fn get_mut(&mut self) -> &mut DbShard {
+        match self {
+            Piece::Shared(_) => panic!("read-only command tried to write"),
+            Piece::Exclusive(piece) => piece,
+        }
+    }
+}
+
+impl Db<'_> {
+    fn piece(&self, key: &[u8]) -> &DbShard {
+        self.pieces[shard_of(key)].as_ref().expect("shard of the key is not locked").get()
+    }
+    
+    fn piece_mut(&mut self, key: &[u8]) -> &mut DbShard {
+        self.pieces[shard_of(key)].as_mut().expect("shard of the key is not locked").get_mut()
+    }
+    
+    // Counts as an access for LRU eviction
+    fn get(&self, key: &[u8]) -> Option<&Entry> {
+        let entry = self.piece(key).get(key)?;
+        entry.touch();
+        Some(entry)
+    }
+    
+    // For looking at a key's type, TTL or other metadata without counting
+    // as an access
+    fn peek(&self, key: &[u8]) -> Option<&Entry> {
+        self.piece(key).get(key)
+    }
+    
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.piece(key).contains_key(key)
+    }
+    
+    fn removed_since(&self, key: &[u8], since: u64) -> bool {
+        self.piece(key).removed_since(key, since)
+    }
+    
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        let entry = self.piece_mut(key).get_mut(key)?;
+        entry.touch();
+        Some(entry)
+    }
+    
+    // For changing only the TTL, which Redis doesn't count as an access
+    fn peek_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        self.piece_mut(key).get_mut(key)
+    }
+    
+    fn get_or_insert(&mut self, key: Vec<u8>, default: impl FnOnce() -> Value) -> &mut Entry {
+        self.piece_mut(&key).get_or_insert(key, default)
+    }
+    
+    fn insert(&mut self, key: Vec<u8>, entry: Entry) -> Option<Entry> {
+        self.piece_mut(&key).insert(key, entry)
+    }
+    
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.piece_mut(key).remove(key)
+    }
+    
+    fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Entry)> {
+        self.pieces.iter().flatten().flat_map(|piece| piece.get().iter())
+    }
+    
+    fn values(&self) -> impl Iterator<Item = &Entry> {
+        self.pieces.iter().flatten().flat_map(|piece| piece.get().values())
+    }
+    
+    fn len(&self) -> usize {
+        self.pieces.iter().flatten().map(|piece| piece.get().len()).sum()
+    }
+    
+    fn take(&mut self) -> Vec<DbShard> {
+        self.pieces.iter_mut().flatten().map(|piece| piece.get_mut().take()).collect()
+    }
+}
+
+// A shard locked for reading or for writing
+enum ShardGuard<'a> {
+    Read(RwLockReadGuard<'a, Vec<DbShard>>),
+    Write(RwLockWriteGuard<'a, Vec<DbShard>>),
+}
+
+impl std::ops::Deref for ShardGuard<'_> {
+    type Target = Vec<DbShard>;
+    
+    fn deref(&self) -> &Self::Target {
+        match self {
+            ShardGuard::Read(guard) => guard,
+            ShardGuard::Write(guard) => guard,
+        }
+    }
+}
+
+impl ShardGuard<'_> {
+    fn exclusive(&mut self) -> &mut Vec<DbShard> {
+        match self {
+            ShardGuard::Read(_) => panic!("read-only command tried to write"),
+            ShardGuard::Write(guard) => guard,
+        }
+    }
+}
+
+// The shards a command locked, by shard number
+struct Shards<'a> {
+    guards: Vec<Option<ShardGuard<'a>>>,
+}
+
+// Entries the command changed are remeasured before the locks go
+impl Drop for Shards<'_> {
+    fn drop(&mut self) {
+        for guard in self.guards.iter_mut().flatten() {
+            if let ShardGuard::Write(dbs) = guard {
+                dbs.iter_mut().for_each(DbShard::settle);
+            }
+        }
+    }
+}
+
+impl Shards<'_> {
+    fn db(&mut self, index: usize) -> Db<'_> {
+        let pieces = self
+            .guards
+            .iter_mut()
+            .map(|guard| match guard {
+                Some(ShardGuard::Read(dbs)) => Some(Piece::Shared(&dbs[index])),
+                Some(ShardGuard::Write(dbs)) => Some(Piece::Exclusive(&mut dbs[index])),
+                None => None,
+            })
+            .collect();
+        Db { pieces, index }
+    }
+    
+    // The pieces of logical database `index`, for read-only walks
+    fn pieces(&self, index: usize) -> impl Iterator<Item = &DbShard> {
+        self.guards.iter().flatten().map(move |dbs| &dbs[index])
+    }
+    
+    fn swap(&mut self, a: usize, b: usize) {
+        for dbs in self.guards.iter_mut().flatten() {
+            dbs.exclusive().swap(a, b);
+        }
+    }
+    
+    // Empty every database, handing back what they held
+    fn take_all(&mut self) -> Vec<DbShard> {
+        self.guards.iter_mut().flatten().flat_map(|dbs| dbs.exclusive().iter_mut().map(DbShard::take)).collect()
+    }
+}
+
+// Pub/sub subscribers of each channel (or pattern), by client id
+type Subscribers = HashMap<Vec<u8>, HashMap<u64, Subscriber>>;
+
+// The outbox that delivers to a subscribed connection, and the RESP version
+// to encode messages in (fixed while subscribed, since HELLO isn't allowed)
+struct Subscriber {
+    outbox: mpsc::UnboundedSender<Vec<u8>>,
+    protocol: u8,
+}
+
+// Both ends of replication. A master streams every write to its replicas
+// after a full sync; a replica follows one master through a background task.
+struct Replication {
+    // Identifies this server's stream, handed to replicas on sync
+    replid: String,
+    // Bytes streamed to replicas so far; replicas acknowledge up to a point in it
+    offset: u64,
+    // Database the stream's last SELECT points at; None forces a new one
+    db: Option<usize>,
+    // Replicas fed by this server, by client id
+    replicas: HashMap<u64, ReplicaLink>,
+    // The master this server follows, if it is a replica
+    master: Option<MasterLink>,
+}
+
+struct ReplicaLink {
+    outbox: mpsc::UnboundedSender<Vec<u8>>,
+    // Stream offset the replica last said it applied (REPLCONF ACK)
+    acked: u64,
+}
+
+struct MasterLink {
+    host: String,
+    port: u16,
+    // Set once the dataset is synced, until the connection drops
+    link_up: Arc<AtomicBool>,
+    task: tokio::task::AbortHandle,
+}
+
+impl Replication {
+    // Send a write to every replica, after a SELECT if it is for another database
+    fn propagate(&mut self, index: usize, command: &[u8]) {
+        if self.replicas.is_empty() {
+            return;
+        }
+        let mut out = Vec::new();
+        if self.db != Some(index) {
+            out.extend(encode_command(&[b"SELECT", index.to_string().as_bytes()]));
+            self.db = Some(index);
+        }
+        out.extend_from_slice(command);
+        self.offset += out.len() as u64;
+        for replica in self.replicas.values() {
+            let _ = replica.outbox.send(out.clone());
+        }
+    }
+    
+    // How many replicas acknowledged the stream up to `offset`
+    fn acknowledged(&self, offset: u64) -> usize {
+        self.replicas.values().filter(|replica| replica.acked >= offset).count()
+    }
+}
+
+// A live connection as CLIENT LIST reports it
+struct ClientInfo {
+    addr: String,
+    name: Vec<u8>,
+    connected_at: Instant,
+    // Lowercase name of the last command it sent
+    last_command: String,
+    // Signalled by CLIENT KILL to make the connection hang up
+    kill: Arc<Notify>,
+}
+
+// Commands that ran longer than slowlog-log-slower-than, newest first
+#[derive(Default)]
+struct SlowLog {
+    entries: VecDeque<SlowLogEntry>,
+    next_id: u64,
+}
+
+struct SlowLogEntry {
+    id: u64,
+    // Unix time it ran at, in seconds, and how long it took in microseconds
+    time: u64,
+    micros: u64,
+    args: Vec<Vec<u8>>,
+    addr: String,
+    name: Vec<u8>,
+}
+
+// Operations that took at least latency-monitor-threshold ms, by event name
+// ("command", "expire-cycle", "fork"). Each event keeps at most one sample
+// per second, the worst, for its latest LATENCY_HISTORY_LEN seconds.
+#[derive(Default)]
+struct LatencyMonitor {
+    events: BTreeMap<&'static str, LatencyEvent>,
+}
+
+const LATENCY_HISTORY_LEN: usize = 160;
+
+#[derive(Default)]
+struct LatencyEvent {
+    // (unix time in seconds, milliseconds taken), oldest first
+    samples: VecDeque<(u64, u64)>,
+    // The worst ever seen, which outlives the samples
+    max: u64,
+}
+
+// All logical databases, split into shards by key hash so that commands on
+// unrelated keys don't queue behind each other. A command locks the shards of
+// the keys it names and whole-keyspace commands lock every shard; read-only
+// commands share the lock with each other. Shards are always locked in
+// ascending order, so two commands can't deadlock, and before the pub/sub
+// registries and the AOF.
+struct Keyspace {
+    shards: Vec<RwLock<Vec<DbShard>>>,
+    // Count of list pushes; blocked BLPOP/BRPOP clients wait for it to move
+    list_pushed: watch::Sender<u64>,
+    subscribers: Mutex<Subscribers>,
+    pattern_subscribers: Mutex<Subscribers>,
+    // Open append-only file when appendonly is on; locked after the shards
+    aof: Mutex<Option<AppendOnlyFile>>,
+    // Told the database and name of every key the cleanup thread expires,
+    // if anything listens (--log-expirations)
+    expired_keys: OnceLock<std::sync::mpsc::Sender<(usize, Vec<u8>)>>,
+    // Every open connection by client id, kept up to date by handle_client
+    clients: Mutex<BTreeMap<u64, ClientInfo>>,
+    slowlog: Mutex<SlowLog>,
+    latency: Mutex<LatencyMonitor>,
+    // Connections in MONITOR mode, sent a line for every command run
+    monitors: Mutex<HashMap<u64, Subscriber>>,
+    // Locked after the AOF
+    replication: Mutex<Replication>,
+    // Count of REPLCONF ACKs received; WAIT watches it
+    replica_acks: watch::Sender<u64>,
+}
+
+impl Keyspace {
+    fn new() -> Keyspace {
+        Keyspace {
+            shards: (0..SHARDS)
+                .map(|_| RwLock::new((0..DATABASES).map(|_| DbShard::default()).collect()))
+                .collect(),
+            list_pushed: watch::Sender::new(0),
+            subscribers: Mutex::new(HashMap::new()),
+            pattern_subscribers: Mutex::new(HashMap::new()),
+            aof: Mutex::new(None),
+            expired_keys: OnceLock::new(),
+            clients: Mutex::new(BTreeMap::new()),
+            slowlog: Mutex::new(SlowLog::default()),
+            latency: Mutex::new(LatencyMonitor::default()),
+            monitors: Mutex::new(HashMap::new()),
+            replication: Mutex::new(Replication {
+                replid: (0..5).map(|_| format!("{:08x}", rng().next_u64() as u32)).collect(),
+                offset: 0,
+                db: None,
+                replicas: HashMap::new(),
+                master: None,
+            }),
+            replica_acks: watch::Sender::new(0),
+        }
+    }
+    
+    // Lock the shards holding `keys`, or every shard for None, for writing
+    // or only for reading
+    fn lock(&self, keys: Option<&[&[u8]]>, write: bool) -> Shards<'_> {
+        let mut wanted = [keys.is_none(); SHARDS];
+        for key in keys.unwrap_or_default() {
+            wanted[shard_of(key)] = true;
+        }
+        let guards = self
+            .shards
+            .iter()
+            .zip(wanted)
+            .map(|(shard, wanted)| match (wanted, write) {
+                (false, _) => None,
+                (true, true) => Some(ShardGuard::Write(shard.write().unwrap())),
+                (true, false) => Some(ShardGuard::Read(shard.read().unwrap())),
+            })
+            .collect();
+        Shards { guards }
+    }
+    
+    fn lock_all(&self) -> Shards<'_> {
+        self.lock(None, true)
+    }
+    
+    fn read_all(&self) -> Shards<'_> {
+        self.lock(None, false)
+    }
+    
+    // Called after anything that may have filled a list, to wake blocked
+    // BLPOP/BRPOP clients
+    fn notify_list_pushed(&self) {
+        self.list_pushed.send_modify(|pushes| *pushes += 1);
+    }
+}
+
+type Store = Arc<Keyspace>;
+
+// Settings taken from the command line at startup (`--name value`); the
+// mutable ones can also be changed at runtime with CONFIG SET
+struct Config {
+    bind: String,
+    port: u16,
+    dbfilename: String,
+    unixsocket: Option<String>,
+    requirepass: Option<String>,
+    // Snapshot after `seconds` once at least `changes` writes happened
+    save_points: Vec<(u64, u64)>,
+    appendonly: bool,
+    appendfilename: String,
+    // Connections beyond this are turned away
+    maxclients: usize,
+    // Memory limit in bytes (0 for none), what to evict to stay under it and
+    // how many keys each eviction looks at
+    maxmemory: usize,
+    maxmemory_policy: MaxmemoryPolicy,
+    maxmemory_samples: usize,
+    // Keyspace event classes to publish, as NOTIFY_* bits
+    notify_keyspace_events: u32,
+    // Print each key the cleanup thread expires to stderr
+    log_expirations: bool,
+    // Expiry cycles per second
+    hz: u64,
+    // Commands taking longer than this many microseconds go in the slow log
+    // (negative turns it off), which keeps the latest slowlog_max_len
+    slowlog_log_slower_than: i64,
+    slowlog_max_len: usize,
+    // Operations taking at least this many milliseconds are sampled by the
+    // latency monitor; 0 turns it off
+    latency_monitor_threshold: u64,
+    // Largest list OBJECT ENCODING calls a listpack: a count of entries if
+    // positive, -1 to -5 for 4 KB to 64 KB
+    list_max_listpack_size: i64,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MaxmemoryPolicy {
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysRandom,
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    const ALL: [MaxmemoryPolicy; 5] = [
+        MaxmemoryPolicy::NoEviction,
+        MaxmemoryPolicy::AllKeysLru,
+        MaxmemoryPolicy::VolatileLru,
+        MaxmemoryPolicy::AllKeysRandom,
+        MaxmemoryPolicy::VolatileTtl,
+    ];
+    
+    fn name(self) -> &'static str {
+        match self {
+            MaxmemoryPolicy::NoEviction => "noeviction",
+            MaxmemoryPolicy::AllKeysLru => "allkeys-lru",
+            MaxmemoryPolicy::VolatileLru => "volatile-lru",
+            MaxmemoryPolicy::AllKeysRandom => "allkeys-random",
+            MaxmemoryPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+    
+    // Whether only keys with a TTL may be evicted
+    fn volatile(self) -> bool {
+        matches!(self, MaxmemoryPolicy::VolatileLru | MaxmemoryPolicy::VolatileTtl)
+    }
+}
+
+fn parse_yes_no(name: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("{} must be yes or no, not '{}'", name, value)),
+    }
+}
+
+// A byte count, optionally with a unit as Redis takes them: k/m/g are powers
+// of 1000 and kb/mb/gb powers of 1024
+fn parse_memory(value: &str) -> Option<usize> {
+    let value = value.to_ascii_lowercase();
+    let units = [("gb", 1 << 30), ("mb", 1 << 20), ("kb", 1 << 10), ("g", 1_000_000_000), ("m", 1_000_000), ("k", 1000), ("b", 1)];
+    let (digits, unit) = units
+        .iter()
+        .find_map(|&(suffix, unit)| value.strip_suffix(suffix).map(|digits| (digits, unit)))
+        .unwrap_or((value.as_str(), 1));
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+// Parameter names CONFIG GET knows about, in the order it lists them
+const CONFIG_PARAMETERS: &[&str] = &[
+    "bind", "port", "dbfilename", "unixsocket", "requirepass", "save", "appendonly", "appendfilename",
+    "maxclients", "maxmemory", "maxmemory-policy", "maxmemory-samples",
+    "notify-keyspace-events", "log-expirations", "hz", "slowlog-log-slower-than", "slowlog-max-len",
+    "latency-monitor-threshold", "list-max-listpack-size",
+];
+
+impl Config {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config {
+            bind: "127.0.0.1".to_string(),
+            port: 6379,
+            dbfilename: "redrust.rdb".to_string(),
+            unixsocket: None,
+            requirepass: None,
+            save_points: vec![(3600, 1), (300, 100), (60, 10000)],
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            maxclients: 10000,
+            maxmemory: 0,
+            maxmemory_policy: MaxmemoryPolicy::NoEviction,
+            maxmemory_samples: 5,
+            notify_keyspace_events: 0,
+            log_expirations: false,
+            hz: 10,
+            slowlog_log_slower_than: 10000,
+            slowlog_max_len: 128,
+            latency_monitor_threshold: 0,
+            list_max_listpack_size: -2,
+        };
+        while let Some(flag) = args.next() {
+            let Some(name) = flag.strip_prefix("--") else {
+                return Err(format!("unknown option '{}'", flag));
+            };
+            let value = args.next().ok_or_else(|| format!("missing value for {}", flag))?;
+            config.set(name, &value, true)?;
+        }
+        Ok(config)
+    }
+    
+    fn get(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "bind" => self.bind.clone(),
+            "port" => self.port.to_string(),
+            "dbfilename" => self.dbfilename.clone(),
+            "unixsocket" => self.unixsocket.clone().unwrap_or_default(),
+            "requirepass" => self.requirepass.clone().unwrap_or_default(),
+            "save" => self
+                .save_points
+                .iter()
+                .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+                .collect::<Vec<_>>()
+                .join(" "),
+            "appendonly" => if self.appendonly { "yes" } else { "no" }.to_string(),
+            "appendfilename" => self.appendfilename.clone(),
+            "maxclients" => self.maxclients.to_string(),
+            "maxmemory" => self.maxmemory.to_string(),
+            "maxmemory-policy" => self.maxmemory_policy.name().to_string(),
+            "maxmemory-samples" => self.maxmemory_samples.to_string(),
+            "notify-keyspace-events" => format_notify_flags(self.notify_keyspace_events),
+            "log-expirations" => if self.log_expirations { "yes" } else { "no" }.to_string(),
+            "hz" => self.hz.to_string(),
+            "slowlog-log-slower-than" => self.slowlog_log_slower_than.to_string(),
+            "slowlog-max-len" => self.slowlog_max_len.to_string(),
+            "latency-monitor-threshold" => self.latency_monitor_threshold.to_string(),
+            "list-max-listpack-size" => self.list_max_listpack_size.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+    
+    // Listener settings only make sense before the server starts
+    fn set(&mut self, name: &str, value: &str, startup: bool) -> Result<(), String> {
+        match name {
+            "bind" | "port" | "unixsocket" | "appendonly" | "appendfilename" | "log-expirations" if !startup => {
+                return Err(format!("can't set immutable config '{}'", name));
+            }
+            "bind" => self.bind = value.to_string(),
+            "port" => self.port = value.parse().map_err(|_| format!("invalid port '{}'", value))?,
+            "unixsocket" => self.unixsocket = Some(value.to_string()),
+            "appendonly" => self.appendonly = parse_yes_no(name, value)?,
+            "log-expirations" => self.log_expirations = parse_yes_no(name, value)?,
+            "appendfilename" => self.appendfilename = value.to_string(),
+            "dbfilename" => self.dbfilename = value.to_string(),
+            "maxclients" => {
+                self.maxclients = value
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| format!("invalid maxclients '{}'", value))?
+            }
+            "maxmemory" => {
+                self.maxmemory = parse_memory(value).ok_or_else(|| format!("invalid maxmemory '{}'", value))?
+            }
+            "maxmemory-policy" => {
+                self.maxmemory_policy = MaxmemoryPolicy::ALL
+                    .into_iter()
+                    .find(|policy| policy.name().eq_ignore_ascii_case(value))
+                    .ok_or_else(|| format!("invalid maxmemory-policy '{}'", value))?
+            }
+            "maxmemory-samples" => {
+                self.maxmemory_samples = value
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| format!("invalid maxmemory-samples '{}'", value))?
+            }
+            "hz" => {
+                self.hz = value
+                    .parse()
+                    .ok()
+                    .filter(|hz| (1..=500).contains(hz))
+                    .ok_or_else(|| format!("hz must be between 1 and 500, not '{}'", value))?
+            }
+            "slowlog-log-slower-than" => {
+                self.slowlog_log_slower_than =
+                    value.parse().map_err(|_| format!("invalid slowlog-log-slower-than '{}'", value))?
+            }
+            "slowlog-max-len" => {
+                self.slowlog_max_len = value.parse().map_err(|_| format!("invalid slowlog-max-len '{}'", value))?
+            }
+            "latency-monitor-threshold" => {
+                self.latency_monitor_threshold =
+                    value.parse().map_err(|_| format!("invalid latency-monitor-threshold '{}'", value))?
+            }
+            "list-max-listpack-size" => {
+                self.list_max_listpack_size = value
+                    .parse()
+                    .ok()
+                    .filter(|&n| n != 0 && n >= -5)
+                    .ok_or_else(|| format!("list-max-listpack-size must be -1 to -5 or a positive count, not '{}'", value))?
+            }
+            "notify-keyspace-events" => {
+                self.notify_keyspace_events =
+                    parse_notify_flags(value).ok_or_else(|| format!("invalid notify-keyspace-events '{}'", value))?
+            }
+            "requirepass" => self.requirepass = Some(value.to_string()).filter(|p| !p.is_empty()),
+            "save" => {
+                // Pairs of "seconds changes"; an empty string turns saving off
+                let numbers: Option<Vec<u64>> = value.split_whitespace().map(|n| n.parse().ok()).collect();
+                match numbers {
+                    Some(numbers) if numbers.len().is_multiple_of(2) => {
+                        self.save_points = numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect();
+                    }
+                    _ => return Err(format!("invalid save rules '{}'", value)),
+                }
+            }
+            _ => return Err(format!("unknown option '{}'", name)),
+        }
+        Ok(())
+    }
+}
+
+static CONFIG: OnceLock<RwLock<Config>> = OnceLock::new();
+
+fn config() -> RwLockReadGuard<'static, Config> {
+    CONFIG.get().expect("config is set in main").read().unwrap()
+}
+
+// Writes since the last successful snapshot, compared against the save points
+static DIRTY: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // Mutations made on this thread; unlike DIRTY it is never reset, so the
+    // AOF can tell whether the command it just ran wrote anything
+    static WRITES: Cell<u64> = const { Cell::new(0) };
+}
+
+fn mark_dirty(changes: u64) {
+    DIRTY.fetch_add(changes, Ordering::Relaxed);
+    WRITES.set(WRITES.get() + changes);
+}
+
+// A snapshot taken when the counter read `saved` reached disk. Writes made
+// while it was being written still count.
+fn clear_dirty(saved: u64) {
+    let _ = DIRTY.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |dirty| Some(dirty.saturating_sub(saved)));
+}
+
+// Keyspace event classes, one per letter of notify-keyspace-events as in
+// Redis. K and E choose the channels; the rest choose which events go out.
+const NOTIFY_KEYSPACE: u32 = 1 << 0;
+const NOTIFY_KEYEVENT: u32 = 1 << 1;
+const NOTIFY_GENERIC: u32 = 1 << 2;
+const NOTIFY_STRING: u32 = 1 << 3;
+const NOTIFY_LIST: u32 = 1 << 4;
+const NOTIFY_SET: u32 = 1 << 5;
+const NOTIFY_HASH: u32 = 1 << 6;
+const NOTIFY_ZSET: u32 = 1 << 7;
+const NOTIFY_EXPIRED: u32 = 1 << 8;
+const NOTIFY_EVICTED: u32 = 1 << 9;
+const NOTIFY_STREAM: u32 = 1 << 10;
+// What 'A' stands for: every event class
+const NOTIFY_ALL: u32 = NOTIFY_GENERIC | NOTIFY_STRING | NOTIFY_LIST | NOTIFY_SET | NOTIFY_HASH | NOTIFY_ZSET
+    | NOTIFY_EXPIRED | NOTIFY_EVICTED | NOTIFY_STREAM;
+
+const NOTIFY_FLAGS: &[(char, u32)] = &[
+    ('g', NOTIFY_GENERIC),
+    ('$', NOTIFY_STRING),
+    ('l', NOTIFY_LIST),
+    ('s', NOTIFY_SET),
+    ('h', NOTIFY_HASH),
+    ('z', NOTIFY_ZSET),
+    ('x', NOTIFY_EXPIRED),
+    ('e', NOTIFY_EVICTED),
+    ('t', NOTIFY_STREAM),
+    ('K', NOTIFY_KEYSPACE),
+    ('E', NOTIFY_KEYEVENT),
+];
+
+fn parse_notify_flags(value: &str) -> Option<u32> {
+    value.chars().try_fold(0, |flags, c| match c {
+        'A' => Some(flags | NOTIFY_ALL),
+        _ => NOTIFY_FLAGS.iter().find(|&&(letter, _)| letter == c).map(|&(_, flag)| flags | flag),
+    })
+}
+
+fn format_notify_flags(flags: u32) -> String {
+    let mut text = String::new();
+    let mut rest = flags;
+    if flags & NOTIFY_ALL == NOTIFY_ALL {
+        text.push('A');
+        rest &= !NOTIFY_ALL;
+    }
+    text.extend(NOTIFY_FLAGS.iter().filter(|&&(_, flag)| rest & flag != 0).map(|&(letter, _)| letter));
+    text
+}
+
+thread_local! {
+    // Keyspace events raised by the command running on this thread, published
+    // once it is done
+    static KEYSPACE_EVENTS: RefCell<Vec<(usize, &'static str, Vec<u8>)>> = const { RefCell::new(Vec::new()) };
+}
+
+// Record that `event` happened to `key` in database `db`, if
+// notify-keyspace-events asks for its class
+fn notify_keyspace_event(db: usize, class: u32, event: &'static str, key: &[u8]) {
+    let flags = config().notify_keyspace_events;
+    if flags & class == 0 || flags & (NOTIFY_KEYSPACE | NOTIFY_KEYEVENT) == 0 {
+        return;
+    }
+    KEYSPACE_EVENTS.with_borrow_mut(|events| events.push((db, event, key.to_vec())));
+}
+
+// Publish the events recorded on this thread: the event name on
+// __keyspace@<db>__:<key> and the key on __keyevent@<db>__:<event>
+fn publish_keyspace_events(store: &Store) {
+    let events = KEYSPACE_EVENTS.take();
+    if events.is_empty() {
+        return;
+    }
+    let flags = config().notify_keyspace_events;
+    for (db, event, key) in events {
+        if flags & NOTIFY_KEYSPACE != 0 {
+            let channel = [format!("__keyspace@{}__:", db).as_bytes(), &key].concat();
+            publish(store, &channel, event.as_bytes());
+        }
+        if flags & NOTIFY_KEYEVENT != 0 {
+            publish(store, format!("__keyevent@{}__:{}", db, event).as_bytes(), &key);
+        }
+    }
+}
+
+// Server-wide figures reported by INFO
+static STARTED_AT: OnceLock<Instant> = OnceLock::new();
+static CONNECTED_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+// Per-connection state that outlives a single command
+struct Session {
+    db: usize,
+    authenticated: bool,
+    // Commands queued since MULTI, and whether one of them was rejected
+    transaction: Option<Vec<Vec<Vec<u8>>>>,
+    transaction_failed: bool,
+    // WATCHed keys as (database, key, what WATCH saw of it)
+    watched: Vec<(usize, Vec<u8>, Watched)>,
+    id: u64,
+    // Everything written to the client goes through here, so pub/sub messages
+    // from other connections are never interleaved with a reply
+    outbox: mpsc::UnboundedSender<Vec<u8>>,
+    channels: HashSet<Vec<u8>>,
+    patterns: HashSet<Vec<u8>>,
+    // RESP version negotiated with HELLO
+    protocol: u8,
+    // Set by MONITOR; the connection then only watches
+    monitoring: bool,
+}
+
+impl Session {
+    fn new(outbox: mpsc::UnboundedSender<Vec<u8>>) -> Session {
+        static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+        Session {
+            db: 0,
+            // Without a password every connection starts out trusted
+            authenticated: config().requirepass.is_none(),
+            transaction: None,
+            transaction_failed: false,
+            watched: Vec::new(),
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            outbox,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            protocol: 2,
+            monitoring: false,
+        }
+    }
+    
+    // While subscribed, a connection only takes pub/sub commands
+    fn subscribed(&self) -> bool {
+        !self.channels.is_empty() || !self.patterns.is_empty()
+    }
+    
+    // The count reported in (un)subscribe confirmations covers both kinds
+    fn subscription_count(&self) -> Reply {
+        Reply::Integer((self.channels.len() + self.patterns.len()) as i64)
+    }
+}
+
+const WRONG_TYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+const NOT_HYPERLOGLOG: &str = "WRONGTYPE Key is not a valid HyperLogLog string value.";
+
+// Every command process_command understands, with its arity as in Redis'
+// command table: the number of arguments including the name, negated when
+// that is only the minimum. MULTI uses it to reject unknown commands at
+// queue time, and COMMAND reports it.
+const COMMANDS: &[(&str, i64)] = &[
+    ("SET", -3), ("SETEX", 4), ("PSETEX", 4), ("GET", 2), ("MSET", -3), ("MGET", -2), ("GETEX", -2), ("INCR", 2), ("DECR", 2), ("INCRBY", 3),
+    ("DECRBY", 3), ("INCRBYFLOAT", 3), ("APPEND", 3), ("GETSET", 3),
+    ("SETRANGE", 4), ("GETRANGE", 4), ("SETBIT", 4), ("GETBIT", 3),
+    ("BITCOUNT", -2), ("BITPOS", -3), ("BITOP", -4),
+    ("LPUSH", -3), ("RPUSH", -3), ("LPUSHX", -3), ("RPUSHX", -3), ("LPOP", -2), ("RPOP", -2), ("BLPOP", -3),
+    ("BRPOP", -3), ("LLEN", 2), ("LRANGE", 4), ("LPOS", -3),
+    ("LTRIM", 4), ("LINSERT", 5), ("LREM", 4),
+    ("HSET", -4), ("HMSET", -4), ("HGET", 3), ("HMGET", -3), ("HEXISTS", 3), ("HLEN", 2), ("HKEYS", 2),
+    ("HVALS", 2), ("HINCRBY", 4), ("HINCRBYFLOAT", 4),
+    ("HDEL", -3), ("HGETALL", 2), ("HSCAN", -3),
+    ("SADD", -3), ("SREM", -3), ("SMEMBERS", 2), ("SSCAN", -3), ("SMOVE", 4), ("SISMEMBER", 3), ("SMISMEMBER", -3), ("SCARD", 2), ("SPOP", -2),
+    ("SRANDMEMBER", -2),
+    ("SINTER", -2), ("SINTERCARD", -3), ("SUNION", -2), ("SDIFF", -2), ("SINTERSTORE", -3), ("SUNIONSTORE", -3),
+    ("SDIFFSTORE", -3),
+    ("ZADD", -4), ("ZSCORE", 3), ("ZSCAN", -3), ("ZRANGE", -4), ("ZRANGEBYSCORE", -4), ("ZCOUNT", 4),
+    ("ZINCRBY", 4), ("ZPOPMIN", -2), ("ZPOPMAX", -2),
+    ("ZREM", -3), ("ZCARD", 2), ("ZRANDMEMBER", -2), ("HRANDFIELD", -2),
+    ("PFADD", -2), ("PFCOUNT", -2), ("PFMERGE", -2), ("GEOADD", -5), ("GEOPOS", -2), ("GEODIST", -4),
+    ("GEOSEARCH", -7), ("XADD", -5), ("XLEN", 2), ("XRANGE", -4),
+    ("XREVRANGE", -4),
+    ("SAVE", 1), ("BGSAVE", -1), ("LASTSAVE", 1), ("BGREWRITEAOF", 1), ("SHUTDOWN", -1), ("CONFIG", -2),
+    ("EXPIRE", 3), ("EXPIREAT", 3), ("PEXPIREAT", 3), ("EXPIRETIME", 2), ("PEXPIRETIME", 2), ("TTL", 2),
+    ("DEL", -2), ("UNLINK", -2), ("TOUCH", -2),
+    ("COPY", -3), ("RENAME", 3), ("RENAMENX", 3), ("DUMP", 2), ("RESTORE", -4), ("SORT", -2), ("KEYS", -1),
+    ("SCAN", -2), ("TYPE", 2), ("DBSIZE", 1), ("RANDOMKEY", 1), ("FLUSHDB", -1), ("FLUSHALL", -1),
+    ("SELECT", 2), ("SWAPDB", 3), ("INFO", -1), ("MEMORY", -3), ("OBJECT", 3), ("AUTH", 2), ("HELLO", -1), ("CLIENT", -2),
+    ("SLOWLOG", -2), ("LATENCY", -2), ("MONITOR", 1), ("WAIT", 3), ("REPLICAOF", 3), ("SLAVEOF", 3),
+    ("SYNC", 1), ("PSYNC", -3), ("REPLCONF", -1), ("RESET", 1), ("QUIT", -1),
+    ("PING", -1),
+    ("MULTI", 1), ("EXEC", 1), ("DISCARD", 1), ("WATCH", -2), ("UNWATCH", 1),
+    ("SUBSCRIBE", -2), ("UNSUBSCRIBE", -1), ("PSUBSCRIBE", -2), ("PUNSUBSCRIBE", -1), ("PUBLISH", 3),
+    ("DEBUG", -2), ("COMMAND", -1), ("LOLWUT", -1),
+];
+
+// Where a command's keys are, as in Redis' command table: the first and last
+// key argument (negative counts from the end) and the step between keys.
+// (0, 0, 0) is a command without keys; None is one that works on the whole
+// keyspace, which locks every shard.
+fn key_spec(cmd: &str) -> Option<(usize, isize, usize)> {
+    match cmd {
+        "SET" | "SETEX" | "PSETEX" | "GET" | "GETEX" | "INCR" | "DECR" | "INCRBY" | "DECRBY" | "INCRBYFLOAT" | "APPEND" | "GETSET"
+        | "SETRANGE" | "GETRANGE" | "SETBIT" | "GETBIT" | "BITCOUNT" | "BITPOS"
+        | "LPUSH" | "RPUSH" | "LPUSHX" | "RPUSHX" | "LPOP" | "RPOP" | "LLEN" | "LRANGE" | "LPOS"
+        | "LTRIM" | "LINSERT" | "LREM"
+        | "HSET" | "HMSET" | "HGET" | "HMGET" | "HEXISTS" | "HLEN" | "HKEYS" | "HVALS" | "HINCRBY"
+        | "HINCRBYFLOAT" | "HDEL" | "HGETALL" | "HSCAN" | "HRANDFIELD"
+        | "SADD" | "SREM" | "SMEMBERS" | "SSCAN" | "SISMEMBER" | "SMISMEMBER" | "SCARD" | "SPOP" | "SRANDMEMBER"
+        | "ZADD" | "ZSCORE" | "ZSCAN" | "ZRANGE" | "ZRANGEBYSCORE" | "ZCOUNT" | "ZINCRBY" | "ZPOPMIN"
+        | "ZPOPMAX" | "ZREM" | "ZCARD" | "ZRANDMEMBER" | "PFADD" | "GEOADD" | "GEOPOS" | "GEODIST"
+        | "GEOSEARCH" | "XADD" | "XLEN" | "XRANGE" | "XREVRANGE"
+        | "EXPIRE" | "EXPIREAT" | "PEXPIREAT" | "EXPIRETIME" | "PEXPIRETIME" | "TTL" | "TYPE"
+        | "DUMP" | "RESTORE" | "SORT" => Some((1, 1, 1)),
+        "DEL" | "UNLINK" | "TOUCH" | "WATCH" | "MGET"
+        | "SINTER" | "SUNION" | "SDIFF" | "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" | "PFCOUNT" | "PFMERGE" => Some((1, -1, 1)),
+        "COPY" | "RENAME" | "RENAMENX" | "SMOVE" => Some((1, 2, 1)),
+        "MSET" => Some((1, -1, 2)),
+        "BITOP" => Some((2, -1, 1)),
+        // Really as many keys as numkeys says; command_keys narrows it down
+        "SINTERCARD" => Some((2, -1, 1)),
+        "MEMORY" | "OBJECT" => Some((2, 2, 1)),
+        "BLPOP" | "BRPOP" => Some((1, -2, 1)),
+        "LASTSAVE" | "BGSAVE" | "BGREWRITEAOF" | "CONFIG" | "SELECT" | "AUTH" | "PING" | "UNWATCH"
+        | "MULTI" | "EXEC" | "DISCARD" | "DEBUG" | "COMMAND" | "HELLO" | "CLIENT" | "SLOWLOG" | "LATENCY"
+        | "LOLWUT" | "MONITOR" | "WAIT" | "RESET" | "QUIT" | "REPLICAOF" | "SLAVEOF" | "REPLCONF"
+        | "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "PUBLISH" => Some((0, 0, 0)),
+        _ => None,
+    }
+}
+
+// Commands that never modify the keyspace. They take shared locks, so any
+// number of them can run on a shard at once. One that names an expired key
+// takes the exclusive lock instead, to delete it first (see run_locked).
+fn read_only(cmd: &str) -> bool {
+    matches!(
+        cmd,
+        "GET" | "MGET" | "GETRANGE" | "GETBIT" | "BITCOUNT" | "BITPOS" | "LLEN" | "LRANGE" | "LPOS"
+            | "HGET" | "HMGET" | "HEXISTS" | "HLEN" | "HKEYS" | "HVALS" | "HGETALL" | "HSCAN" | "HRANDFIELD"
+            | "SMEMBERS" | "SSCAN" | "SISMEMBER" | "SMISMEMBER" | "SCARD" | "SRANDMEMBER" | "SINTER" | "SINTERCARD"
+            | "SUNION" | "SDIFF"
+            | "ZSCORE" | "ZSCAN" | "ZRANGE" | "ZRANGEBYSCORE" | "ZCOUNT" | "ZCARD" | "ZRANDMEMBER" | "PFCOUNT"
+            | "GEOPOS" | "GEODIST" | "GEOSEARCH" | "XLEN" | "XRANGE" | "XREVRANGE"
+            | "EXPIRETIME" | "PEXPIRETIME" | "TTL" | "TOUCH" | "TYPE" | "DUMP" | "SORT" | "KEYS" | "SCAN" | "DBSIZE"
+            | "RANDOMKEY" | "INFO" | "MEMORY" | "OBJECT" | "SAVE" | "SHUTDOWN" | "WATCH" | "SYNC" | "PSYNC"
+    )
+}
+
+fn command_arity(cmd: &str) -> Option<i64> {
+    COMMANDS.iter().find(|(name, _)| *name == cmd).map(|&(_, arity)| arity)
+}
+
+// Whether `argc` arguments, name included, satisfy an arity
+fn arity_allows(arity: i64, argc: usize) -> bool {
+    if arity < 0 { argc as i64 >= -arity } else { argc as i64 == arity }
+}
+
+// Flags COMMAND reports for a command, drawn from the lists above
+fn command_flags(cmd: &str) -> Vec<&'static str> {
+    let mut flags = Vec::new();
+    let touches_keys = key_spec(cmd) != Some((0, 0, 0));
+    if matches!(
+        cmd,
+        "SAVE" | "BGSAVE" | "BGREWRITEAOF" | "SHUTDOWN" | "CONFIG" | "DEBUG" | "SLOWLOG" | "LATENCY" | "MONITOR"
+            | "REPLICAOF" | "SLAVEOF" | "SYNC" | "PSYNC" | "REPLCONF"
+    ) {
+        flags.push("admin");
+    } else if touches_keys && !matches!(cmd, "INFO" | "WATCH") {
+        flags.push(if read_only(cmd) { "readonly" } else { "write" });
+    }
+    if may_grow(cmd) {
+        flags.push("denyoom");
+    }
+    if matches!(cmd, "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "PUBLISH") {
+        flags.push("pubsub");
+    }
+    if matches!(cmd, "BLPOP" | "BRPOP" | "WAIT") {
+        flags.push("blocking");
+    }
+    if cmd == "SINTERCARD" {
+        flags.push("movablekeys");
+    }
+    flags
+}
+
+// A command's entry in COMMAND and COMMAND INFO, laid out as Redis did
+// before ACLs: name, arity, flags, first key, last key, key step. Commands
+// without keys, or working on the whole keyspace, report 0 0 0.
+fn command_info(cmd: &str, arity: i64) -> Reply {
+    let (first, last, step) = key_spec(cmd).unwrap_or((0, 0, 0));
+    Reply::Array(vec![
+        Reply::bulk(cmd.to_lowercase().as_bytes()),
+        Reply::Integer(arity),
+        Reply::Array(command_flags(cmd).into_iter().map(|flag| Reply::Simple(flag.to_string())).collect()),
+        Reply::Integer(first as i64),
+        Reply::Integer(last as i64),
+        Reply::Integer(step as i64),
+    ])
+}
+
+// The COMMAND DOCS group a command is listed under
+fn command_group(cmd: &str) -> &'static str {
+    match cmd {
+        "SET" | "SETEX" | "PSETEX" | "GET" | "MSET" | "MGET" | "GETEX" | "INCR" | "DECR" | "INCRBY" | "DECRBY" | "INCRBYFLOAT"
+        | "APPEND" | "GETSET" | "SETRANGE" | "GETRANGE" => "string",
+        "SETBIT" | "GETBIT" | "BITCOUNT" | "BITPOS" | "BITOP" => "bitmap",
+        "SAVE" | "BGSAVE" | "LASTSAVE" | "BGREWRITEAOF" | "SHUTDOWN" | "CONFIG" | "INFO" | "DBSIZE"
+        | "FLUSHDB" | "FLUSHALL" | "SWAPDB" | "MEMORY" | "DEBUG" | "COMMAND" | "SLOWLOG" | "LATENCY" | "LOLWUT" | "MONITOR"
+        | "REPLICAOF" | "SLAVEOF" | "SYNC" | "PSYNC" | "REPLCONF" => "server",
+        "SELECT" | "AUTH" | "HELLO" | "CLIENT" | "RESET" | "QUIT" | "PING" => "connection",
+        "MULTI" | "EXEC" | "DISCARD" | "WATCH" | "UNWATCH" => "transactions",
+        "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "PUBLISH" => "pubsub",
+        "LPUSH" | "RPUSH" | "LPUSHX" | "RPUSHX" | "LPOP" | "RPOP" | "BLPOP" | "BRPOP" | "LLEN" | "LRANGE" | "LPOS"
+        | "LTRIM" | "LINSERT" | "LREM" => "list",
+        "SADD" | "SREM" | "SMOVE" | "SMEMBERS" | "SSCAN" | "SISMEMBER" | "SMISMEMBER" | "SCARD" | "SPOP"
+        | "SRANDMEMBER" | "SINTER" | "SINTERCARD" | "SUNION" | "SDIFF" | "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" => "set",
+        "PFADD" | "PFCOUNT" | "PFMERGE" => "hyperloglog",
+        "GEOADD" | "GEOPOS" | "GEODIST" | "GEOSEARCH" => "geo",
+        "XADD" | "XLEN" | "XRANGE" | "XREVRANGE" => "stream",
+        _ if cmd.starts_with('H') => "hash",
+        _ if cmd.starts_with('Z') => "sorted-set",
+        _ => "generic",
+    }
+}
+
+// Writes that can add data, refused (after trying to evict) once maxmemory
+// is reached. Commands that only delete or shrink are always let through.
+fn may_grow(cmd: &str) -> bool {
+    matches!(
+        cmd,
+        "SET" | "SETEX" | "PSETEX" | "MSET" | "SETBIT" | "BITOP" | "COPY" | "RESTORE"
+            | "INCR" | "DECR" | "INCRBY" | "DECRBY" | "INCRBYFLOAT" | "APPEND" | "GETSET" | "SETRANGE"
+            | "LPUSH" | "RPUSH" | "LPUSHX" | "RPUSHX" | "LINSERT"
+            | "HSET" | "HMSET" | "HINCRBY" | "HINCRBYFLOAT"
+            | "SADD" | "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE"
+            | "ZADD" | "ZINCRBY"
+            | "PFADD" | "PFMERGE" | "GEOADD" | "XADD"
+    )
+}
+
+// The keys a command names, or None when it needs the whole keyspace
+fn command_keys<'a>(cmd: &str, parts: &'a [Vec<u8>]) -> Option<Vec<&'a [u8]>> {
+    // Of the DEBUG subcommands, OBJECT and LISTPACK-ENTRIES name a key and RELOAD
+    // replaces them all
+    if cmd == "DEBUG" {
+        match parts.get(1).map(|sub| upper(sub)).as_deref() {
+            Some("OBJECT" | "LISTPACK-ENTRIES") => return Some(parts.get(2).map(|key| key.as_slice()).into_iter().collect()),
+            Some("RELOAD") => return None,
+            _ => {}
+        }
+    }
+    let (first, last, step) = key_spec(cmd)?;
+    if first == 0 {
+        return Some(Vec::new());
+    }
+    // The keys follow a count of them
+    if cmd == "SINTERCARD" {
+        let count = parts.get(1).and_then(|n| parse_num::<usize>(n)).unwrap_or(0);
+        return Some(parts.iter().skip(first).take(count).map(|key| key.as_slice()).collect());
+    }
+    let last = if last < 0 { parts.len() as isize + last } else { last };
+    Some((first..=last.max(0) as usize).step_by(step).filter_map(|i| parts.get(i).map(|key| key.as_slice())).collect())
+}
+
+// A reply to a client, encoded when written back to the socket in the RESP
+// version the connection negotiated. The RESP3 types fall back to their
+// nearest RESP2 equivalent.
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+    NilArray,
+    // Field/value pairs, sent as a flat array in RESP2
+    Map(Vec<(Reply, Reply)>),
+    // Unordered distinct elements, an array in RESP2
+    Set(Vec<Reply>),
+    // A bulk string in RESP2
+    Double(f64),
+    // Plain text such as INFO's, a bulk string in RESP2
+    Verbatim(String),
+    // Out-of-band data such as pub/sub messages, an array in RESP2
+    Push(Vec<Reply>),
+    // The command already queued its replies on the session's outbox
+    Sent,
+}
+
+impl Reply {
+    fn ok() -> Reply {
+        Reply::Simple("OK".to_string())
+    }
+    
+    fn error(msg: impl Into<String>) -> Reply {
+        Reply::Error(msg.into())
+    }
+    
+    fn bulk(data: &[u8]) -> Reply {
+        Reply::Bulk(data.to_vec())
+    }
+    
+    fn encode(&self, out: &mut Vec<u8>, protocol: u8) {
+        let resp3 = protocol >= 3;
+        let items = |out: &mut Vec<u8>, kind: char, items: &[Reply]| {
+            out.extend_from_slice(format!("{}{}\r\n", kind, items.len()).as_bytes());
+            for item in items {
+                item.encode(out, protocol);
+            }
+        };
+        match self {
+            Reply::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Error(e) => out.extend_from_slice(format!("-{}\r\n", e).as_bytes()),
+            Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(data) => {
+                out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Nil | Reply::NilArray if resp3 => out.extend_from_slice(b"_\r\n"),
+            Reply::Nil => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(elements) => items(out, '*', elements),
+            Reply::NilArray => out.extend_from_slice(b"*-1\r\n"),
+            Reply::Map(pairs) => {
+                if resp3 {
+                    out.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes());
+                } else {
+                    out.extend_from_slice(format!("*{}\r\n", pairs.len() * 2).as_bytes());
+                }
+                for (field, value) in pairs {
+                    field.encode(out, protocol);
+                    value.encode(out, protocol);
+                }
+            }
+            Reply::Set(elements) => items(out, if resp3 { '~' } else { '*' }, elements),
+            Reply::Push(elements) => items(out, if resp3 { '>' } else { '*' }, elements),
+            Reply::Double(n) if resp3 => out.extend_from_slice(format!(",{}\r\n", format_float(*n)).as_bytes()),
+            Reply::Double(n) => Reply::Bulk(format_float(*n).into_bytes()).encode(out, protocol),
+            Reply::Verbatim(text) if resp3 => {
+                out.extend_from_slice(format!("={}\r\ntxt:{}\r\n", text.len() + 4, text).as_bytes());
+            }
+            Reply::Verbatim(text) => Reply::bulk(text.as_bytes()).encode(out, protocol),
+            Reply::Sent => {}
+        }
+    }
+    
+    fn to_bytes(&self, protocol: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out, protocol);
+        out
+    }
+}
+
+// Custom serialization for Option<Duration>
+mod option_duration {
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+        Option::<u64>::deserialize(d)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => CONFIG.get_or_init(|| RwLock::new(config)),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            eprintln!("Usage: redrust [--bind addr] [--port n] [--dbfilename file] [--unixsocket path] [--requirepass password] [--save \"seconds changes ...\"] [--appendonly yes|no] [--appendfilename file] [--maxclients n] [--maxmemory bytes] [--maxmemory-policy policy] [--notify-keyspace-events flags] [--log-expirations yes|no] [--hz n] [--slowlog-log-slower-than micros] [--slowlog-max-len n] [--latency-monitor-threshold ms] [--list-max-listpack-size n]");
+            std::process::exit(1);
+        }
+    };
+    STARTED_AT.get_or_init(Instant::now);
+    let store: Store = Arc::new(Keyspace::new());
+    
+    // Try to load existing data
+    let dbfilename = config().dbfilename.clone();
+    if config().appendonly {
+        // The AOF is the more complete record; the snapshot only seeds a new one
+        let appendfilename = config().appendfilename.clone();
+        if std::path::Path::new(&appendfilename).exists() {
+            load_aof(&store, &appendfilename);
+        } else {
+            load_data(&store, &dbfilename);
+            if let Err(e) = rewrite_aof(&store, &appendfilename) {
+                eprintln!("Error: can't create {}: {}", appendfilename, e);
+                std::process::exit(1);
+            }
+        }
+        if let Err(e) = open_aof(&store, &appendfilename) {
+            eprintln!("Error: can't open {}: {}", appendfilename, e);
+            std::process::exit(1);
+        }
+    } else {
+        load_data(&store, &dbfilename);
+    }
+    // As in Redis, startup counts as the last save until a real one happens
+    LAST_SAVE.store(unix_time().as_secs() as i64, Ordering::Relaxed);
+    
+    // Logging happens on its own thread, so the sweep only pays for a send
+    if config().log_expirations {
+        let (sender, expired) = std::sync::mpsc::channel::<(usize, Vec<u8>)>();
+        let _ = store.expired_keys.set(sender);
+        std::thread::spawn(move || {
+            for (db, key) in expired {
+                eprintln!("Expired key '{}' in database {}", String::from_utf8_lossy(&key), db);
+            }
+        });
+    }
+    
+    // Cleanup thread for expired keys, running `hz` expiry cycles a second
+    let cleanup_store = Arc::clone(&store);
+    std::thread::spawn(move || {
+        loop {
+            let interval = Duration::from_millis(1000 / config().hz);
+            std::thread::sleep(interval);
+            if ACTIVE_EXPIRE.load(Ordering::Relaxed) {
+                let started = Instant::now();
+                cleanup_expired(&cleanup_store, interval);
+                record_latency(&cleanup_store, "expire-cycle", started.elapsed());
+            }
+        }
+    });
+    
+    // Save thread: snapshot once any save point's time and change count are reached
+    let save_store = Arc::clone(&store);
+    std::thread::spawn(move || {
+        let mut last_save = Instant::now();
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+            let dirty = DIRTY.load(Ordering::Relaxed);
+            if dirty == 0 {
+                // Nothing to write, whether or not someone else just saved
+                last_save = Instant::now();
+                continue;
+            }
+            let elapsed = last_save.elapsed().as_secs();
+            let due = config().save_points.iter().any(|&(seconds, changes)| elapsed >= seconds && dirty >= changes);
+            // A BGSAVE already running counts as this save; try again next tick
+            if due && !BGSAVE_RUNNING.swap(true, Ordering::SeqCst) {
+                let filename = config().dbfilename.clone();
+                match save_data(&save_store, &filename) {
+                    Ok(()) => println!("{} changes in {} seconds. Saved to {}", dirty, elapsed, filename),
+                    Err(e) => eprintln!("Automatic save failed: {}", e),
+                }
+                BGSAVE_RUNNING.store(false, Ordering::SeqCst);
+                last_save = Instant::now();
+            }
+        }
+    });
+    
+    let (bind, port) = (config().bind.clone(), config().port);
+    let listener = match TcpListener::bind((bind.as_str(), port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Error: failed to bind {}:{}: {}", bind, port, e);
+            std::process::exit(1);
+        }
+    };
+    println!("🦀 RedRust listening on {}", listener.local_addr().unwrap());
+    println!("   Commands: SET, SETEX, PSETEX, GET, MSET, MGET, GETEX, INCR, DECR, INCRBY, DECRBY, INCRBYFLOAT, APPEND, GETSET, SETRANGE, GETRANGE, DEL, UNLINK, TOUCH, COPY, RENAME, RENAMENX, DUMP, RESTORE, SORT, KEYS, SCAN, EXPIRE, EXPIREAT, PEXPIREAT, EXPIRETIME, PEXPIRETIME, TTL, TYPE, DBSIZE, RANDOMKEY, SELECT, SWAPDB, FLUSHDB, FLUSHALL, INFO, MEMORY, OBJECT, CONFIG, DEBUG, COMMAND, SLOWLOG, LATENCY, LOLWUT, MONITOR, WAIT, REPLICAOF, SLAVEOF, AUTH, HELLO, CLIENT, RESET, QUIT, PING");
+    println!("   Bitmaps: SETBIT, GETBIT, BITCOUNT, BITPOS, BITOP");
+    println!("   Transactions: MULTI, EXEC, DISCARD, WATCH, UNWATCH");
+    println!("   Pub/Sub: SUBSCRIBE, UNSUBSCRIBE, PSUBSCRIBE, PUNSUBSCRIBE, PUBLISH");
+    println!("   Lists: LPUSH, RPUSH, LPUSHX, RPUSHX, LPOP, RPOP, BLPOP, BRPOP, LLEN, LRANGE, LTRIM, LINSERT, LREM, LPOS");
+    println!("   Hashes: HSET, HMSET, HGET, HMGET, HDEL, HGETALL, HKEYS, HVALS, HLEN, HEXISTS, HINCRBY, HINCRBYFLOAT, HSCAN,");
+    println!("           HRANDFIELD");
+    println!("   Sets: SADD, SREM, SMOVE, SMEMBERS, SISMEMBER, SMISMEMBER, SCARD, SPOP, SRANDMEMBER, SINTER, SINTERCARD, SUNION, SDIFF");
+    println!("         SINTERSTORE, SUNIONSTORE, SDIFFSTORE, SSCAN");
+    println!("   Sorted sets: ZADD, ZSCORE, ZRANGE, ZRANGEBYSCORE, ZCOUNT, ZINCRBY, ZPOPMIN, ZPOPMAX,");
+    println!("                ZREM, ZCARD, ZRANDMEMBER, ZSCAN");
+    println!("   HyperLogLogs: PFADD, PFCOUNT, PFMERGE");
+    println!("   Geo: GEOADD, GEOPOS, GEODIST, GEOSEARCH");
+    println!("   Streams: XADD, XLEN, XRANGE, XREVRANGE");
+    println!("   Persistence: SAVE, BGSAVE, LASTSAVE, BGREWRITEAOF, SHUTDOWN");
+    
+    if let Some(path) = config().unixsocket.clone() {
+        let unix_listener = match bind_unix_socket(&path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Error: failed to bind unix socket {}: {}", path, e);
+                std::process::exit(1);
+            }
+        };
+        println!("🦀 RedRust listening on unix socket {}", path);
+        let store = Arc::clone(&store);
+        tokio::spawn(async move {
+            loop {
+                match unix_listener.accept().await {
+                    Ok((stream, _)) => {
+                        let (reader, writer) = stream.into_split();
+                        accept_client(reader, writer, format!("unix:{}", path), &store);
+                    }
+                    Err(e) => eprintln!("Error: {}", e),
+                }
+            }
+        });
+    }
+    
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            eprintln!("Error: can't listen for SIGTERM: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    // One task per connection rather than one thread
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let (reader, writer) = stream.into_split();
+                    accept_client(reader, writer, peer.to_string(), &store);
+                }
+                Err(e) => eprintln!("Error: {}", e),
+            },
+            // Ctrl-C or SIGTERM: save and exit; if the save fails keep serving
+            // rather than lose the writes
+            _ = tokio::signal::ctrl_c() => eprintln!("Error: {}", shutdown(&store, &store.read_all(), true)),
+            _ = terminate.recv() => eprintln!("Error: {}", shutdown(&store, &store.read_all(), true)),
+        }
+    }
+}
+
+// Bind the unix socket, replacing a socket file left behind by a previous
+// run. A file that still accepts connections belongs to a live server.
+fn bind_unix_socket(path: &str) -> io::Result<UnixListener> {
+    if std::fs::symlink_metadata(path).is_ok() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "another server is listening on it"));
+        }
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+const OUT_OF_MEMORY: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+static EVICTED_KEYS: AtomicU64 = AtomicU64::new(0);
+
+// Evict keys as maxmemory-policy says until memory use is back under
+// maxmemory; false if that isn't possible. Runs before the command takes its
+// own locks, one key (and shard) at a time.
+fn make_room(store: &Store) -> bool {
+    loop {
+        let (limit, policy, samples) = {
+            let config = config();
+            (config.maxmemory, config.maxmemory_policy, config.maxmemory_samples)
+        };
+        if limit == 0 || USED_MEMORY.load(Ordering::Relaxed) <= limit {
+            return true;
+        }
+        if policy == MaxmemoryPolicy::NoEviction {
+            return false;
+        }
+        let Some((index, key)) = eviction_candidate(store, policy, samples) else {
+            return false;
+        };
+        
+        let mut shards = store.lock(Some(&[&key]), true);
+        let mut db = shards.db(index);
+        if db.remove(&key).is_some() {
+            EVICTED_KEYS.fetch_add(1, Ordering::Relaxed);
+            notify_keyspace_event(index, NOTIFY_EVICTED, "evicted", &key);
+            // Logged as a DEL so replaying the AOF doesn't bring the key
+            // back, and replicas drop it too
+            log_write(store, index, &[b"DEL".to_vec(), key], "DEL", &Reply::Integer(1), &db);
+        }
+        drop(shards);
+        publish_keyspace_events(store);
+    }
+}
+
+// Like Redis, compare a small sample of keys rather than keep every key in
+// LRU order: `samples` keys in a row, from a random point in a random shard
+fn eviction_candidate(store: &Store, policy: MaxmemoryPolicy, samples: usize) -> Option<(usize, Vec<u8>)> {
+    let start = rng().below(SHARDS);
+    for offset in 0..SHARDS {
+        let dbs = store.shards[(start + offset) % SHARDS].read().unwrap();
+        let candidates = || {
+            dbs.iter()
+                .enumerate()
+                .flat_map(|(index, db)| db.iter().map(move |(key, entry)| (index, key, entry)))
+                .filter(|(_, _, entry)| !policy.volatile() || entry.expires_at.is_some())
+        };
+        let count = candidates().count();
+        if count == 0 {
+            continue;
+        }
+        let first = rng().below(count);
+        let mut sample = candidates().chain(candidates()).skip(first).take(samples.min(count));
+        let victim = match policy {
+            MaxmemoryPolicy::AllKeysRandom => sample.next(),
+            MaxmemoryPolicy::VolatileTtl => sample.min_by_key(|(_, _, entry)| entry.expires_at),
+            _ => sample.min_by_key(|(_, _, entry)| entry.last_access.load(Ordering::Relaxed)),
+        };
+        return victim.map(|(index, key, _)| (index, key.clone()));
+    }
+    None
+}
+
+// Off while DEBUG SET-ACTIVE-EXPIRE 0 is in effect, leaving expired keys to be
+// deleted only when a command touches them
+static ACTIVE_EXPIRE: AtomicBool = AtomicBool::new(true);
+
+// Keys with a TTL each expiry round looks at per database, and the share of
+// them (in percent) that must have expired for the cycle to go another round
+const EXPIRE_SAMPLES: usize = 20;
+const EXPIRE_REPEAT_PERCENT: usize = 25;
+
+// One expiry cycle, Redis-style: rather than walking the whole keyspace,
+// each round samples a few keys with a TTL from every database and deletes
+// those that expired. While many of them had, more are likely waiting, so
+// the shard gets another round, until a quarter of `interval` is spent.
+// One shard at a time, so clients only ever wait for a slice of the cycle.
+fn cleanup_expired(store: &Store, interval: Duration) {
+    let deadline = Instant::now() + interval / 4;
+    for shard in &store.shards {
+        loop {
+            let mut dbs = shard.write().unwrap();
+            let now = Instant::now();
+            let mut sampled = 0;
+            let mut expired = Vec::new();
+            for (index, db) in dbs.iter_mut().enumerate() {
+                for _ in 0..EXPIRE_SAMPLES.min(db.volatile.len()) {
+                    let Some(key) = db.random_volatile() else {
+                        break;
+                    };
+                    sampled += 1;
+                    if db[key].expires_at.is_some_and(|exp| exp <= now) {
+                        let key = key.clone();
+                        db.remove(&key);
+                        notify_keyspace_event(index, NOTIFY_EXPIRED, "expired", &key);
+                        expired.push((index, key));
+                    }
+                }
+            }
+            drop(dbs);
+            publish_keyspace_events(store);
+            let again = expired.len() * 100 > sampled * EXPIRE_REPEAT_PERCENT;
+            if let Some(sender) = store.expired_keys.get() {
+                for key in expired {
+                    let _ = sender.send(key);
+                }
+            }
+            if !again || Instant::now() >= deadline {
+                break;
+            }
+        }
+    }
+}
+
+fn is_expired(entry: &Entry) -> bool {
+    entry.expires_at.map(|exp| exp <= Instant::now()).unwrap_or(false)
+}
+
+// Drop a key whose TTL has passed, so the command that found it sees it gone
+fn purge_expired(db: &mut Db, key: &[u8]) {
+    if db.get(key).is_some_and(is_expired) {
+        db.remove(key);
+        notify_keyspace_event(db.index, NOTIFY_EXPIRED, "expired", key);
+    }
+}
+
+// Compute SINTER/SUNION/SDIFF over the given keys. Missing keys count as
+// empty sets; any other value type is a WRONGTYPE error.
+fn set_algebra(db: &Db, op: &str, keys: &[Vec<u8>]) -> Result<HashSet<Vec<u8>>, Reply> {
+    let empty = HashSet::new();
+    let sets = sets_at(db, keys, &empty)?;
+    let (first, rest) = sets.split_first().expect("at least one key");
+    let result = match op {
+        "SINTER" => first
+            .iter()
+            .filter(|member| rest.iter().all(|set| set.contains(*member)))
+            .cloned()
+            .collect(),
+        "SUNION" => sets.iter().flat_map(|set| set.iter().cloned()).collect(),
+        _ => first
+            .iter()
+            .filter(|member| !rest.iter().any(|set| set.contains(*member)))
+            .cloned()
+            .collect(),
+    };
+    Ok(result)
+}
+
+// The sets stored at `keys`, with `empty` standing in for missing keys, or
+// WRONGTYPE if any key holds something else
+fn sets_at<'a>(
+    db: &'a Db,
+    keys: &[Vec<u8>],
+    empty: &'a HashSet<Vec<u8>>,
+) -> Result<Vec<&'a HashSet<Vec<u8>>>, Reply> {
+    let mut sets = Vec::with_capacity(keys.len());
+    for key in keys {
+        match db.get(key) {
+            Some(entry) if !is_expired(entry) => match &entry.value {
+                Value::Set(set) => sets.push(set),
+                _ => return Err(Reply::error(WRONG_TYPE)),
+            },
+            _ => sets.push(empty),
+        }
+    }
+    Ok(sets)
+}
+
+// Small xorshift PRNG behind the random-member commands. A fixed seed gives
+// a reproducible sequence, which is what makes sampling testable.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // Scramble the seed (splitmix64) so small seeds still give good state
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        Rng((z ^ (z >> 31)) | 1)
+    }
+    
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    
+    // Uniform index in 0..n
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+    
+    // Up to `count` distinct items in random order (partial Fisher-Yates)
+    fn sample<T>(&mut self, mut items: Vec<T>, count: usize) -> Vec<T> {
+        let count = count.min(items.len());
+        for i in 0..count {
+            let j = i + self.below(items.len() - i);
+            items.swap(i, j);
+        }
+        items.truncate(count);
+        items
+    }
+}
+
+// Random items as the SRANDMEMBER family picks them: `count` distinct ones,
+// as many repeats as asked for a negative count, or one without a count.
+// `items` must not be empty, and `count` within RANDOM_COUNT_MAX.
+fn random_picks<T: Clone>(items: Vec<T>, count: Option<i64>) -> Vec<T> {
+    let mut rng = rng();
+    match count {
+        Some(n) if n < 0 => {
+            // Grown as it fills rather than sized up front from the count
+            let mut picks = Vec::new();
+            for _ in 0..n.unsigned_abs() {
+                picks.push(items[rng.below(items.len())].clone());
+            }
+            picks
+        }
+        Some(n) => rng.sample(items, n as usize),
+        None => rng.sample(items, 1),
+    }
+}
+
+// Largest count, either way, the SRANDMEMBER family accepts, as in Redis
+const RANDOM_COUNT_MAX: i64 = i64::MAX / 2;
+
+fn parse_random_count(arg: &[u8]) -> Result<i64, Reply> {
+    match parse_num::<i64>(arg) {
+        Some(n) if (-RANDOM_COUNT_MAX..=RANDOM_COUNT_MAX).contains(&n) => Ok(n),
+        Some(_) => Err(Reply::error("ERR value is out of range")),
+        None => Err(Reply::error("ERR value is not an integer or out of range")),
+    }
+}
+
+// Parse the `count [WITHVALUES]` tail of HRANDFIELD and ZRANDMEMBER
+fn random_count(parts: &[Vec<u8>], with: &str) -> Result<(Option<i64>, bool), Reply> {
+    let count = match parts.get(2) {
+        Some(arg) => Some(parse_random_count(arg)?),
+        None => None,
+    };
+    match parts.get(3) {
+        None => Ok((count, false)),
+        Some(option) if parts.len() == 4 && upper(option) == with => Ok((count, true)),
+        Some(_) => Err(Reply::error("ERR syntax error")),
+    }
+}
+
+// Process-wide RNG, seeded from REDRUST_SEED when set and from the clock otherwise
+fn rng() -> MutexGuard<'static, Rng> {
+    static RNG: OnceLock<Mutex<Rng>> = OnceLock::new();
+    RNG.get_or_init(|| {
+        let seed = std::env::var("REDRUST_SEED")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or_else(|| unix_time().as_nanos() as u64);
+        Mutex::new(Rng::new(seed))
+    })
+    .lock()
+    .unwrap()
+}
+
+// Wall-clock time since the Unix epoch
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+// Parse a numeric argument sent as text
+fn parse_num<T: std::str::FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+// Resolve LRANGE-style start/stop indexes (negative ones count from the end)
+// against a collection length. Returns the inclusive bounds, or None when the
+// range is empty.
+fn resolve_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let stop = if stop < 0 { len + stop } else { stop }.min(len - 1);
+    if start > stop {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+// Bit offsets stay below 2^32, keeping bitmaps within Redis' 512MB string limit
+const MAX_BIT_OFFSET: u64 = 1 << 32;
+
+// The longest string SETRANGE may grow a value to, as in Redis
+const MAX_STRING_LEN: u64 = 512 * 1024 * 1024;
+
+// Bit `offset` of a string, counting from the most significant bit of the
+// first byte as Redis does; bits past the end are 0
+fn bit_at(bytes: &[u8], offset: usize) -> bool {
+    bytes.get(offset / 8).is_some_and(|byte| byte & (0x80 >> (offset % 8)) != 0)
+}
+
+// First offset in from..=to whose bit equals `bit`, stepping over whole
+// bytes that can't contain it
+fn find_bit(bytes: &[u8], bit: bool, from: usize, to: usize) -> Option<usize> {
+    let skip = if bit { 0x00 } else { 0xff };
+    let mut offset = from;
+    while offset <= to {
+        if offset.is_multiple_of(8) && offset + 7 <= to && bytes[offset / 8] == skip {
+            offset += 8;
+            continue;
+        }
+        if bit_at(bytes, offset) == bit {
+            return Some(offset);
+        }
+        offset += 1;
+    }
+    None
+}
+
+// Format a float the way Redis replies with it: no exponent, no trailing ".0"
+fn format_float(n: f64) -> String {
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    format!("{}", n)
+}
+
+// Command names and option keywords are case-insensitive
+fn upper(arg: &[u8]) -> String {
+    String::from_utf8_lossy(arg).to_uppercase()
+}
+
+// Redis-style glob: `*`, `?`, `[abc]`, `[a-z]`, `[^...]` and `\` escapes,
+// anchored at both ends of the string. On a mismatch only the last `*` is
+// moved on by a byte, so matching takes O(pattern × string) time at worst,
+// however many stars there are.
+fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // The last star's position in the pattern, and where in the string the
+    // part after it is being tried from
+    let mut star = None;
+    while s < string.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, s));
+            p += 1;
+        } else if let Some(next) = (p < pattern.len()).then(|| glob_step(pattern, p, string[s])).flatten() {
+            p = next;
+            s += 1;
+        } else if let Some((star_p, star_s)) = star {
+            star = Some((star_p, star_s + 1));
+            p = star_p + 1;
+            s = star_s + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// Whether the pattern element at `p`, anything but a star, matches byte
+// `c`, and if so where the next element starts
+fn glob_step(pattern: &[u8], mut p: usize, c: u8) -> Option<usize> {
+    match pattern[p] {
+        b'?' => Some(p + 1),
+        b'[' => {
+            p += 1;
+            let negate = p < pattern.len() && pattern[p] == b'^';
+            if negate {
+                p += 1;
+            }
+            let mut matched = false;
+            while p < pattern.len() && pattern[p] != b']' {
+                if pattern[p] == b'\\' && p + 1 < pattern.len() {
+                    p += 1;
+                    matched |= pattern[p] == c;
+                } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+                    let (lo, hi) = (pattern[p].min(pattern[p + 2]), pattern[p].max(pattern[p + 2]));
+                    matched |= (lo..=hi).contains(&c);
+                    p += 2;
+                } else {
+                    matched |= pattern[p] == c;
+                }
+                p += 1;
+            }
+            // An unclosed class runs to the end of the pattern
+            (matched != negate).then_some((p + 1).min(pattern.len()))
+        }
+        // A backslash makes the next pattern byte literal
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        literal => (literal == c).then_some(p + 1),
+    }
+}
+
+// Cursor plus MATCH/COUNT/TYPE options shared by SCAN, HSCAN, SSCAN and ZSCAN
+struct ScanArgs<'a> {
+    cursor: usize,
+    pattern: Option<&'a [u8]>,
+    count: usize,
+    kind: Option<String>,
+}
+
+impl<'a> ScanArgs<'a> {
+    // `args` starts at the cursor; TYPE is only accepted by the keyspace SCAN
+    fn parse(args: &'a [Vec<u8>], allow_type: bool) -> Result<ScanArgs<'a>, Reply> {
+        let cursor = match parse_num::<usize>(&args[0]) {
+            Some(c) => c,
+            None => return Err(Reply::error("ERR invalid cursor")),
+        };
+        let mut scan = ScanArgs { cursor, pattern: None, count: 10, kind: None };
+        for option in args[1..].chunks(2) {
+            if option.len() != 2 {
+                return Err(Reply::error("ERR syntax error"));
+            }
+            match upper(&option[0]).as_str() {
+                "MATCH" => scan.pattern = Some(&option[1]),
+                "COUNT" => match parse_num::<usize>(&option[1]) {
+                    Some(n) if n > 0 => scan.count = n,
+                    _ => return Err(Reply::error("ERR syntax error")),
+                },
+                "TYPE" if allow_type => scan.kind = Some(String::from_utf8_lossy(&option[1]).to_lowercase()),
+                _ => return Err(Reply::error("ERR syntax error")),
+            }
+        }
+        Ok(scan)
+    }
+    
+    fn matches(&self, key: &[u8]) -> bool {
+        self.pattern.is_none_or(|p| glob_match(p, key))
+    }
+    
+    // The cursor is how many items earlier calls have walked. Each call only
+    // looks at the next `count` of them, so the lock is held briefly; items
+    // added or removed mid-scan may be missed or repeated. Returns the next
+    // cursor (0 once the walk is done) and the items `keep` accepted.
+    fn page<T>(&self, items: impl Iterator<Item = T>, len: usize, keep: impl Fn(&T) -> bool) -> (usize, Vec<T>) {
+        let batch: Vec<T> = items.skip(self.cursor).take(self.count).collect();
+        let next = if batch.len() == self.count && self.cursor + self.count < len {
+            self.cursor + self.count
+        } else {
+            0
+        };
+        (next, batch.into_iter().filter(|item| keep(item)).collect())
+    }
+}
+
+// Translate an EX/PX/EXAT/PXAT option argument into an Instant-based expiry
+fn parse_expire_option(option: &str, arg: &[u8], cmd: &str) -> Result<Instant, Reply> {
+    let amount = match parse_num::<i64>(arg) {
+        Some(n) => n,
+        None => return Err(Reply::error("ERR value is not an integer or out of range")),
+    };
+    let invalid = || Reply::error(format!("ERR invalid expire time in '{}' command", cmd));
+    if amount <= 0 {
+        return Err(invalid());
+    }
+    let amount = amount as u64;
+    
+    let remaining = match option {
+        "EX" => Duration::from_secs(amount),
+        "PX" => Duration::from_millis(amount),
+        // Absolute times already in the past expire the key immediately
+        "EXAT" => Duration::from_millis(amount.saturating_mul(1000))
+            .saturating_sub(unix_time()),
+        _ => Duration::from_millis(amount).saturating_sub(unix_time()),
+    };
+    Instant::now().checked_add(remaining).ok_or_else(invalid)
+}
+
+// Unix time of the last successful snapshot, or of startup before the first
+static LAST_SAVE: AtomicI64 = AtomicI64::new(0);
+
+fn last_save_time() -> i64 {
+    LAST_SAVE.load(Ordering::Relaxed)
+}
+
+// Build the INFO text for one section, or all of them for "all"/"default"
+fn info(store: &Store, shards: &Shards, section: &str) -> String {
+    let uptime = STARTED_AT.get().map(|t| t.elapsed().as_secs()).unwrap_or(0);
+    let live = |index: usize| shards.pieces(index).flat_map(|db| db.values()).filter(|entry| !is_expired(entry)).count();
+    let mut sections = Vec::new();
+    
+    sections.push(("server", format!(
+        "# Server\r\nredrust_version:{}\r\nprocess_id:{}\r\ntcp_port:{}\r\nuptime_in_seconds:{}\r\nuptime_in_days:{}\r\n",
+        env!("CARGO_PKG_VERSION"),
+        std::process::id(),
+        config().port,
+        uptime,
+        uptime / 86400,
+    )));
+    sections.push(("clients", format!(
+        "# Clients\r\nconnected_clients:{}\r\nmaxclients:{}\r\n",
+        CONNECTED_CLIENTS.load(Ordering::Relaxed),
+        config().maxclients,
+    )));
+    // used_memory is the estimate maxmemory works with, not what the
+    // allocator holds
+    sections.push(("memory", format!(
+        "# Memory\r\nused_memory:{}\r\nmaxmemory:{}\r\nmaxmemory_policy:{}\r\ntotal_keys:{}\r\n",
+        USED_MEMORY.load(Ordering::Relaxed),
+        config().maxmemory,
+        config().maxmemory_policy.name(),
+        (0..DATABASES).map(live).sum::<usize>(),
+    )));
+    sections.push(("persistence", format!(
+        "# Persistence\r\nrdb_bgsave_in_progress:{}\r\nrdb_last_save_time:{}\r\n",
+        BGSAVE_RUNNING.load(Ordering::Relaxed) as u8,
+        last_save_time(),
+    )));
+    let replication = store.replication.lock().unwrap();
+    let mut role = match &replication.master {
+        Some(master) => format!(
+            "role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\nmaster_link_status:{}\r\n",
+            master.host,
+            master.port,
+            if master.link_up.load(Ordering::Relaxed) { "up" } else { "down" },
+        ),
+        None => "role:master\r\n".to_string(),
+    };
+    role.push_str(&format!(
+        "connected_slaves:{}\r\nmaster_replid:{}\r\nmaster_repl_offset:{}\r\n",
+        replication.replicas.len(),
+        replication.replid,
+        replication.offset,
+    ));
+    drop(replication);
+    sections.push(("replication", format!("# Replication\r\n{}", role)));
+    sections.push(("stats", format!(
+        "# Stats\r\nevicted_keys:{}\r\n",
+        EVICTED_KEYS.load(Ordering::Relaxed),
+    )));
+    let mut keyspace = "# Keyspace\r\n".to_string();
+    for index in 0..DATABASES {
+        let keys = live(index);
+        if keys > 0 {
+            let expires = shards
+                .pieces(index)
+                .flat_map(|db| db.values())
+                .filter(|e| e.expires_at.is_some() && !is_expired(e))
+                .count();
+            keyspace.push_str(&format!("db{}:keys={},expires={}\r\n", index, keys, expires));
+        }
+    }
+    sections.push(("keyspace", keyspace));
+    let mut commandstats = "# Commandstats\r\n".to_string();
+    for (name, stats) in command_stats() {
+        let calls = stats.calls.load(Ordering::Relaxed);
+        if calls > 0 {
+            let usec = stats.usec.load(Ordering::Relaxed);
+            commandstats.push_str(&format!(
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2}\r\n",
+                name.to_lowercase(),
+                calls,
+                usec,
+                usec as f64 / calls as f64,
+            ));
+        }
+    }
+    sections.push(("commandstats", commandstats));
+    
+    // Like Redis, the default sections leave out commandstats
+    let all = section == "all" || section == "everything";
+    let default = all || section == "default";
+    sections
+        .into_iter()
+        .filter(|(name, _)| all || (default && *name != "commandstats") || *name == section)
+        .map(|(_, text)| text)
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+// Snapshot files start with this magic and a format version byte
+const RDB_MAGIC: &[u8] = b"REDRUST";
+const RDB_VERSION: u8 = 1;
+
+// Value type tags in the binary snapshot
+const RDB_STRING: u8 = 0;
+const RDB_LIST: u8 = 1;
+const RDB_HASH: u8 = 2;
+const RDB_SET: u8 = 3;
+const RDB_ZSET: u8 = 4;
+const RDB_HYPERLOGLOG: u8 = 5;
+const RDB_STREAM: u8 = 6;
+
+// Set once shutdown starts, so no new clients are let in while saving
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+// Save the dataset (unless `save` is false), flush the AOF and exit; used
+// by both SHUTDOWN and the signal handlers. Only returns, with the reason,
+// if the final save failed.
+fn shutdown(store: &Store, shards: &Shards, save: bool) -> String {
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+    if save {
+        println!("Shutting down, saving the final snapshot...");
+        let filename = config().dbfilename.clone();
+        if let Err(e) = write_snapshot(&encode_snapshot(shards), &filename) {
+            SHUTTING_DOWN.store(false, Ordering::Relaxed);
+            return format!("can't shut down, the final save to {} failed: {}", filename, e);
+        }
+        println!("DB saved on disk to {}", filename);
+    } else {
+        println!("Shutting down without saving...");
+    }
+    
+    if let Some(aof) = store.aof.lock().unwrap().as_mut()
+        && let Err(e) = aof.file.sync_all()
+    {
+        eprintln!("Error: can't flush the AOF: {}", e);
+    }
+    if let Some(path) = config().unixsocket.as_deref() {
+        let _ = std::fs::remove_file(path);
+    }
+    println!("🦀 RedRust is now ready to exit, bye bye...");
+    std::process::exit(0);
+}
+
+// Set while a background save runs, so a second one can't race it on the
+// same file
+static BGSAVE_RUNNING: AtomicBool = AtomicBool::new(false);
+
+// Background saves copy the live keys under the lock and encode the copy
+// after letting it go, the way a forked Redis child works from its own view
+// of memory. SAVE has no one to keep waiting and encodes in place.
+fn save_data(store: &Store, filename: &str) -> Result<(), String> {
+    let started = Instant::now();
+    let shards = store.read_all();
+    let copy = copy_dataset(&shards);
+    let dirty = DIRTY.load(Ordering::Relaxed);
+    drop(shards);
+    // Writers wait while the copy is taken, the stall Redis reports as its
+    // "fork" event
+    record_latency(store, "fork", started.elapsed());
+    let snapshot = encode_databases(
+        copy.iter().map(|live| live.iter().map(|(key, value, expires_at)| (key.as_slice(), value, *expires_at)).collect()).collect(),
+    );
+    write_snapshot(&snapshot, filename)?;
+    clear_dirty(dirty);
+    Ok(())
+}
+
+fn write_snapshot(snapshot: &[u8], filename: &str) -> Result<(), String> {
+    // Write a temp file next to the target and rename it into place, so a
+    // crash mid-write never leaves a truncated snapshot behind. The counter
+    // keeps a SAVE and a BGSAVE running at once from sharing a temp file.
+    static SAVES: AtomicUsize = AtomicUsize::new(0);
+    let temp = format!("{}.tmp.{}.{}", filename, std::process::id(), SAVES.fetch_add(1, Ordering::Relaxed));
+    let written = std::fs::File::create(&temp).and_then(|mut file| {
+        file.write_all(snapshot)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|()| std::fs::rename(&temp, filename)) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(format!("Write error: {}", e));
+    }
+    LAST_SAVE.store(unix_time().as_secs() as i64, Ordering::Relaxed);
+    
+    Ok(())
+}
+
+// Binary snapshot layout, all integers little-endian:
+//   "REDRUST" version:u8
+//   payload: databases:u32, then per database entries:u32 and per entry
+//            key type:u8 has_ttl:u8 [expires_at_ms:u64] value
+//   crc32 of the payload:u32
+// Byte strings are a u32 length followed by the bytes. Lists and sets are a
+// u32 count of strings, hashes a count of field/value pairs and sorted sets a
+// count of member/score pairs with the score as an f64.
+fn encode_snapshot(shards: &Shards) -> Vec<u8> {
+    encode_databases(
+        (0..DATABASES)
+            .map(|index| {
+                shards
+                    .pieces(index)
+                    .flat_map(|db| db.iter())
+                    .filter(|(_, entry)| !is_expired(entry))
+                    .map(|(key, entry)| (key.as_slice(), &entry.value, entry.expires_at))
+                    .collect()
+            })
+            .collect(),
+    )
+}
+
+// Each database's live keys with their values and expiry, owned so they
+// outlive the locks they were copied under
+type DatasetCopy = Vec<Vec<(Vec<u8>, Value, Option<Instant>)>>;
+
+fn copy_dataset(shards: &Shards) -> DatasetCopy {
+    (0..DATABASES)
+        .map(|index| {
+            shards
+                .pieces(index)
+                .flat_map(|db| db.iter())
+                .filter(|(_, entry)| !is_expired(entry))
+                .map(|(key, entry)| (key.clone(), entry.value.clone(), entry.expires_at))
+                .collect()
+        })
+        .collect()
+}
+
+// A key as the snapshot stores it: name, value and expiry
+type SnapshotKey<'a> = (&'a [u8], &'a Value, Option<Instant>);
+
+// The snapshot of the given databases' keys, in order
+fn encode_databases(databases: Vec<Vec<SnapshotKey>>) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&(databases.len() as u32).to_le_bytes());
+    for live in databases {
+        payload.extend_from_slice(&(live.len() as u32).to_le_bytes());
+        for (key, value, expires_at) in live {
+            put_bytes(&mut payload, key);
+            payload.push(value_tag(value));
+            match expires_at {
+                Some(expires_at) => {
+                    payload.push(1);
+                    payload.extend_from_slice(&(unix_millis(expires_at) as u64).to_le_bytes());
+                }
+                None => payload.push(0),
+            }
+            put_value(&mut payload, value);
+        }
+    }
+    
+    let mut out = Vec::with_capacity(RDB_MAGIC.len() + 1 + payload.len() + 4);
+    out.extend_from_slice(RDB_MAGIC);
+    out.push(RDB_VERSION);
+    out.extend_from_slice(&payload);
+    out.extend_from_slice(&crc32(&payload).to_le_bytes());
+    out
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn value_tag(value: &Value) -> u8 {
+    match value {
+        Value::String(_) => RDB_STRING,
+        Value::List(_) => RDB_LIST,
+        Value::Hash(_) => RDB_HASH,
+        Value::Set(_) => RDB_SET,
+        Value::ZSet(_) => RDB_ZSET,
+        Value::HyperLogLog(_) => RDB_HYPERLOGLOG,
+        Value::Stream(_) => RDB_STREAM,
+    }
+}
+
+// A value's contents, which follow its type tag
+fn put_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(s) => put_bytes(out, s),
+        Value::List(list) => {
+            out.extend_from_slice(&(list.len() as u32).to_le_bytes());
+            list.iter().for_each(|item| put_bytes(out, item));
+        }
+        Value::Hash(hash) => {
+            out.extend_from_slice(&(hash.len() as u32).to_le_bytes());
+            for (field, value) in hash {
+                put_bytes(out, field);
+                put_bytes(out, value);
+            }
+        }
+        Value::Set(set) => {
+            out.extend_from_slice(&(set.len() as u32).to_le_bytes());
+            set.iter().for_each(|member| put_bytes(out, member));
+        }
+        Value::ZSet(zset) => {
+            out.extend_from_slice(&(zset.len() as u32).to_le_bytes());
+            for (member, score) in zset.iter() {
+                put_bytes(out, member);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        Value::HyperLogLog(hll) => put_bytes(out, &hll.registers),
+        Value::Stream(stream) => {
+            let put_id = |out: &mut Vec<u8>, id: &StreamId| {
+                out.extend_from_slice(&id.ms.to_le_bytes());
+                out.extend_from_slice(&id.seq.to_le_bytes());
+            };
+            put_id(out, &stream.last_id);
+            out.extend_from_slice(&(stream.entries.len() as u32).to_le_bytes());
+            for (id, fields) in &stream.entries {
+                put_id(out, id);
+                out.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+                for (field, value) in fields {
+                    put_bytes(out, field);
+                    put_bytes(out, value);
+                }
+            }
+        }
+    }
+}
+
+// DUMP payload: type:u8 value version:u8 crc32:u32, with the value laid
+// out as in a snapshot and the CRC covering everything before it
+fn encode_dump(value: &Value) -> Vec<u8> {
+    let mut out = vec![value_tag(value)];
+    put_value(&mut out, value);
+    out.push(RDB_VERSION);
+    let crc = crc32(&out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+fn decode_dump(data: &[u8]) -> Result<Value, String> {
+    let wrong = || "ERR DUMP payload version or checksum are wrong".to_string();
+    let Some(split) = data.len().checked_sub(4) else {
+        return Err(wrong());
+    };
+    let (body, checksum) = data.split_at(split);
+    if body.last() != Some(&RDB_VERSION) || crc32(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err(wrong());
+    }
+    let mut reader = SnapshotReader { data: &body[..body.len() - 1] };
+    match reader.u8().and_then(|tag| decode_value(&mut reader, tag)) {
+        Ok(value) if reader.data.is_empty() => Ok(value),
+        _ => Err("ERR Bad data format".to_string()),
+    }
+}
+
+// Cursor over a binary snapshot's payload
+struct SnapshotReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < n {
+            return Err("snapshot is truncated".to_string());
+        }
+        let (head, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(head)
+    }
+    
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+    
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    
+    fn f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    
+    fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+fn decode_snapshot(data: &[u8]) -> Result<Vec<DbShard>, String> {
+    let body = &data[RDB_MAGIC.len()..];
+    match body.first() {
+        Some(&RDB_VERSION) => {}
+        Some(version) => return Err(format!("unsupported snapshot version {}", version)),
+        None => return Err("snapshot is truncated".to_string()),
+    }
+    if body.len() < 5 {
+        return Err("snapshot is truncated".to_string());
+    }
+    let (payload, checksum) = body[1..].split_at(body.len() - 5);
+    if crc32(payload) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err("checksum mismatch, the snapshot is corrupt".to_string());
+    }
+    
+    let now = Instant::now();
+    let now_ms = unix_time().as_millis() as u64;
+    let mut reader = SnapshotReader { data: payload };
+    let mut dbs = Vec::new();
+    for index in 0..reader.u32()? {
+        let mut db = DbShard::default();
+        for _ in 0..reader.u32()? {
+            let key = reader.bytes()?;
+            let (value, expires_at_ms) = decode_entry(&mut reader)
+                .map_err(|e| format!("{} in key '{}' of database {}", e, String::from_utf8_lossy(&key), index))?;
+            
+            // Skip entries that expired while the server was down
+            if let Some(ms) = expires_at_ms
+                && ms <= now_ms
+            {
+                continue;
+            }
+            let expires_at = expires_at_ms.map(|ms| now + Duration::from_millis(ms - now_ms));
+            db.insert(key, Entry::new(value, expires_at));
+        }
+        dbs.push(db);
+    }
+    Ok(dbs)
+}
+
+// A key's value and its expiry in Unix milliseconds
+fn decode_entry(reader: &mut SnapshotReader) -> Result<(Value, Option<u64>), String> {
+    let tag = reader.u8()?;
+    let expires_at_ms = match reader.u8()? {
+        0 => None,
+        _ => Some(reader.u64()?),
+    };
+    Ok((decode_value(reader, tag)?, expires_at_ms))
+}
+
+fn decode_value(reader: &mut SnapshotReader, tag: u8) -> Result<Value, String> {
+    let value = match tag {
+        RDB_STRING => Value::String(reader.bytes()?),
+        RDB_LIST => Value::List((0..reader.u32()?).map(|_| reader.bytes()).collect::<Result<_, _>>()?),
+        RDB_HASH => {
+            let mut hash = HashMap::new();
+            for _ in 0..reader.u32()? {
+                hash.insert(reader.bytes()?, reader.bytes()?);
+            }
+            Value::Hash(hash)
+        }
+        RDB_SET => Value::Set((0..reader.u32()?).map(|_| reader.bytes()).collect::<Result<_, _>>()?),
+        RDB_ZSET => {
+            let mut zset = ZSet::default();
+            for _ in 0..reader.u32()? {
+                zset.insert(reader.bytes()?, reader.f64()?);
+            }
+            Value::ZSet(zset)
+        }
+        RDB_HYPERLOGLOG => {
+            let registers = reader.bytes()?;
+            if registers.len() != HLL_REGISTERS {
+                return Err(format!("HyperLogLog with {} registers", registers.len()));
+            }
+            Value::HyperLogLog(HyperLogLog { registers })
+        }
+        RDB_STREAM => {
+            let mut stream = Stream { last_id: StreamId { ms: reader.u64()?, seq: reader.u64()? }, ..Stream::default() };
+            for _ in 0..reader.u32()? {
+                let id = StreamId { ms: reader.u64()?, seq: reader.u64()? };
+                let fields = (0..reader.u32()?).map(|_| Ok((reader.bytes()?, reader.bytes()?))).collect::<Result<_, String>>()?;
+                stream.entries.insert(id, fields);
+            }
+            Value::Stream(stream)
+        }
+        _ => return Err(format!("unknown value type {}", tag)),
+    };
+    Ok(value)
+}
+
+// CRC-32 (IEEE), as used by zlib and PNG
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+fn load_data(store: &Store, filename: &str) {
+    let data = match std::fs::read(filename) {
+        Ok(content) => content,
+        Err(_) => {
+            println!("No existing database found, starting fresh");
+            return;
+        }
+    };
+    
+    // Snapshots written before the binary format are JSON
+    let loaded = if data.starts_with(RDB_MAGIC) {
+        decode_snapshot(&data).map(|dbs| (dbs, 0))
+    } else {
+        decode_json_snapshot(&data)
+    };
+    let corrupt = format!("{}.corrupt", filename);
+    let (loaded, skipped) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            // Move the file aside so the next save can't overwrite what an
+            // operator might still recover from it
+            eprintln!("Failed to load database: {}", e);
+            match std::fs::rename(filename, &corrupt) {
+                Ok(()) => eprintln!("Moved the unreadable snapshot to {}, starting empty", corrupt),
+                Err(e) => eprintln!("Failed to move the unreadable snapshot to {}: {}", corrupt, e),
+            }
+            return;
+        }
+    };
+    if skipped > 0 {
+        // The readable keys are loaded, but keep a copy of the original
+        match std::fs::copy(filename, &corrupt) {
+            Ok(_) => eprintln!("Skipped {} unreadable keys, kept the original snapshot as {}", skipped, corrupt),
+            Err(e) => eprintln!("Failed to copy the damaged snapshot to {}: {}", corrupt, e),
+        }
+    }
+    
+    let total = install_dataset(&mut store.lock_all(), loaded);
+
+    println!("Loaded {} keys from {}", total, filename);
+}
+
+// JSON snapshots hold one object per logical database. Files written before
+// SELECT existed are a single object, which loads into database 0. Keys are
+// decoded one at a time so a damaged entry only costs that key; the count of
+// skipped keys is returned with the databases.
+fn decode_json_snapshot(data: &[u8]) -> Result<(Vec<DbShard>, usize), String> {
+    let saved = match serde_json::from_slice(data).map_err(|e| e.to_string())? {
+        serde_json::Value::Array(dbs) => dbs,
+        db @ serde_json::Value::Object(_) => vec![db],
+        _ => return Err("expected a JSON object or array of objects".to_string()),
+    };
+    
+    let mut skipped = 0;
+    let mut dbs = Vec::new();
+    for (index, saved) in saved.into_iter().enumerate() {
+        let serde_json::Value::Object(saved) = saved else {
+            return Err(format!("database {} is not a JSON object", index));
+        };
+        let mut entries = HashMap::new();
+        for (key, entry) in saved {
+            match serde_json::from_value::<SerializableEntry>(entry) {
+                Ok(entry) => {
+                    entries.insert(key, entry);
+                }
+                Err(e) => {
+                    eprintln!("Failed to load key '{}' of database {}: {}", key, index, e);
+                    skipped += 1;
+                }
+            }
+        }
+        let mut db = DbShard::default();
+        load_db(&mut db, entries);
+        dbs.push(db);
+    }
+    Ok((dbs, skipped))
+}
+
+fn load_db(db: &mut DbShard, serializable: HashMap<String, SerializableEntry>) {
+    let now_secs = unix_time().as_secs();
+    let now = Instant::now();
+    
+    for (key, entry) in serializable {
+        // Skip expired entries
+        if let Some(exp) = entry.expires_in_secs
+            && exp <= now_secs
+        {
+            continue;
+        }
+        
+        let value = match entry.value {
+            SerializableValue::String(s) => Value::String(s.0),
+            SerializableValue::List(l) => Value::List(l.into_iter().map(|b| b.0).collect()),
+            SerializableValue::Hash(h) => Value::Hash(h.into_iter().map(|(f, v)| (f.0, v.0)).collect()),
+            SerializableValue::Set(set) => Value::Set(set.into_iter().map(|m| m.0).collect()),
+            SerializableValue::ZSet(members) => {
+                let mut zset = ZSet::default();
+                for (member, score) in members {
+                    zset.insert(member.0, score.parse().unwrap_or_default());
+                }
+                Value::ZSet(zset)
+            }
+        };
+        
+        let expires_at = entry.expires_in_secs.map(|exp| {
+            let remaining = exp.saturating_sub(now_secs);
+            now + Duration::from_secs(remaining)
+        });
+        
+        db.insert(key.into_bytes(), Entry::new(value, expires_at));
+    }
+}
+
+// The append-only file: every write command, in RESP, as it was executed
+struct AppendOnlyFile {
+    file: std::fs::File,
+    // Database the file's last SELECT points at
+    db: usize,
+    // While BGREWRITEAOF runs, writes are also collected here for the new file
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+impl AppendOnlyFile {
+    fn log(&mut self, db_index: usize, command: &[u8]) {
+        let mut out = Vec::new();
+        if db_index != self.db {
+            out.extend(encode_command(&[b"SELECT", db_index.to_string().as_bytes()]));
+            self.db = db_index;
+        }
+        out.extend_from_slice(command);
+        if let Some(buffer) = self.rewrite_buffer.as_mut() {
+            buffer.extend_from_slice(&out);
+        }
+        if let Err(e) = self.file.write_all(&out) {
+            eprintln!("Error writing to the AOF: {}", e);
+        }
+    }
+}
+
+// Record a write in the AOF and stream it to replicas. Called while the
+// write's shards are still locked, so both see writes in the order they happened.
+fn log_write(store: &Store, db_index: usize, parts: &[Vec<u8>], cmd: &str, reply: &Reply, db: &Db) {
+    let mut aof = store.aof.lock().unwrap();
+    let mut replication = store.replication.lock().unwrap();
+    if aof.is_none() && replication.replicas.is_empty() {
+        return;
+    }
+    let command = replayable_write(parts, cmd, reply, db);
+    if let Some(aof) = aof.as_mut() {
+        aof.log(db_index, &command);
+    }
+    replication.propagate(db_index, &command);
+}
+
+// A write as it is logged and replicated: commands that would do something
+// else when run again are rewritten to what they did
+fn replayable_write(parts: &[Vec<u8>], cmd: &str, reply: &Reply, db: &Db) -> Vec<u8> {
+    let mut out = Vec::new();
+    if cmd == "SPOP" {
+        // Replaying a random pop could pick other members; log what went
+        let mut srem: Vec<&[u8]> = vec![b"SREM", &parts[1]];
+        match reply {
+            Reply::Bulk(member) => srem.push(member),
+            Reply::Array(items) => srem.extend(items.iter().filter_map(|item| match item {
+                Reply::Bulk(member) => Some(member.as_slice()),
+                _ => None,
+            })),
+            _ => {}
+        }
+        out.extend(encode_command(&srem));
+    } else if cmd == "XADD"
+        && let Reply::Bulk(id) = reply
+    {
+        // An auto-generated ID would come out different; log the one given
+        let mut args: Vec<&[u8]> = parts.iter().map(|p| p.as_slice()).collect();
+        args[2] = id;
+        out.extend(encode_command(&args));
+    } else {
+        let args: Vec<&[u8]> = parts.iter().map(|p| p.as_slice()).collect();
+        out.extend(encode_command(&args));
+    }
+    // Relative TTLs (EXPIRE, SET EX, ...) would restart on replay, so pin
+    // the written keys' deadlines down as absolute times. Only the command's
+    // own keys have their shards locked; a command without a key spec has
+    // them all locked.
+    let keys = command_keys(cmd, parts).unwrap_or_else(|| parts.get(1).map(|key| key.as_slice()).into_iter().collect());
+    for key in keys {
+        if let Some(expires_at) = db.get(key).and_then(|entry| entry.expires_at) {
+            out.extend(encode_command(&[b"PEXPIREAT", key, unix_millis(expires_at).to_string().as_bytes()]));
+        }
+    }
+    out
+}
+
+fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    Reply::Array(args.iter().map(|arg| Reply::bulk(arg)).collect()).to_bytes(2)
+}
+
+// Unix time in milliseconds of an Instant-based expiry
+fn unix_millis(at: Instant) -> u128 {
+    let now = Instant::now();
+    let unix_now = unix_time().as_millis();
+    if at >= now {
+        unix_now + at.duration_since(now).as_millis()
+    } else {
+        unix_now.saturating_sub(now.duration_since(at).as_millis())
+    }
+}
+
+fn open_aof(store: &Store, filename: &str) -> io::Result<()> {
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(filename)?;
+    *store.aof.lock().unwrap() = Some(AppendOnlyFile { file, db: 0, rewrite_buffer: None });
+    Ok(())
+}
+
+// Follow a master: sync its dataset, then apply the writes it streams.
+// A dropped link is retried every second until REPLICAOF aborts the task.
+async fn replicate(store: Store, host: String, port: u16, link_up: Arc<AtomicBool>) {
+    loop {
+        if let Err(e) = follow_master(&store, &host, port, &link_up).await {
+            eprintln!("Replication link with {}:{} lost: {}", host, port, e);
+        }
+        link_up.store(false, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+// One replication session: PSYNC, load the snapshot in place of the current
+// data, then apply each streamed write and acknowledge how far it got
+async fn follow_master(store: &Store, host: &str, port: u16, link_up: &AtomicBool) -> io::Result<()> {
+    let (mut reader, mut writer) = tokio::net::TcpStream::connect((host, port)).await?.into_split();
+    let mut buffer = Vec::new();
+    let listening_port = config().port.to_string();
+    writer.write_all(&encode_command(&[b"REPLCONF", b"listening-port", listening_port.as_bytes()])).await?;
+    let reply = read_line(&mut reader, &mut buffer).await?;
+    if reply != "+OK" {
+        return Err(protocol_error(&format!("master refused REPLCONF: {}", reply)));
+    }
+    writer.write_all(&encode_command(&[b"PSYNC", b"?", b"-1"])).await?;
+    let reply = read_line(&mut reader, &mut buffer).await?;
+    let mut offset = match reply.strip_prefix("+FULLRESYNC ").and_then(|rest| rest.split(' ').nth(1)) {
+        Some(offset) => offset.parse::<u64>().map_err(|_| protocol_error("invalid offset in FULLRESYNC"))?,
+        None => return Err(protocol_error(&format!("unexpected reply to PSYNC: {}", reply))),
+    };
+    let header = read_line(&mut reader, &mut buffer).await?;
+    let len = parse_length(header.as_bytes(), usize::MAX, "snapshot")?;
+    while buffer.len() < len {
+        if reader.read_buf(&mut buffer).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    let loaded = decode_snapshot(&buffer[..len]).map_err(|e| protocol_error(&e))?;
+    buffer.drain(..len);
+    
+    let total = replace_dataset(store, loaded);
+    link_up.store(true, Ordering::Relaxed);
+    println!("Synced {} keys from master {}:{}", total, host, port);
+    
+    // Writes are applied as they are, without the checks clients go through
+    let mut session = Session::new(mpsc::unbounded_channel().0);
+    loop {
+        let mut used = 0;
+        while let Some((args, len)) = parse_command(&buffer[used..])? {
+            used += len;
+            if !args.is_empty() {
+                run_locked(&args, &upper(&args[0]), store, &mut session);
+            }
+        }
+        buffer.drain(..used);
+        if used > 0 {
+            offset += used as u64;
+            writer.write_all(&encode_command(&[b"REPLCONF", b"ACK", offset.to_string().as_bytes()])).await?;
+        }
+        if reader.read_buf(&mut buffer).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+// Swap every database for the ones synced from a master, returning the
+// number of keys loaded
+fn replace_dataset(store: &Store, loaded: Vec<DbShard>) -> usize {
+    let mut shards = store.lock_all();
+    drop(shards.take_all());
+    install_dataset(&mut shards, loaded)
+}
+
+// Each database was decoded as one map; spread its keys over the (empty)
+// shards, returning the number of keys loaded
+fn install_dataset(shards: &mut Shards, loaded: Vec<DbShard>) -> usize {
+    let mut total = 0;
+    for (index, mut saved) in loaded.into_iter().enumerate().take(DATABASES) {
+        let mut db = shards.db(index);
+        total += saved.len();
+        for (key, entry) in std::mem::take(&mut saved.entries) {
+            db.insert(key, entry);
+        }
+    }
+    total
+}
+
+// Read a reply line from the master, without its \r\n
+async fn read_line(reader: &mut (impl AsyncRead + Unpin), buffer: &mut Vec<u8>) -> io::Result<String> {
+    loop {
+        if let Some((line, next)) = next_line(buffer, 0)? {
+            let line = String::from_utf8_lossy(line).into_owned();
+            buffer.drain(..next);
+            return Ok(line);
+        }
+        if reader.read_buf(buffer).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+// Rebuild the dataset by running every command in the AOF
+fn load_aof(store: &Store, filename: &str) {
+    let data = match std::fs::read(filename) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", filename, e);
+            return;
+        }
+    };
+    let mut session = Session::new(mpsc::unbounded_channel().0);
+    let mut shards = store.lock_all();
+    let mut commands = 0;
+    let mut rest = data.as_slice();
+    loop {
+        match parse_command(rest) {
+            Ok(Some((parts, used))) => {
+                rest = &rest[used..];
+                if !parts.is_empty() {
+                    execute_command(&parts, &upper(&parts[0]), store, &mut session, &mut shards);
+                    commands += 1;
+                }
+            }
+            // Most likely a write cut short by a crash; keep what came before
+            Ok(None) => {
+                if !rest.is_empty() {
+                    eprintln!("AOF {} ends with a truncated command after {} commands", filename, commands);
+                }
+                break;
+            }
+            Err(e) => {
+                eprintln!("AOF {} is damaged after {} commands: {}", filename, commands, e);
+                break;
+            }
+        }
+    }
+    // Nobody can be subscribed yet
+    KEYSPACE_EVENTS.take();
+    DIRTY.store(0, Ordering::Relaxed);
+    println!("Loaded {} commands from {}", commands, filename);
+}
+
+// One command per key that recreates its value, plus PEXPIREAT for its TTL
+fn dump_commands(shards: &Shards) -> Vec<u8> {
+    let mut out = Vec::new();
+    for index in 0..DATABASES {
+        if shards.pieces(index).flat_map(|db| db.values()).all(is_expired) {
+            continue;
+        }
+        out.extend(encode_command(&[b"SELECT", index.to_string().as_bytes()]));
+        let live = shards.pieces(index).flat_map(|db| db.iter()).filter(|(_, entry)| !is_expired(entry));
+        for (key, entry) in live {
+            let mut args: Vec<Vec<u8>> = Vec::new();
+            match &entry.value {
+                Value::String(s) => args.extend([b"SET".to_vec(), key.clone(), s.clone()]),
+                Value::List(list) => {
+                    args.extend([b"RPUSH".to_vec(), key.clone()]);
+                    args.extend(list.iter().cloned());
+                }
+                Value::Hash(hash) => {
+                    args.extend([b"HSET".to_vec(), key.clone()]);
+                    for (field, value) in hash {
+                        args.extend([field.clone(), value.clone()]);
+                    }
+                }
+                Value::Set(set) => {
+                    args.extend([b"SADD".to_vec(), key.clone()]);
+                    args.extend(set.iter().cloned());
+                }
+                Value::ZSet(zset) => {
+                    args.extend([b"ZADD".to_vec(), key.clone()]);
+                    for (member, score) in zset.iter() {
+                        args.extend([format_float(score).into_bytes(), member.clone()]);
+                    }
+                }
+                // Restored whole: HyperLogLog registers can't be rebuilt from
+                // elements, and XADDs wouldn't bring back a stream's last ID
+                Value::HyperLogLog(_) | Value::Stream(_) => {
+                    args.extend([b"RESTORE".to_vec(), key.clone(), b"0".to_vec(), encode_dump(&entry.value)]);
+                }
+            }
+            let args: Vec<&[u8]> = args.iter().map(|a| a.as_slice()).collect();
+            out.extend(encode_command(&args));
+            if let Some(expires_at) = entry.expires_at {
+                out.extend(encode_command(&[b"PEXPIREAT", key, unix_millis(expires_at).to_string().as_bytes()]));
+            }
+        }
+    }
+    out
+}
+
+static AOF_REWRITING: AtomicBool = AtomicBool::new(false);
+
+// Replace the AOF with the shortest file that rebuilds the current dataset.
+// Writes that land while the new file is being written are collected by the
+// live AOF and appended before the swap, so none are lost.
+fn rewrite_aof(store: &Store, filename: &str) -> io::Result<()> {
+    // Shared locks still keep every writer out while the dump is taken
+    let shards = store.read_all();
+    let mut contents = dump_commands(&shards);
+    let mut aof = store.aof.lock().unwrap();
+    if let Some(aof) = aof.as_mut() {
+        // Leave the new file on the database the live one was logging to
+        contents.extend(encode_command(&[b"SELECT", aof.db.to_string().as_bytes()]));
+        aof.rewrite_buffer = Some(Vec::new());
+    }
+    drop(aof);
+    drop(shards);
+    
+    let temp = format!("{}.rewrite.{}", filename, std::process::id());
+    let written = std::fs::File::create(&temp).and_then(|mut file| {
+        file.write_all(&contents)?;
+        file.sync_all()
+    });
+    
+    let mut aof = store.aof.lock().unwrap();
+    let swapped = written.and_then(|()| {
+        let buffered = aof.as_mut().and_then(|aof| aof.rewrite_buffer.take()).unwrap_or_default();
+        let mut file = std::fs::OpenOptions::new().append(true).open(&temp)?;
+        file.write_all(&buffered)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, filename)?;
+        if let Some(aof) = aof.as_mut() {
+            aof.file = std::fs::OpenOptions::new().append(true).open(filename)?;
+        }
+        Ok(())
+    });
+    if let Some(aof) = aof.as_mut() {
+        aof.rewrite_buffer = None;
+    }
+    if swapped.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    swapped
+}
+
+// Start serving a new connection, or turn it away once maxclients are
+// connected, or dropped during shutdown. The slot is taken here and given
+// back by handle_client.
+fn accept_client(
+    reader: impl AsyncRead + Unpin + Send + 'static,
+    mut writer: impl AsyncWrite + Unpin + Send + 'static,
+    peer: String,
+    store: &Store,
+) {
+    if SHUTTING_DOWN.load(Ordering::Relaxed) {
+        return;
+    }
+    let maxclients = config().maxclients;
+    let admitted = CONNECTED_CLIENTS
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n < maxclients).then_some(n + 1))
+        .is_ok();
+    if admitted {
+        tokio::spawn(handle_client(reader, writer, peer, Arc::clone(store)));
+    } else {
+        println!("Rejected client {}: max number of clients reached", peer);
+        tokio::spawn(async move {
+            let _ = writer.write_all(b"-ERR max number of clients reached\r\n").await;
+        });
+    }
+}
+
+// Serve one connection, TCP or unix socket, given its two halves
+async fn handle_client(
+    mut reader: impl AsyncRead + Unpin + Send,
+    mut stream: impl AsyncWrite + Unpin + Send + 'static,
+    peer: String,
+    store: Store,
+) {
+    println!("Client connected: {}", peer);
+    
+    // A writer task drains the outbox; it stops once every sender is gone.
+    // Replies to pipelined commands pile up while one write is in flight, so
+    // whatever is waiting goes out together, still in order.
+    let (outbox, mut pending) = mpsc::unbounded_channel::<Vec<u8>>();
+    let writer = tokio::spawn(async move {
+        while let Some(mut bytes) = pending.recv().await {
+            while let Ok(more) = pending.try_recv() {
+                bytes.extend_from_slice(&more);
+            }
+            if stream.write_all(&bytes).await.is_err() {
+                break;
+            }
+        }
+    });
+    
+    let mut session = Session::new(outbox);
+    let killed = Arc::new(Notify::new());
+    store.clients.lock().unwrap().insert(
+        session.id,
+        ClientInfo {
+            addr: peer.clone(),
+            name: Vec::new(),
+            connected_at: Instant::now(),
+            last_command: String::new(),
+            kill: Arc::clone(&killed),
+        },
+    );
+    let mut buffer = Vec::new();
+    let mut chunk = vec![0; 16 * 1024];
+    
+    'connection: loop {
+        // Run every complete command already received before reading more;
+        // a command cut off at the end of a read waits in the buffer for the rest
+        let mut used = 0;
+        loop {
+            match parse_command(&buffer[used..]) {
+                Ok(Some((args, len))) => {
+                    used += len;
+                    // Blank inline lines get no reply, as in Redis, or every
+                    // reply after them would seem to answer the wrong command
+                    if args.is_empty() {
+                        continue;
+                    }
+                    // A kill also ends a command that is blocked waiting
+                    let response = tokio::select! {
+                        response = process_command(&args, &store, &mut session) => response,
+                        _ = killed.notified() => break 'connection,
+                    };
+                    if session.outbox.send(response.to_bytes(session.protocol)).is_err() {
+                        break 'connection;
+                    }
+                    // QUIT hangs up once its OK is on its way
+                    if args[0].eq_ignore_ascii_case(b"QUIT") {
+                        break 'connection;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    let _ = session.outbox.send(format!("-ERR Protocol error: {}\r\n", e).into_bytes());
+                    break 'connection;
+                }
+            }
+        }
+        buffer.drain(..used);
+        
+        let read = tokio::select! {
+            read = reader.read(&mut chunk) => read,
+            _ = killed.notified() => break,
+        };
+        match read {
+            Ok(0) | Err(_) => break,
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+        }
+    }
+    
+    unsubscribe(&store, &mut session, &[], false, false);
+    unsubscribe(&store, &mut session, &[], true, false);
+    store.monitors.lock().unwrap().remove(&session.id);
+    store.replication.lock().unwrap().replicas.remove(&session.id);
+    store.clients.lock().unwrap().remove(&session.id);
+    drop(session);
+    let _ = writer.await;
+    CONNECTED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+    println!("Client disconnected: {}", peer);
+}
+
+// Longest line accepted without a newline, as in Redis
+const MAX_LINE: usize = 64 * 1024;
+
+// The line starting at `pos` without its \r\n, and the position after it;
+// None if the buffer ends first
+fn next_line(buffer: &[u8], pos: usize) -> io::Result<Option<(&[u8], usize)>> {
+    match buffer[pos..].iter().position(|&b| b == b'\n') {
+        Some(len) => {
+            let line = &buffer[pos..pos + len];
+            Ok(Some((line.strip_suffix(b"\r").unwrap_or(line), pos + len + 1)))
+        }
+        None if buffer.len() - pos > MAX_LINE => Err(protocol_error("too big inline request")),
+        None => Ok(None),
+    }
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Parse the length that follows a RESP type byte (e.g. "*3" or "$5")
+fn parse_length(line: &[u8], max: usize, what: &str) -> io::Result<usize> {
+    std::str::from_utf8(&line[1..])
+        .ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|&n| n <= max)
+        .ok_or_else(|| protocol_error(&format!("invalid {} length", what)))
+}
+
+// Parse the command at the front of `buffer`, returning its arguments and
+// how many bytes it took, or None until all of it has arrived. Real clients
+// send RESP arrays of bulk strings (*2\r\n$3\r\nGET\r\n$1\r\nx\r\n); anything
+// that doesn't start with '*' is an inline, whitespace-separated command.
+fn parse_command(buffer: &[u8]) -> io::Result<Option<(Vec<Vec<u8>>, usize)>> {
+    let Some((line, mut pos)) = next_line(buffer, 0)? else {
+        return Ok(None);
+    };
+    
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some((args, pos)));
+    }
+    
+    let count = parse_length(line, 1024 * 1024, "multibulk")?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let Some((header, start)) = next_line(buffer, pos)? else {
+            return Ok(None);
+        };
+        if header.first() != Some(&b'$') {
+            return Err(protocol_error("expected '$'"));
+        }
+        let len = parse_length(header, 512 * 1024 * 1024, "bulk")?;
+        
+        // Payload plus its trailing \r\n
+        let end = start + len + 2;
+        if buffer.len() < end {
+            return Ok(None);
+        }
+        if &buffer[end - 2..end] != b"\r\n" {
+            return Err(protocol_error("bulk string not terminated by CRLF"));
+        }
+        args.push(buffer[start..end - 2].to_vec());
+        pos = end;
+    }
+    Ok(Some((args, pos)))
+}
+
+async fn process_command(parts: &[Vec<u8>], store: &Store, session: &mut Session) -> Reply {
+    if parts.is_empty() {
+        return Reply::error("ERR empty command");
+    }
+    
+    let cmd = upper(&parts[0]);
+    if cmd == "QUIT" {
+        return Reply::ok();
+    }
+    if let Some(client) = store.clients.lock().unwrap().get_mut(&session.id) {
+        client.last_command = cmd.to_lowercase();
+    }
+    // Works in any state: unauthenticated, subscribed, monitoring or in MULTI
+    if cmd == "RESET" {
+        reset_session(store, session);
+        return Reply::Simple("RESET".to_string());
+    }
+    if !session.authenticated && cmd != "AUTH" && cmd != "HELLO" && cmd != "PING" {
+        return Reply::error("NOAUTH Authentication required.");
+    }
+    if session.subscribed()
+        && !matches!(cmd.as_str(), "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "PING")
+    {
+        return Reply::error(format!(
+            "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+            cmd.to_lowercase()
+        ));
+    }
+    if session.monitoring {
+        return Reply::error(format!(
+            "ERR Can't execute '{}': only RESET and QUIT are allowed while monitoring",
+            cmd.to_lowercase()
+        ));
+    }
+    if command_arity(&cmd).is_some()
+        && command_flags(&cmd).contains(&"write")
+        && store.replication.lock().unwrap().master.is_some()
+    {
+        return Reply::error("READONLY You can't write against a read only replica.");
+    }
+    feed_monitors(store, session, parts, &cmd);
+    
+    // Inside MULTI everything but the transaction commands is queued for EXEC
+    match cmd.as_str() {
+        "MULTI" => {
+            if session.transaction.is_some() {
+                return Reply::error("ERR MULTI calls can not be nested");
+            }
+            session.transaction = Some(Vec::new());
+            session.transaction_failed = false;
+            return Reply::ok();
+        }
+        "EXEC" => {
+            let Some(queued) = session.transaction.take() else {
+                return Reply::error("ERR EXEC without MULTI");
+            };
+            if session.transaction_failed {
+                session.watched.clear();
+                return Reply::error("EXECABORT Transaction discarded because of previous errors.");
+            }
+            if queued.iter().any(|parts| may_grow(&upper(&parts[0]))) && !make_room(store) {
+                session.watched.clear();
+                return Reply::error(OUT_OF_MEMORY);
+            }
+            // One lock for the whole batch, so no other client sees it half done
+            let mut shards = store.lock_all();
+            let started = Instant::now();
+            let watched = std::mem::take(&mut session.watched);
+            if watched.iter().any(|(index, key, seen)| touched_since_watch(&shards.db(*index), key, *seen)) {
+                return Reply::NilArray;
+            }
+            let replies = queued
+                .iter()
+                .map(|parts| {
+                    let cmd = upper(&parts[0]);
+                    let mut db = shards.db(session.db);
+                    for key in command_keys(&cmd, parts).unwrap_or_default() {
+                        purge_expired(&mut db, key);
+                    }
+                    drop(db);
+                    // Queued commands count as calls of their own, as in Redis
+                    let started = Instant::now();
+                    let reply = run_command(parts, &cmd, store, session, &mut shards);
+                    record_call(&cmd, started.elapsed());
+                    reply
+                })
+                .collect();
+            drop(shards);
+            log_if_slow(store, session, parts, started.elapsed());
+            return Reply::Array(replies);
+        }
+        "DISCARD" => {
+            if session.transaction.take().is_none() {
+                return Reply::error("ERR DISCARD without MULTI");
+            }
+            session.watched.clear();
+            return Reply::ok();
+        }
+        "WATCH" if session.transaction.is_some() => {
+            return Reply::error("ERR WATCH inside MULTI is not allowed");
+        }
+        _ => {}
+    }
+    if let Some(queued) = &mut session.transaction {
+        if command_arity(&cmd).is_none() {
+            session.transaction_failed = true;
+            return Reply::error("ERR unknown command");
+        }
+        queued.push(parts.to_vec());
+        return Reply::Simple("QUEUED".to_string());
+    }
+    
+    if may_grow(&cmd) && !make_room(store) {
+        return Reply::error(OUT_OF_MEMORY);
+    }
+    if cmd == "WAIT" && parts.len() == 3 {
+        return wait_for_replicas(parts, store).await;
+    }
+    // Sleeps without any lock, so other clients carry on meanwhile
+    if cmd == "DEBUG" && parts.len() == 3 && upper(&parts[1]) == "SLEEP" {
+        let Some(duration) = parse_num::<f64>(&parts[2]).and_then(|s| Duration::try_from_secs_f64(s).ok()) else {
+            return Reply::error("ERR value is not a valid float or out of range");
+        };
+        let started = Instant::now();
+        tokio::time::sleep(duration).await;
+        log_if_slow(store, session, parts, started.elapsed());
+        return Reply::ok();
+    }
+    if cmd != "BLPOP" && cmd != "BRPOP" {
+        return run_locked(parts, &cmd, store, session);
+    }
+    
+    // A zero timeout blocks forever, as does one too far off to have an
+    // Instant. One that isn't a Duration at all is the command's to reject.
+    let timeout = parse_num::<f64>(&parts[parts.len() - 1]).unwrap_or(0.0);
+    let Ok(timeout) = Duration::try_from_secs_f64(timeout) else {
+        return run_locked(parts, &cmd, store, session);
+    };
+    let deadline = (!timeout.is_zero()).then(|| tokio::time::Instant::now().checked_add(timeout)).flatten();
+    loop {
+        // Subscribed before trying, so a push that lands any time after the
+        // attempt's lists were checked still wakes us
+        let mut pushed = store.list_pushed.subscribe();
+        let reply = run_locked(parts, &cmd, store, session);
+        // Every list was empty: wait for a push and try again
+        if !matches!(reply, Reply::NilArray) {
+            return reply;
+        }
+        match deadline {
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline, pushed.changed()).await.is_err() {
+                    return reply;
+                }
+            }
+            None => {
+                let _ = pushed.changed().await;
+            }
+        }
+    }
+}
+
+// WAIT numreplicas timeout: block until that many replicas acknowledged
+// this connection's writes, or for `timeout` milliseconds (0 is forever),
+// then report how many did
+async fn wait_for_replicas(parts: &[Vec<u8>], store: &Store) -> Reply {
+    let (Some(wanted), Some(timeout)) = (parse_num::<i64>(&parts[1]), parse_num::<i64>(&parts[2])) else {
+        return Reply::error("ERR value is not an integer or out of range");
+    };
+    if timeout < 0 {
+        return Reply::error("ERR timeout is negative");
+    }
+    // Everything streamed so far, which takes in this connection's writes
+    let offset = store.replication.lock().unwrap().offset;
+    let deadline = (timeout > 0).then(|| tokio::time::Instant::now() + Duration::from_millis(timeout as u64));
+    loop {
+        let mut acks = store.replica_acks.subscribe();
+        let acknowledged = store.replication.lock().unwrap().acknowledged(offset);
+        if acknowledged as i64 >= wanted {
+            return Reply::Integer(acknowledged as i64);
+        }
+        match deadline {
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline, acks.changed()).await.is_err() {
+                    return Reply::Integer(acknowledged as i64);
+                }
+            }
+            None => {
+                let _ = acks.changed().await;
+            }
+        }
+    }
+}
+
+// Lock the shards a command needs and run it. Keys it names whose TTL has
+// passed are deleted first; a reader that finds one trades its shared locks
+// for exclusive ones to do that.
+fn run_locked(parts: &[Vec<u8>], cmd: &str, store: &Store, session: &mut Session) -> Reply {
+    let keys = command_keys(cmd, parts);
+    let mut shards = store.lock(keys.as_deref(), !read_only(cmd));
+    if let Some(keys) = &keys {
+        let db = shards.db(session.db);
+        let expired: Vec<&[u8]> = keys.iter().copied().filter(|key| db.peek(key).is_some_and(is_expired)).collect();
+        drop(db);
+        if !expired.is_empty() {
+            if read_only(cmd) {
+                drop(shards);
+                shards = store.lock(Some(keys), true);
+            }
+            let mut db = shards.db(session.db);
+            for key in expired {
+                purge_expired(&mut db, key);
+            }
+        }
+    }
+    let started = Instant::now();
+    let reply = run_command(parts, cmd, store, session, &mut shards);
+    drop(shards);
+    log_if_slow(store, session, parts, started.elapsed());
+    reply
+}
+
+// Put a command in the slow log if it ran longer than slowlog-log-slower-than.
+// Long arguments are cut short, as in Redis, and passwords are kept out.
+fn log_if_slow(store: &Store, session: &Session, parts: &[Vec<u8>], took: Duration) {
+    let cmd = upper(&parts[0]);
+    record_call(&cmd, took);
+    record_latency(store, "command", took);
+    let (threshold, max_len) = {
+        let config = config();
+        (config.slowlog_log_slower_than, config.slowlog_max_len)
+    };
+    let micros = took.as_micros() as u64;
+    if threshold < 0 || micros < threshold as u64 || matches!(cmd.as_str(), "AUTH" | "HELLO") {
+        return;
+    }
+    const MAX_ARGS: usize = 32;
+    const MAX_ARG_LEN: usize = 128;
+    let mut args: Vec<Vec<u8>> = parts
+        .iter()
+        .take(if parts.len() > MAX_ARGS { MAX_ARGS - 1 } else { MAX_ARGS })
+        .map(|arg| match arg.len() > MAX_ARG_LEN {
+            true => [&arg[..MAX_ARG_LEN], format!("... ({} more bytes)", arg.len() - MAX_ARG_LEN).as_bytes()].concat(),
+            false => arg.clone(),
+        })
+        .collect();
+    if parts.len() > MAX_ARGS {
+        args.push(format!("... ({} more arguments)", parts.len() - MAX_ARGS + 1).into_bytes());
+    }
+    let (addr, name) = match store.clients.lock().unwrap().get(&session.id) {
+        Some(client) => (client.addr.clone(), client.name.clone()),
+        None => (String::new(), Vec::new()),
+    };
+    
+    let mut slowlog = store.slowlog.lock().unwrap();
+    let id = slowlog.next_id;
+    slowlog.next_id += 1;
+    slowlog.entries.push_front(SlowLogEntry { id, time: unix_time().as_secs(), micros, args, addr, name });
+    slowlog.entries.truncate(max_len);
+}
+
+// Calls and total run time of each command, for INFO commandstats. The
+// table holds every command from the start, so counting a call is two
+// atomic adds and never takes a lock.
+#[derive(Default)]
+struct CommandStats {
+    calls: AtomicU64,
+    usec: AtomicU64,
+}
+
+fn command_stats() -> &'static BTreeMap<&'static str, CommandStats> {
+    static STATS: OnceLock<BTreeMap<&'static str, CommandStats>> = OnceLock::new();
+    STATS.get_or_init(|| COMMANDS.iter().map(|&(name, _)| (name, CommandStats::default())).collect())
+}
+
+// Unknown commands aren't counted
+fn record_call(cmd: &str, took: Duration) {
+    if let Some(stats) = command_stats().get(cmd) {
+        stats.calls.fetch_add(1, Ordering::Relaxed);
+        stats.usec.fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+// Sample `event` in the latency monitor if it took at least
+// latency-monitor-threshold. Samples in the same second keep the worst.
+fn record_latency(store: &Store, event: &'static str, took: Duration) {
+    let threshold = config().latency_monitor_threshold;
+    let ms = took.as_millis() as u64;
+    if threshold == 0 || ms < threshold {
+        return;
+    }
+    let now = unix_time().as_secs();
+    let mut latency = store.latency.lock().unwrap();
+    let event = latency.events.entry(event).or_default();
+    event.max = event.max.max(ms);
+    match event.samples.back_mut() {
+        Some((time, worst)) if *time == now => *worst = (*worst).max(ms),
+        _ => {
+            event.samples.push_back((now, ms));
+            if event.samples.len() > LATENCY_HISTORY_LEN {
+                event.samples.pop_front();
+            }
+        }
+    }
+}
+
+// Execute a command and, if it changed anything, append it to the AOF and
+// send it to replicas
+fn run_command(parts: &[Vec<u8>], cmd: &str, store: &Store, session: &mut Session, shards: &mut Shards) -> Reply {
+    let writes = WRITES.get();
+    let reply = execute_command(parts, cmd, store, session, shards);
+    // The command ran on this thread, so any new writes counted here are its
+    // own; logging while its shards are still locked keeps the AOF in the
+    // order the writes happened
+    if WRITES.get() != writes {
+        log_write(store, session.db, parts, cmd, &reply, &shards.db(session.db));
+    }
+    publish_keyspace_events(store);
+    reply
+}
+
+// Name the connection in the client registry; an empty name clears it.
+// Names show up space-separated in CLIENT LIST, so they can't hold spaces.
+fn set_client_name(store: &Store, session: &Session, name: &[u8]) -> Result<(), Reply> {
+    if name.iter().any(|&b| !b.is_ascii_graphic()) {
+        return Err(Reply::error("ERR Client names cannot contain spaces, newlines or special characters."));
+    }
+    if let Some(client) = store.clients.lock().unwrap().get_mut(&session.id) {
+        client.name = name.to_vec();
+    }
+    Ok(())
+}
+
+// Send every MONITOR connection a line describing a command about to run,
+// e.g. `1700000000.123456 [0 127.0.0.1:50000] "set" "key" "value"`.
+// Passwords given to AUTH and HELLO are redacted.
+fn feed_monitors(store: &Store, session: &Session, parts: &[Vec<u8>], cmd: &str) {
+    let monitors = store.monitors.lock().unwrap();
+    if monitors.is_empty() {
+        return;
+    }
+    let addr = store.clients.lock().unwrap().get(&session.id).map(|client| client.addr.clone()).unwrap_or_default();
+    let now = unix_time();
+    let mut line = format!("{}.{:06} [{} {}]", now.as_secs(), now.subsec_micros(), session.db, addr);
+    let mut redacted = false;
+    for (i, arg) in parts.iter().enumerate() {
+        line.push(' ');
+        let secret = match cmd {
+            "AUTH" => i > 0,
+            "HELLO" => redacted || (i > 1 && parts[i - 1].eq_ignore_ascii_case(b"AUTH")),
+            _ => false,
+        };
+        redacted |= secret;
+        if secret {
+            line.push_str("\"(redacted)\"");
+        } else {
+            quote_arg(&mut line, arg);
+        }
+    }
+    deliver(&monitors, &Reply::Simple(line));
+}
+
+// Append an argument in double quotes, escaped the way redis-cli reads it back
+fn quote_arg(out: &mut String, arg: &[u8]) {
+    out.push('"');
+    for &b in arg {
+        match b {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x07 => out.push_str("\\a"),
+            0x08 => out.push_str("\\b"),
+            b' '..=b'~' => out.push(b as char),
+            _ => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out.push('"');
+}
+
+// Hang up on the connections matching every filter given, returning how
+// many there were. Each one closes once its current command is done.
+fn kill_clients(store: &Store, session: &Session, id: Option<u64>, addr: Option<&[u8]>, skip_me: bool) -> usize {
+    let clients = store.clients.lock().unwrap();
+    clients
+        .iter()
+        .filter(|(client_id, client)| {
+            id.is_none_or(|id| **client_id == id)
+                && addr.is_none_or(|addr| client.addr.as_bytes() == addr)
+                && !(skip_me && **client_id == session.id)
+        })
+        .inspect(|(_, client)| client.kill.notify_one())
+        .count()
+}
+
+// Deliver a message to the channel's subscribers and to every matching
+// pattern subscriber, returning how many received it
+fn publish(store: &Store, channel: &[u8], payload: &[u8]) -> usize {
+    let mut receivers = 0;
+    
+    let subscribers = store.subscribers.lock().unwrap();
+    if let Some(clients) = subscribers.get(channel) {
+        receivers += deliver(clients, &pubsub_message("message", channel, Reply::bulk(payload)));
+    }
+    drop(subscribers);
+    
+    let pattern_subscribers = store.pattern_subscribers.lock().unwrap();
+    for (pattern, clients) in pattern_subscribers.iter() {
+        if !glob_match(pattern, channel) {
+            continue;
+        }
+        let message = Reply::Push(vec![
+            Reply::bulk(b"pmessage"),
+            Reply::bulk(pattern),
+            Reply::bulk(channel),
+            Reply::bulk(payload),
+        ]);
+        receivers += deliver(clients, &message);
+    }
+    receivers
+}
+
+// Queue a message for each subscriber, encoded once per RESP version,
+// returning how many connections were still there to take it
+fn deliver(clients: &HashMap<u64, Subscriber>, message: &Reply) -> usize {
+    let encoded = [message.to_bytes(2), message.to_bytes(3)];
+    clients
+        .values()
+        .filter(|client| client.outbox.send(encoded[(client.protocol >= 3) as usize].clone()).is_ok())
+        .count()
+}
+
+// What a subscriber receives for a (un)subscribe confirmation or a message
+fn pubsub_message(kind: &str, channel: &[u8], payload: Reply) -> Reply {
+    Reply::Push(vec![Reply::bulk(kind.as_bytes()), Reply::bulk(channel), payload])
+}
+
+// Subscribe to channels, or to patterns for PSUBSCRIBE. Confirmations are
+// queued while the registry is locked, so no message can overtake them.
+fn subscribe(store: &Store, session: &mut Session, names: &[Vec<u8>], patterns: bool) {
+    let (registry, kind) = if patterns {
+        (&store.pattern_subscribers, "psubscribe")
+    } else {
+        (&store.subscribers, "subscribe")
+    };
+    let mut subscribers = registry.lock().unwrap();
+    for name in names {
+        let subscriber = Subscriber { outbox: session.outbox.clone(), protocol: session.protocol };
+        subscribers.entry(name.clone()).or_default().insert(session.id, subscriber);
+        if patterns {
+            session.patterns.insert(name.clone());
+        } else {
+            session.channels.insert(name.clone());
+        }
+        let _ = session.outbox.send(pubsub_message(kind, name, session.subscription_count()).to_bytes(session.protocol));
+    }
+}
+
+// Leave the given channels (or patterns), or all of them when `names` is
+// empty, sending one confirmation for each if `confirm` is set
+fn unsubscribe(store: &Store, session: &mut Session, names: &[Vec<u8>], patterns: bool, confirm: bool) {
+    let (registry, kind) = if patterns {
+        (&store.pattern_subscribers, "punsubscribe")
+    } else {
+        (&store.subscribers, "unsubscribe")
+    };
+    let names: Vec<Vec<u8>> = if names.is_empty() {
+        let current = if patterns { &session.patterns } else { &session.channels };
+        let mut all: Vec<Vec<u8>> = current.iter().cloned().collect();
+        all.sort();
+        all
+    } else {
+        names.to_vec()
+    };
+    let mut subscribers = registry.lock().unwrap();
+    for name in &names {
+        if patterns {
+            session.patterns.remove(name);
+        } else {
+            session.channels.remove(name);
+        }
+        if let Some(clients) = subscribers.get_mut(name) {
+            clients.remove(&session.id);
+            if clients.is_empty() {
+                subscribers.remove(name);
+            }
+        }
+        if confirm {
+            let _ = session.outbox.send(pubsub_message(kind, name, session.subscription_count()).to_bytes(session.protocol));
+        }
+    }
+}
+
+// Put a connection back the way it was when it connected, keeping its id:
+// no transaction, watches, subscriptions, MONITOR or name, database 0,
+// RESP2, and logged out if a password is required
+fn reset_session(store: &Store, session: &mut Session) {
+    session.transaction = None;
+    session.transaction_failed = false;
+    session.watched.clear();
+    unsubscribe(store, session, &[], false, false);
+    unsubscribe(store, session, &[], true, false);
+    if session.monitoring {
+        store.monitors.lock().unwrap().remove(&session.id);
+        session.monitoring = false;
+    }
+    if let Some(client) = store.clients.lock().unwrap().get_mut(&session.id) {
+        client.name.clear();
+    }
+    session.db = 0;
+    session.protocol = 2;
+    session.authenticated = config().requirepass.is_none();
+}
+
+// What WATCH saw of a key: the version of its entry or, if it was absent
+// (expired counts as absent), the version counter at the time
+#[derive(Clone, Copy)]
+enum Watched {
+    Version(u64),
+    AbsentSince(u64),
+}
+
+fn watch_key(db: &Db, key: &[u8]) -> Watched {
+    match db.get(key).filter(|entry| !is_expired(entry)) {
+        Some(entry) => Watched::Version(entry.version),
+        None => Watched::AbsentSince(next_version()),
+    }
+}
+
+// Whether anything wrote to a watched key since, which makes EXEC abort.
+// Every write stamps a new version, even one leaving the value as it was;
+// a key that expired counts as deleted.
+fn touched_since_watch(db: &Db, key: &[u8], watched: Watched) -> bool {
+    match (db.get(key).filter(|entry| !is_expired(entry)), watched) {
+        (Some(entry), Watched::Version(version)) => entry.version != version,
+        (Some(_), Watched::AbsentSince(_)) => true,
+        (None, Watched::Version(_)) => true,
+        // Created and deleted again in between
+        (None, Watched::AbsentSince(since)) => db.removed_since(key, since),
+    }
+}
+
+// Run one command against the shards locked for it
+fn execute_command(parts: &[Vec<u8>], cmd: &str, store: &Store, session: &mut Session, shards: &mut Shards) -> Reply {
+    let db = &mut shards.db(session.db);
+    
+    match cmd {
+        // ========== STRING COMMANDS ==========
+        "SET" => {
+            if parts.len() < 3 {
+                return Reply::error("ERR usage: SET key value [NX|XX] [EX seconds|PX millis|EXAT unix-secs|PXAT unix-millis|KEEPTTL]");
+            }
+            let key = parts[1].clone();
+            let value = Value::String(parts[2].clone());
+            
+            // Options may come in any order after the value
+            let mut expires_at = None;
+            let mut keep_ttl = false;
+            let mut nx = false;
+            let mut xx = false;
+            let mut i = 3;
+            while i < parts.len() {
+                let option = upper(&parts[i]);
+                match option.as_str() {
+                    "NX" if !xx => nx = true,
+                    "XX" if !nx => xx = true,
+                    "KEEPTTL" if expires_at.is_none() => keep_ttl = true,
+                    "EX" | "PX" | "EXAT" | "PXAT" if expires_at.is_none() && !keep_ttl && i + 1 < parts.len() => {
+                        match parse_expire_option(&option, &parts[i + 1], "set") {
+                            Ok(at) => expires_at = Some(at),
+                            Err(e) => return e,
+                        }
+                        i += 1;
+                    }
+                    _ => return Reply::error("ERR syntax error"),
+                }
+                i += 1;
+            }
+            
+            let existing = db.get(&key).filter(|entry| !is_expired(entry));
+            if (nx && existing.is_some()) || (xx && existing.is_none()) {
+                return Reply::Nil;
+            }
+            if keep_ttl {
+                expires_at = existing.and_then(|entry| entry.expires_at);
+            }
+            
+            notify_keyspace_event(session.db, NOTIFY_STRING, "set", &key);
+            if expires_at.is_some() && !keep_ttl {
+                notify_keyspace_event(session.db, NOTIFY_GENERIC, "expire", &key);
+            }
+            db.insert(key, Entry::new(value, expires_at));
+            Reply::ok()
+        }
+        
+        "SETEX" | "PSETEX" => {
+            if parts.len() != 4 {
+                return Reply::error(format!("ERR usage: {} key {} value", cmd, if cmd == "SETEX" { "seconds" } else { "milliseconds" }));
+            }
+            let name = cmd.to_lowercase();
+            let option = if cmd == "SETEX" { "EX" } else { "PX" };
+            let expires_at = match parse_expire_option(option, &parts[2], &name) {
+                Ok(at) => at,
+                Err(_) => return Reply::error(format!("ERR invalid expire time in '{}' command", name)),
+            };
+            
+            db.insert(parts[1].clone(), Entry::new(Value::String(parts[3].clone()), Some(expires_at)));
+            notify_keyspace_event(session.db, NOTIFY_STRING, "set", &parts[1]);
+            notify_keyspace_event(session.db, NOTIFY_GENERIC, "expire", &parts[1]);
+            Reply::ok()
+        }
+        
+        "GET" => {
+            if parts.len() != 2 {
+                return Reply::error("ERR usage: GET key");
+            }
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::String(s) => Reply::bulk(s),
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Nil,
+            }
+        }
+        
+        // Like a SET of each pair, so every key loses its TTL
+        "MSET" => {
+            if parts.len() < 3 || parts.len().is_multiple_of(2) {
+                return Reply::error("ERR usage: MSET key value [key value ...]");
+            }
+            for pair in parts[1..].chunks(2) {
+                db.insert(pair[0].clone(), Entry::new(Value::String(pair[1].clone()), None));
+                notify_keyspace_event(session.db, NOTIFY_STRING, "set", &pair[0]);
+            }
+            Reply::ok()
+        }
+        
+        // Keys that are missing or hold another type come back as nil
+        "MGET" => {
+            if parts.len() < 2 {
+                return Reply::error("ERR usage: MGET key [key ...]");
+            }
+            Reply::Array(
+                parts[1..]
+                    .iter()
+                    .map(|key| match db.get(key) {
+                        Some(entry) if !is_expired(entry) => match &entry.value {
+                            Value::String(s) => Reply::bulk(s),
+                            _ => Reply::Nil,
+                        },
+                        _ => Reply::Nil,
+                    })
+                    .collect(),
+            )
+        }
+        
+        "SETBIT" => {
+            if parts.len() != 4 {
+                return Reply::error("ERR usage: SETBIT key offset value");
+            }
+            let Some(offset) = parse_num::<u64>(&parts[2]).filter(|&offset| offset < MAX_BIT_OFFSET) else {
+                return Reply::error("ERR bit offset is not an integer or out of range");
+            };
+            let on = match parts[3].as_slice() {
+                b"0" => false,
+                b"1" => true,
+                _ => return Reply::error("ERR bit is not an integer or out of range"),
+            };
+            
+            purge_expired(db, &parts[1]);
+            if db.peek(&parts[1]).is_some_and(|entry| !matches!(entry.value, Value::String(_))) {
+                return Reply::error(WRONG_TYPE);
+            }
+            let entry = db.get_or_insert(parts[1].clone(), || Value::String(Vec::new()));
+            let mut old = false;
+            if let Value::String(bytes) = &mut entry.value {
+                // Zero bytes fill the gap up to a bit past the end
+                let index = (offset / 8) as usize;
+                if bytes.len() <= index {
+                    bytes.resize(index + 1, 0);
+                }
+                let mask = 0x80 >> (offset % 8);
+                old = bytes[index] & mask != 0;
+                if on {
+                    bytes[index] |= mask;
+                } else {
+                    bytes[index] &= !mask;
+                }
+            }
+            notify_keyspace_event(session.db, NOTIFY_STRING, "setbit", &parts[1]);
+            Reply::Integer(old as i64)
+        }
+        
+        "GETBIT" => {
+            if parts.len() != 3 {
+                return Reply::error("ERR usage: GETBIT key offset");
+            }
+            let Some(offset) = parse_num::<u64>(&parts[2]).filter(|&offset| offset < MAX_BIT_OFFSET) else {
+                return Reply::error("ERR bit offset is not an integer or out of range");
+            };
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => match &entry.value {
+                    Value::String(bytes) => Reply::Integer(bit_at(bytes, offset as usize) as i64),
+                    _ => Reply::error(WRONG_TYPE),
+                },
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        "BITCOUNT" => {
+            if !matches!(parts.len(), 2 | 4 | 5) {
+                return Reply::error("ERR syntax error");
+            }
+            // The range counts bytes unless BIT says it counts bits
+            let bit_unit = match parts.get(4).map(|unit| upper(unit)).as_deref() {
+                None | Some("BYTE") => false,
+                Some("BIT") => true,
+                _ => return Reply::error("ERR syntax error"),
+            };
+            let range = match parts.get(2..4) {
+                Some([start, end]) => match (parse_num::<i64>(start), parse_num::<i64>(end)) {
+                    (Some(start), Some(end)) => Some((start, end)),
+                    _ => return Reply::error("ERR value is not an integer or out of range"),
+                },
+                _ => None,
+            };
+            
+            let bytes = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => match &entry.value {
+                    Value::String(bytes) => bytes,
+                    _ => return Reply::error(WRONG_TYPE),
+                },
+                _ => return Reply::Integer(0),
+            };
+            let count = match range {
+                None => bytes.iter().map(|byte| byte.count_ones() as usize).sum(),
+                Some((start, end)) if bit_unit => match resolve_range(start, end, bytes.len() * 8) {
+                    Some((from, to)) => (from..=to).filter(|&offset| bit_at(bytes, offset)).count(),
+                    None => 0,
+                },
+                Some((start, end)) => match resolve_range(start, end, bytes.len()) {
+                    Some((from, to)) => bytes[from..=to].iter().map(|byte| byte.count_ones() as usize).sum(),
+                    None => 0,
+                },
+            };
+            Reply::Integer(count as i64)
+        }
+        
+        "BITPOS" => {
+            if parts.len() < 3 || parts.len() > 6 {
+                return Reply::error("ERR usage: BITPOS key bit [start [end [BYTE|BIT]]]");
+            }
+            let bit = match parts[2].as_slice() {
+                b"0" => false,
+                b"1" => true,
+                _ => return Reply::error("ERR The bit argument must be 1 or 0."),
+            };
+            let bit_unit = match parts.get(5).map(|unit| upper(unit)).as_deref() {
+                None | Some("BYTE") => false,
+                Some("BIT") => true,
+                _ => return Reply::error("ERR syntax error"),
+            };
+            let mut bounds = [0, -1];
+            for (bound, arg) in bounds.iter_mut().zip(parts.iter().skip(3).take(2)) {
+                match parse_num::<i64>(arg) {
+                    Some(n) => *bound = n,
+                    None => return Reply::error("ERR value is not an integer or out of range"),
+                }
+            }
+            
+            let bytes = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => match &entry.value {
+                    Value::String(bytes) => bytes,
+                    _ => return Reply::error(WRONG_TYPE),
+                },
+                // A missing key is all zeros
+                _ => return Reply::Integer(if bit { -1 } else { 0 }),
+            };
+            let range = if bit_unit {
+                resolve_range(bounds[0], bounds[1], bytes.len() * 8)
+            } else {
+                resolve_range(bounds[0], bounds[1], bytes.len()).map(|(from, to)| (from * 8, to * 8 + 7))
+            };
+            let Some((from, to)) = range else {
+                return Reply::Integer(-1);
+            };
+            match find_bit(bytes, bit, from, to) {
+                Some(offset) => Reply::Integer(offset as i64),
+                // Without an explicit end the string counts as padded with
+                // zeros, so the first clear bit is just past it
+                None if !bit && parts.len() < 5 => Reply::Integer(to as i64 + 1),
+                None => Reply::Integer(-1),
+            }
+        }
+        
+        "BITOP" => {
+            if parts.len() < 4 {
+                return Reply::error("ERR usage: BITOP AND|OR|XOR|NOT destkey key [key ...]");
+            }
+            let op = upper(&parts[1]);
+            if !matches!(op.as_str(), "AND" | "OR" | "XOR" | "NOT") {
+                return Reply::error("ERR syntax error");
+            }
+            if op == "NOT" && parts.len() != 4 {
+                return Reply::error("ERR BITOP NOT must be called with a single source key.");
+            }
+            
+            // Missing keys count as empty strings; shorter ones are padded with zeros
+            let mut sources: Vec<&[u8]> = Vec::new();
+            for key in &parts[3..] {
+                match db.get(key) {
+                    Some(entry) if !is_expired(entry) => match &entry.value {
+                        Value::String(bytes) => sources.push(bytes),
+                        _ => return Reply::error(WRONG_TYPE),
+                    },
+                    _ => sources.push(&[]),
+                }
+            }
+            let len = sources.iter().map(|bytes| bytes.len()).max().unwrap_or(0);
+            let result: Vec<u8> = (0..len)
+                .map(|i| {
+                    let mut bytes = sources.iter().map(|bytes| bytes.get(i).copied().unwrap_or(0));
+                    match op.as_str() {
+                        "AND" => bytes.fold(0xff, |acc, byte| acc & byte),
+                        "OR" => bytes.fold(0, |acc, byte| acc | byte),
+                        "XOR" => bytes.fold(0, |acc, byte| acc ^ byte),
+                        _ => !bytes.next().unwrap_or(0),
+                    }
+                })
+                .collect();
+            
+            // The destination is overwritten whatever it held before
+            if result.is_empty() {
+                if db.remove(&parts[2]).is_some() {
+                    notify_keyspace_event(session.db, NOTIFY_GENERIC, "del", &parts[2]);
+                }
+            } else {
+                db.insert(parts[2].clone(), Entry::new(Value::String(result), None));
+                notify_keyspace_event(session.db, NOTIFY_STRING, "set", &parts[2]);
+            }
+            Reply::Integer(len as i64)
+        }
+        
+        "GETEX" => {
+            if parts.len() < 2 {
+                return Reply::error("ERR usage: GETEX key [EX seconds|PX millis|EXAT unix-secs|PXAT unix-millis|PERSIST]");
+            }
+            // None leaves the TTL untouched, Some(None) is PERSIST
+            let mut new_expiry: Option<Option<Instant>> = None;
+            let mut i = 2;
+            while i < parts.len() {
+                let option = upper(&parts[i]);
+                match option.as_str() {
+                    "PERSIST" if new_expiry.is_none() => new_expiry = Some(None),
+                    "EX" | "PX" | "EXAT" | "PXAT" if new_expiry.is_none() && i + 1 < parts.len() => {
+                        match parse_expire_option(&option, &parts[i + 1], "getex") {
+                            Ok(at) => new_expiry = Some(Some(at)),
+                            Err(e) => return e,
+                        }
+                        i += 1;
+                    }
+                    _ => return Reply::error("ERR syntax error"),
+                }
+                i += 1;
+            }
+            
+            let response = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::String(s) => Reply::bulk(s),
+                        _ => return Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => return Reply::Nil,
+            };
+            // Without an option GETEX is a plain read and leaves the entry alone
+            if let Some(expires_at) = new_expiry
+                && let Some(entry) = db.get_mut(&parts[1])
+            {
+                let persisted = expires_at.is_none() && entry.expires_at.is_some();
+                entry.expires_at = expires_at;
+                if expires_at.is_some() {
+                    notify_keyspace_event(session.db, NOTIFY_GENERIC, "expire", &parts[1]);
+                } else if persisted {
+                    notify_keyspace_event(session.db, NOTIFY_GENERIC, "persist", &parts[1]);
+                }
+            }
+            response
+        }
+        
+        "INCR" | "DECR" | "INCRBY" | "DECRBY" => {
+            let by = matches!(cmd, "INCRBY" | "DECRBY");
+            if parts.len() != if by { 3 } else { 2 } {
+                return Reply::error(format!("ERR usage: {} key{}", cmd, if by { " increment" } else { "" }));
+            }
+            let delta = match parts.get(2).map(|arg| parse_num::<i64>(arg)) {
+                None => 1,
+                Some(Some(n)) => n,
+                Some(None) => return Reply::error("ERR value is not an integer or out of range"),
+            };
+            let delta = if cmd.starts_with("DECR") {
+                match delta.checked_neg() {
+                    Some(n) => n,
+                    None => return Reply::error("ERR decrement would overflow"),
+                }
+            } else {
+                delta
+            };
+            
+            purge_expired(db, &parts[1]);
+            // The type is checked before the stored value is parsed, so a
+            // list gets WRONGTYPE rather than "not an integer"
+            let current = match db.get(&parts[1]).map(|entry| &entry.value) {
+                Some(Value::String(s)) => match parse_num::<i64>(s) {
+                    Some(n) => n,
+                    None => return Reply::error("ERR value is not an integer or out of range"),
+                },
+                Some(_) => return Reply::error(WRONG_TYPE),
+                None => 0,
+            };
+            let Some(result) = current.checked_add(delta) else {
+                return Reply::error("ERR increment or decrement would overflow");
+            };
+            db.get_or_insert(parts[1].clone(), || Value::String(Vec::new())).value = Value::String(result.to_string().into_bytes());
+            notify_keyspace_event(session.db, NOTIFY_STRING, "incrby", &parts[1]);
+            Reply::Integer(result)
+        }
+        
+        "INCRBYFLOAT" => {
+            if parts.len() != 3 {
+                return Reply::error("ERR usage: INCRBYFLOAT key increment");
+            }
+            let Some(delta) = parse_num::<f64>(&parts[2]).filter(|d| !d.is_nan()) else {
+                return Reply::error("ERR value is not a valid float");
+            };
+            
+            purge_expired(db, &parts[1]);
+            // Type first, then the stored value, as for INCR
+            let current = match db.get(&parts[1]).map(|entry| &entry.value) {
+                Some(Value::String(s)) => match parse_num::<f64>(s).filter(|n| !n.is_nan()) {
+                    Some(n) => n,
+                    None => return Reply::error("ERR value is not a valid float"),
+                },
+                Some(_) => return Reply::error(WRONG_TYPE),
+                None => 0.0,
+            };
+            let result = current + delta;
+            if !result.is_finite() {
+                return Reply::error("ERR increment would produce NaN or Infinity");
+            }
+            db.get_or_insert(parts[1].clone(), || Value::String(Vec::new())).value = Value::String(format_float(result).into_bytes());
+            notify_keyspace_event(session.db, NOTIFY_STRING, "incrbyfloat", &parts[1]);
+            Reply::Double(result)
+        }
+        
+        // Appends in place, so the key keeps its TTL
+        "APPEND" => {
+            if parts.len() != 3 {
+                return Reply::error("ERR usage: APPEND key value");
+            }
+            purge_expired(db, &parts[1]);
+            let entry = db.get_or_insert(parts[1].clone(), || Value::String(Vec::new()));
+            match &mut entry.value {
+                Value::String(s) => {
+                    s.extend_from_slice(&parts[2]);
+                    let len = s.len();
+                    notify_keyspace_event(session.db, NOTIFY_STRING, "append", &parts[1]);
+                    Reply::Integer(len as i64)
+                }
+                _ => Reply::error(WRONG_TYPE),
+            }
+        }
+        
+        // Replaces the value, and with it any TTL, like SET
+        "GETSET" => {
+            if parts.len() != 3 {
+                return Reply::error("ERR usage: GETSET key value");
+            }
+            purge_expired(db, &parts[1]);
+            let old = match db.get(&parts[1]).map(|entry| &entry.value) {
+                Some(Value::String(s)) => Reply::bulk(s),
+                Some(_) => return Reply::error(WRONG_TYPE),
+                None => Reply::Nil,
+            };
+            db.insert(parts[1].clone(), Entry::new(Value::String(parts[2].clone()), None));
+            notify_keyspace_event(session.db, NOTIFY_STRING, "set", &parts[1]);
+            old
+        }
+        
+        // Overwrites in place, so the key keeps its TTL. A gap before the
+        // offset is filled with zero bytes.
+        "SETRANGE" => {
+            if parts.len() != 4 {
+                return Reply::error("ERR usage: SETRANGE key offset value");
+            }
+            let Some(offset) = parse_num::<i64>(&parts[2]) else {
+                return Reply::error("ERR value is not an integer or out of range");
+            };
+            if offset < 0 {
+                return Reply::error("ERR offset is out of range");
+            }
+            if offset as u64 + parts[3].len() as u64 > MAX_STRING_LEN {
+                return Reply::error("ERR string exceeds maximum allowed size (proto-max-bulk-len)");
+            }
+            
+            purge_expired(db, &parts[1]);
+            let len = match db.peek(&parts[1]).map(|entry| &entry.value) {
+                Some(Value::String(s)) => s.len(),
+                Some(_) => return Reply::error(WRONG_TYPE),
+                None => 0,
+            };
+            // Writing nothing changes nothing, and creates no key
+            if parts[3].is_empty() {
+                return Reply::Integer(len as i64);
+            }
+            let entry = db.get_or_insert(parts[1].clone(), || Value::String(Vec::new()));
+            let (start, end) = (offset as usize, offset as usize + parts[3].len());
+            let mut len = 0;
+            if let Value::String(s) = &mut entry.value {
+                if s.len() < end {
+                    s.resize(end, 0);
+                }
+                s[start..end].copy_from_slice(&parts[3]);
+                len = s.len();
+            }
+            notify_keyspace_event(session.db, NOTIFY_STRING, "setrange", &parts[1]);
+            Reply::Integer(len as i64)
+        }
+        
+        // Indexes are inclusive and may count from the end, as in LRANGE
+        "GETRANGE" => {
+            if parts.len() != 4 {
+                return Reply::error("ERR usage: GETRANGE key start end");
+            }
+            let (Some(start), Some(end)) = (parse_num::<i64>(&parts[2]), parse_num::<i64>(&parts[3])) else {
+                return Reply::error("ERR value is not an integer or out of range");
+            };
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => match &entry.value {
+                    Value::String(s) => match resolve_range(start, end, s.len()) {
+                        Some((start, end)) => Reply::bulk(&s[start..=end]),
+                        None => Reply::bulk(b""),
+                    },
+                    _ => Reply::error(WRONG_TYPE),
+                },
+                _ => Reply::bulk(b""),
+            }
+        }
+        
+        // ========== LIST COMMANDS ==========
+        "LPUSH" => {
+            if parts.len() < 3 {
+                return Reply::error("ERR usage: LPUSH key value [value ...]");
+            }
+            purge_expired(db, &parts[1]);
+            let entry = db.get_or_insert(parts[1].clone(), || Value::List(Vec::new()));
+            
+            match &mut entry.value {
+                Value::List(list) => {
+                    // Each value goes to the head in turn, so they end up reversed
+                    for value in &parts[2..] {
+                        list.insert(0, value.clone());
+                    }
+                    store.notify_list_pushed();
+                    notify_keyspace_event(session.db, NOTIFY_LIST, "lpush", &parts[1]);
+                    Reply::Integer(list.len() as i64)
+                }
+                _ => Reply::error(WRONG_TYPE),
+            }
+        }
+        
+        "RPUSH" => {
+            if parts.len() < 3 {
+                return Reply::error("ERR usage: RPUSH key value [value ...]");
+            }
+            purge_expired(db, &parts[1]);
+            let entry = db.get_or_insert(parts[1].clone(), || Value::List(Vec::new()));
+            
+            match &mut entry.value {
+                Value::List(list) => {
+                    for value in &parts[2..] {
+                        list.push(value.clone());
+                    }
+                    store.notify_list_pushed();
+                    notify_keyspace_event(session.db, NOTIFY_LIST, "rpush", &parts[1]);
+                    Reply::Integer(list.len() as i64)
+                }
+                _ => Reply::error(WRONG_TYPE),
+            }
+        }
+        
+        "LPUSHX" | "RPUSHX" => {
+            if parts.len() < 3 {
+                return Reply::error(format!("ERR usage: {} key value [value ...]", cmd));
+            }
+            // Only push onto a list that already exists
+            match db.get_mut(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &mut entry.value {
+                        Value::List(list) => {
+                            for value in &parts[2..] {
+                                if cmd == "LPUSHX" {
+                                    list.insert(0, value.clone());
+                                } else {
+                                    list.push(value.clone());
+                                }
+                            }
+                            store.notify_list_pushed();
+                            let event = if cmd == "LPUSHX" { "lpush" } else { "rpush" };
+                            notify_keyspace_event(session.db, NOTIFY_LIST, event, &parts[1]);
+                            Reply::Integer(list.len() as i64)
+                        }
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        "LPOP" | "RPOP" => {
+            if parts.len() != 2 && parts.len() != 3 {
+                return Reply::error(format!("ERR usage: {} key [count]", cmd));
+            }
+            let count = match parts.get(2).map(|arg| parse_num::<i64>(arg)) {
+                None => None,
+                Some(Some(n)) if n >= 0 => Some(n as usize),
+                Some(Some(_)) => return Reply::error("ERR value is out of range, must be positive"),
+                Some(None) => return Reply::error("ERR value is not an integer or out of range"),
+            };
+            
+            match db.get_mut(&parts[1]) {
+                Some(ref mut entry) if !is_expired(entry) => {
+                    match &mut entry.value {
+                        Value::List(list) => {
+                            let n = count.unwrap_or(1).min(list.len());
+                            let popped: Vec<Vec<u8>> = if cmd == "LPOP" {
+                                list.drain(..n).collect()
+                            } else {
+                                list.drain(list.len() - n..).rev().collect()
+                            };
+                            let emptied = list.is_empty();
+                            if !popped.is_empty() {
+                                notify_keyspace_event(session.db, NOTIFY_LIST, if cmd == "LPOP" { "lpop" } else { "rpop" }, &parts[1]);
+                            }
+                            if emptied {
+                                db.remove(&parts[1]);
+                                notify_keyspace_event(session.db, NOTIFY_GENERIC, "del", &parts[1]);
+                            }
+                            // Without a count the reply is a single bulk string
+                            match count {
+                                Some(_) => Reply::Array(popped.into_iter().map(Reply::Bulk).collect()),
+                                None => popped.into_iter().next().map_or(Reply::Nil, Reply::Bulk),
+                            }
+                        }
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => if count.is_some() { Reply::NilArray } else { Reply::Nil },
+            }
+        }
+        
+        "BLPOP" | "BRPOP" => {
+            if parts.len() < 3 {
+                return Reply::error(format!("ERR usage: {} key [key ...] timeout", cmd));
+            }
+            match parse_num::<f64>(&parts[parts.len() - 1]) {
+                Some(t) if t < 0.0 => return Reply::error("ERR timeout is negative"),
+                Some(t) if Duration::try_from_secs_f64(t).is_ok() => {}
+                Some(t) if t.is_finite() => return Reply::error("ERR timeout is out of range"),
+                _ => return Reply::error("ERR timeout is not a float or out of range"),
+            }
+            
+            // Serve the first non-empty list, in the order the keys were given.
+            // When there is none, process_command does the waiting.
+            for key in &parts[1..parts.len() - 1] {
+                let list = match db.get_mut(key) {
+                    Some(entry) if !is_expired(entry) => match &mut entry.value {
+                        Value::List(list) => list,
+                        _ => return Reply::error(WRONG_TYPE),
+                    },
+                    _ => continue,
+                };
+                let value = if cmd == "BLPOP" { list.remove(0) } else { list.pop().unwrap_or_default() };
+                let emptied = list.is_empty();
+                notify_keyspace_event(session.db, NOTIFY_LIST, if cmd == "BLPOP" { "lpop" } else { "rpop" }, key);
+                if emptied {
+                    db.remove(key);
+                    notify_keyspace_event(session.db, NOTIFY_GENERIC, "del", key);
+                }
+                return Reply::Array(vec![Reply::bulk(key), Reply::Bulk(value)]);
+            }
+            Reply::NilArray
+        }
+        
+        "LLEN" => {
+            if parts.len() != 2 {
+                return Reply::error("ERR usage: LLEN key");
+            }
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::List(list) => Reply::Integer(list.len() as i64),
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        "LRANGE" => {
+            if parts.len() != 4 {
+                return Reply::error("ERR usage: LRANGE key start stop");
+            }
+            let start: i64 = parse_num(&parts[2]).unwrap_or(0);
+            let stop: i64 = parse_num(&parts[3]).unwrap_or(-1);
+            
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::List(list) => match resolve_range(start, stop, list.len()) {
+                            Some((from, to)) => Reply::Array(list[from..=to].iter().map(|v| Reply::bulk(v)).collect()),
+                            None => Reply::Array(Vec::new()),
+                        },
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Array(Vec::new()),
+            }
+        }
+        
+        "LPOS" => {
+            if parts.len() < 3 || parts.len().is_multiple_of(2) {
+                return Reply::error("ERR usage: LPOS key element [RANK rank] [COUNT num-matches] [MAXLEN len]");
+            }
+            let mut rank: i64 = 1;
+            let mut count: Option<usize> = None;
+            let mut maxlen = usize::MAX;
+            for option in parts[3..].chunks(2) {
+                let Some(n) = parse_num::<i64>(&option[1]) else {
+                    return Reply::error("ERR value is not an integer or out of range");
+                };
+                match upper(&option[0]).as_str() {
+                    "RANK" if n == 0 => return Reply::error("ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list"),
+                    "RANK" => rank = n,
+                    "COUNT" if n < 0 => return Reply::error("ERR COUNT can't be negative"),
+                    // COUNT 0 means "all matches"
+                    "COUNT" => count = Some(if n == 0 { usize::MAX } else { n as usize }),
+                    "MAXLEN" if n < 0 => return Reply::error("ERR MAXLEN can't be negative"),
+                    "MAXLEN" => maxlen = if n == 0 { usize::MAX } else { n as usize },
+                    _ => return Reply::error("ERR syntax error"),
+                }
+            }
+            
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::List(list) => {
+                            // A negative rank scans from the tail
+                            let indexes: Box<dyn Iterator<Item = usize>> = if rank > 0 {
+                                Box::new(0..list.len())
+                            } else {
+                                Box::new((0..list.len()).rev())
+                            };
+                            let found: Vec<usize> = indexes
+                                .take(maxlen)
+                                .filter(|&i| list[i] == parts[2])
+                                .skip(rank.unsigned_abs() as usize - 1)
+                                .take(count.unwrap_or(1))
+                                .collect();
+                            match count {
+                                Some(_) => Reply::Array(found.into_iter().map(|i| Reply::Integer(i as i64)).collect()),
+                                None => found.first().map_or(Reply::Nil, |&i| Reply::Integer(i as i64)),
+                            }
+                        }
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => if count.is_some() { Reply::Array(Vec::new()) } else { Reply::Nil },
+            }
+        }
+        
+        "LTRIM" => {
+            if parts.len() != 4 {
+                return Reply::error("ERR usage: LTRIM key start stop");
+            }
+            let (start, stop) = match (parse_num::<i64>(&parts[2]), parse_num::<i64>(&parts[3])) {
+                (Some(start), Some(stop)) => (start, stop),
+                _ => return Reply::error("ERR value is not an integer or out of range"),
+            };
+            
+            match db.get_mut(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &mut entry.value {
+                        Value::List(list) => {
+                            notify_keyspace_event(session.db, NOTIFY_LIST, "ltrim", &parts[1]);
+                            match resolve_range(start, stop, list.len()) {
+                                Some((from, to)) => {
+                                    list.truncate(to + 1);
+                                    list.drain(..from);
+                                }
+                                None => {
+                                    db.remove(&parts[1]);
+                                    notify_keyspace_event(session.db, NOTIFY_GENERIC, "del", &parts[1]);
+                                }
+                            }
+                            Reply::ok()
+                        }
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::ok(),
+            }
+        }
+        
+        "LINSERT" => {
+            if parts.len() != 5 {
+                return Reply::error("ERR usage: LINSERT key BEFORE|AFTER pivot value");
+            }
+            let after = match upper(&parts[2]).as_str() {
+                "BEFORE" => false,
+                "AFTER" => true,
+                _ => return Reply::error("ERR syntax error"),
+            };
+            
+            match db.get_mut(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &mut entry.value {
+                        Value::List(list) => match list.iter().position(|item| *item == parts[3]) {
+                            Some(pos) => {
+                                list.insert(if after { pos + 1 } else { pos }, parts[4].clone());
+                                notify_keyspace_event(session.db, NOTIFY_LIST, "linsert", &parts[1]);
+                                Reply::Integer(list.len() as i64)
+                            }
+                            None => Reply::Integer(-1),
+                        },
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        "LREM" => {
+            if parts.len() != 4 {
+                return Reply::error("ERR usage: LREM key count value");
+            }
+            let count = match parse_num::<i64>(&parts[2]) {
+                Some(n) => n,
+                None => return Reply::error("ERR value is not an integer or out of range"),
+            };
+            
+            match db.get_mut(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &mut entry.value {
+                        Value::List(list) => {
+                            // count > 0 scans head to tail, count < 0 tail to head, 0 removes all
+                            let limit = if count == 0 { usize::MAX } else { count.unsigned_abs() as usize };
+                            let mut removed = 0;
+                            if count < 0 {
+                                list.reverse();
+                            }
+                            list.retain(|item| {
+                                let matches = removed < limit && *item == parts[3];
+                                if matches {
+                                    removed += 1;
+                                }
+                                !matches
+                            });
+                            if count < 0 {
+                                list.reverse();
+                            }
+                            let emptied = list.is_empty();
+                            if removed > 0 {
+                                notify_keyspace_event(session.db, NOTIFY_LIST, "lrem", &parts[1]);
+                            }
+                            if emptied {
+                                db.remove(&parts[1]);
+                                notify_keyspace_event(session.db, NOTIFY_GENERIC, "del", &parts[1]);
+                            }
+                            Reply::Integer(removed as i64)
+                        }
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        // ========== HASH COMMANDS ==========
+        "HSET" | "HMSET" => {
+            if parts.len() < 4 || !parts.len().is_multiple_of(2) {
+                return Reply::error(format!("ERR usage: {} key field value [field value ...]", cmd));
+            }
+            purge_expired(db, &parts[1]);
+            let entry = db.get_or_insert(parts[1].clone(), || Value::Hash(HashMap::new()));
+            
+            match &mut entry.value {
+                Value::Hash(hash) => {
+                    let mut added = 0;
+                    for pair in parts[2..].chunks(2) {
+                        if hash.insert(pair[0].clone(), pair[1].clone()).is_none() {
+                            added += 1;
+                        }
+                    }
+                    notify_keyspace_event(session.db, NOTIFY_HASH, "hset", &parts[1]);
+                    // HMSET is the older multi-field form and just acknowledges
+                    if cmd == "HMSET" { Reply::ok() } else { Reply::Integer(added) }
+                }
+                _ => Reply::error(WRONG_TYPE),
+            }
+        }
+        
+        "HGET" => {
+            if parts.len() != 3 {
+                return Reply::error("ERR usage: HGET key field");
+            }
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::Hash(hash) => match hash.get(&parts[2]) {
+                            Some(value) => Reply::bulk(value),
+                            None => Reply::Nil,
+                        },
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Nil,
+            }
+        }
+        
+        "HMGET" => {
+            if parts.len() < 3 {
+                return Reply::error("ERR usage: HMGET key field [field ...]");
+            }
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::Hash(hash) => {
+                            let values = parts[2..]
+                                .iter()
+                                .map(|field| hash.get(field).map_or(Reply::Nil, |v| Reply::bulk(v)))
+                                .collect();
+                            Reply::Array(values)
+                        }
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Array(parts[2..].iter().map(|_| Reply::Nil).collect()),
+            }
+        }
+        
+        "HEXISTS" => {
+            if parts.len() != 3 {
+                return Reply::error("ERR usage: HEXISTS key field");
+            }
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::Hash(hash) => Reply::Integer(if hash.contains_key(&parts[2]) { 1 } else { 0 }),
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        "HLEN" => {
+            if parts.len() != 2 {
+                return Reply::error("ERR usage: HLEN key");
+            }
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::Hash(hash) => Reply::Integer(hash.len() as i64),
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        "HKEYS" | "HVALS" => {
+            if parts.len() != 2 {
+                return Reply::error(format!("ERR usage: {} key", cmd));
+            }
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::Hash(hash) => {
+                            let items = if cmd == "HKEYS" {
+                                hash.keys().map(|f| Reply::bulk(f)).collect()
+                            } else {
+                                hash.values().map(|v| Reply::bulk(v)).collect()
+                            };
+                            Reply::Array(items)
+                        }
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Array(Vec::new()),
+            }
+        }
+        
+        "HINCRBY" | "HINCRBYFLOAT" => {
+            if parts.len() != 4 {
+                return Reply::error(format!("ERR usage: {} key field increment", cmd));
+            }
+            let is_float = cmd == "HINCRBYFLOAT";
+            let int_delta = parse_num::<i64>(&parts[3]);
+            let float_delta = parse_num::<f64>(&parts[3]).filter(|d| !d.is_nan());
+            if !is_float && int_delta.is_none() {
+                return Reply::error("ERR value is not an integer or out of range");
+            }
+            if is_float && float_delta.is_none() {
+                return Reply::error("ERR value is not a valid float");
+            }
+            
+            purge_expired(db, &parts[1]);
+            let current = match db.get(&parts[1]).map(|entry| &entry.value) {
+                Some(Value::Hash(hash)) => hash.get(&parts[2]),
+                Some(_) => return Reply::error(WRONG_TYPE),
+                None => None,
+            };
+            
+            let (new_value, reply) = if is_float {
+                let current = match current {
+                    Some(v) => match parse_num::<f64>(v).filter(|n| !n.is_nan()) {
+                        Some(n) => n,
+                        None => return Reply::error("ERR hash value is not a float"),
+                    },
+                    None => 0.0,
+                };
+                let result = current + float_delta.unwrap_or_default();
+                if !result.is_finite() {
+                    return Reply::error("ERR increment would produce NaN or Infinity");
+                }
+                (format_float(result).into_bytes(), Reply::Double(result))
+            } else {
+                let current = match current {
+                    Some(v) => match parse_num::<i64>(v) {
+                        Some(n) => n,
+                        None => return Reply::error("ERR hash value is not an integer"),
+                    },
+                    None => 0,
+                };
+                match current.checked_add(int_delta.unwrap_or_default()) {
+                    Some(result) => (result.to_string().into_bytes(), Reply::Integer(result)),
+                    None => return Reply::error("ERR increment or decrement would overflow"),
+                }
+            };
+            
+            let entry = db.get_or_insert(parts[1].clone(), || Value::Hash(HashMap::new()));
+            if let Value::Hash(hash) = &mut entry.value {
+                hash.insert(parts[2].clone(), new_value);
+            }
+            notify_keyspace_event(session.db, NOTIFY_HASH, if is_float { "hincrbyfloat" } else { "hincrby" }, &parts[1]);
+            reply
+        }
+        
+        "HDEL" => {
+            if parts.len() < 3 {
+                return Reply::error("ERR usage: HDEL key field [field ...]");
+            }
+            match db.get_mut(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &mut entry.value {
+                        Value::Hash(hash) => {
+                            let removed = parts[2..].iter().filter(|field| hash.remove(*field).is_some()).count();
+                            let emptied = hash.is_empty();
+                            if removed > 0 {
+                                notify_keyspace_event(session.db, NOTIFY_HASH, "hdel", &parts[1]);
+                            }
+                            if emptied {
+                                db.remove(&parts[1]);
+                                notify_keyspace_event(session.db, NOTIFY_GENERIC, "del", &parts[1]);
+                            }
+                            Reply::Integer(removed as i64)
+                        }
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        "HRANDFIELD" => {
+            if parts.len() < 2 {
+                return Reply::error("ERR usage: HRANDFIELD key [count [WITHVALUES]]");
+            }
+            let (count, with_values) = match random_count(parts, "WITHVALUES") {
+                Ok(parsed) => parsed,
+                Err(e) => return e,
+            };
+            let hash = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => match &entry.value {
+                    Value::Hash(hash) => hash,
+                    _ => return Reply::error(WRONG_TYPE),
+                },
+                _ => return if count.is_some() { Reply::Array(Vec::new()) } else { Reply::Nil },
+            };
+            
+            // Sorted first, like SRANDMEMBER, so a seeded RNG is reproducible
+            let mut fields: Vec<(&Vec<u8>, &Vec<u8>)> = hash.iter().collect();
+            fields.sort();
+            let picked = random_picks(fields, count);
+            if count.is_none() {
+                return Reply::bulk(picked[0].0);
+            }
+            let mut items = Vec::new();
+            for (field, value) in picked {
+                items.push(Reply::bulk(field));
+                if with_values {
+                    items.push(Reply::bulk(value));
+                }
+            }
+            Reply::Array(items)
+        }
+        
+        "HGETALL" => {
+            if parts.len() != 2 {
+                return Reply::error("ERR usage: HGETALL key");
+            }
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::Hash(hash) => {
+                            Reply::Map(hash.iter().map(|(field, value)| (Reply::bulk(field), Reply::bulk(value))).collect())
+                        }
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Map(Vec::new()),
+            }
+        }
+        
+        "HSCAN" => {
+            if parts.len() < 3 {
+                return Reply::error("ERR usage: HSCAN key cursor [MATCH pattern] [COUNT count]");
+            }
+            let args = match ScanArgs::parse(&parts[2..], false) {
+                Ok(args) => args,
+                Err(reply) => return reply,
+            };
+            let (next, fields) = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::Hash(hash) => args.page(hash.iter(), hash.len(), |(field, _)| args.matches(field)),
+                        _ => return Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => (0, Vec::new()),
+            };
+            let mut items = Vec::with_capacity(fields.len() * 2);
+            for (field, value) in fields {
+                items.push(Reply::bulk(field));
+                items.push(Reply::bulk(value));
+            }
+            Reply::Array(vec![Reply::Bulk(next.to_string().into_bytes()), Reply::Array(items)])
+        }
+        
+        // ========== SET COMMANDS ==========
+        "SADD" => {
+            if parts.len() < 3 {
+                return Reply::error("ERR usage: SADD key member [member ...]");
+            }
+            purge_expired(db, &parts[1]);
+            let entry = db.get_or_insert(parts[1].clone(), || Value::Set(HashSet::new()));
+            
+            match &mut entry.value {
+                Value::Set(set) => {
+                    let added = parts[2..].iter().filter(|member| set.insert(member.to_vec())).count();
+                    if added > 0 {
+                        notify_keyspace_event(session.db, NOTIFY_SET, "sadd", &parts[1]);
+                    }
+                    Reply::Integer(added as i64)
+                }
+                _ => Reply::error(WRONG_TYPE),
+            }
+        }
+        
+        "SREM" => {
+            if parts.len() < 3 {
+                return Reply::error("ERR usage: SREM key member [member ...]");
+            }
+            match db.get_mut(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &mut entry.value {
+                        Value::Set(set) => {
+                            let removed = parts[2..].iter().filter(|member| set.remove(*member)).count();
+                            let emptied = set.is_empty();
+                            if removed > 0 {
+                                notify_keyspace_event(session.db, NOTIFY_SET, "srem", &parts[1]);
+                            }
+                            if emptied {
+                                db.remove(&parts[1]);
+                                notify_keyspace_event(session.db, NOTIFY_GENERIC, "del", &parts[1]);
+                            }
+                            Reply::Integer(removed as i64)
+                        }
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        "SMOVE" => {
+            if parts.len() != 4 {
+                return Reply::error("ERR usage: SMOVE source destination member");
+            }
+            let (source, destination, member) = (&parts[1], &parts[2], &parts[3]);
+            // Both keys must be sets (or missing) before anything moves
+            let empty = HashSet::new();
+            let present = match sets_at(db, &parts[1..3], &empty) {
+                Ok(sets) => sets[0].contains(member),
+                Err(e) => return e,
+            };
+            if !present || source == destination {
+                return Reply::Integer(present as i64);
+            }
+            
+            if let Some(Entry { value: Value::Set(set), .. }) = db.get_mut(source) {
+                set.remove(member);
+                let emptied = set.is_empty();
+                notify_keyspace_event(session.db, NOTIFY_SET, "srem", source);
+                if emptied {
+                    db.remove(source);
+                    notify_keyspace_event(session.db, NOTIFY_GENERIC, "del", source);
+                }
+            }
+            purge_expired(db, destination);
+            if let Value::Set(set) = &mut db.get_or_insert(destination.clone(), || Value::Set(HashSet::new())).value {
+                set.insert(member.clone());
+            }
+            notify_keyspace_event(session.db, NOTIFY_SET, "sadd", destination);
+            Reply::Integer(1)
+        }
+        
+        "SMEMBERS" => {
+            if parts.len() != 2 {
+                return Reply::error("ERR usage: SMEMBERS key");
+            }
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::Set(set) => Reply::Set(set.iter().map(|m| Reply::bulk(m)).collect()),
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Set(Vec::new()),
+            }
+        }
+        
+        "SSCAN" => {
+            if parts.len() < 3 {
+                return Reply::error("ERR usage: SSCAN key cursor [MATCH pattern] [COUNT count]");
+            }
+            let args = match ScanArgs::parse(&parts[2..], false) {
+                Ok(args) => args,
+                Err(reply) => return reply,
+            };
+            let (next, members) = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::Set(set) => args.page(set.iter(), set.len(), |member| args.matches(member)),
+                        _ => return Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => (0, Vec::new()),
+            };
+            let items = members.into_iter().map(|m| Reply::bulk(m)).collect();
+            Reply::Array(vec![Reply::Bulk(next.to_string().into_bytes()), Reply::Array(items)])
+        }
+        
+        "SISMEMBER" => {
+            if parts.len() != 3 {
+                return Reply::error("ERR usage: SISMEMBER key member");
+            }
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::Set(set) => Reply::Integer(if set.contains(&parts[2]) { 1 } else { 0 }),
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        "SMISMEMBER" => {
+            if parts.len() < 3 {
+                return Reply::error("ERR usage: SMISMEMBER key member [member ...]");
+            }
+            let empty = HashSet::new();
+            let set = match sets_at(db, &parts[1..2], &empty) {
+                Ok(sets) => sets[0],
+                Err(e) => return e,
+            };
+            Reply::Array(parts[2..].iter().map(|member| Reply::Integer(set.contains(member) as i64)).collect())
+        }
+        
+        "SCARD" => {
+            if parts.len() != 2 {
+                return Reply::error("ERR usage: SCARD key");
+            }
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::Set(set) => Reply::Integer(set.len() as i64),
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        "SPOP" | "SRANDMEMBER" => {
+            if parts.len() != 2 && parts.len() != 3 {
+                return Reply::error(format!("ERR usage: {} key [count]", cmd));
+            }
+            let count = match parts.get(2) {
+                Some(arg) if cmd == "SPOP" && parse_num::<i64>(arg).is_some_and(|n| n < 0) => {
+                    return Reply::error("ERR value is out of range, must be positive");
+                }
+                Some(arg) => match parse_random_count(arg) {
+                    Ok(n) => Some(n),
+                    Err(e) => return e,
+                },
+                None => None,
+            };
+            
+            let set = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => match &entry.value {
+                    Value::Set(set) => set,
+                    _ => return Reply::error(WRONG_TYPE),
+                },
+                _ => return if count.is_some() { Reply::Array(Vec::new()) } else { Reply::Nil },
+            };
+            
+            // Sample from a sorted view so a seeded RNG picks the same members
+            // regardless of the set's hash order
+            let mut members: Vec<Vec<u8>> = set.iter().cloned().collect();
+            members.sort();
+            let picked = random_picks(members, count);
+            
+            if cmd == "SPOP"
+                && let Some(Entry { value: Value::Set(set), .. }) = db.get_mut(&parts[1])
+            {
+                for member in &picked {
+                    set.remove(member);
+                }
+                let emptied = set.is_empty();
+                if !picked.is_empty() {
+                    notify_keyspace_event(session.db, NOTIFY_SET, "spop", &parts[1]);
+                }
+                if emptied {
+                    db.remove(&parts[1]);
+                    notify_keyspace_event(session.db, NOTIFY_GENERIC, "del", &parts[1]);
+                }
+            }
+            match count {
+                Some(_) => Reply::Array(picked.iter().map(|m| Reply::bulk(m)).collect()),
+                None => picked.first().map_or(Reply::Nil, |m| Reply::bulk(m)),
+            }
+        }
+        
+        "SINTER" | "SUNION" | "SDIFF" => {
+            if parts.len() < 2 {
+                return Reply::error(format!("ERR usage: {} key [key ...]", cmd));
+            }
+            match set_algebra(db, cmd, &parts[1..]) {
+                Ok(result) => Reply::Set(result.iter().map(|m| Reply::bulk(m)).collect()),
+                Err(e) => e,
+            }
+        }
+        
+        "SINTERCARD" => {
+            let usage = "ERR usage: SINTERCARD numkeys key [key ...] [LIMIT limit]";
+            let Some(numkeys) = parts.get(1).and_then(|n| parse_num::<i64>(n)) else {
+                return Reply::error(usage);
+            };
+            if numkeys <= 0 {
+                return Reply::error("ERR numkeys should be greater than 0");
+            }
+            if numkeys as usize > parts.len() - 2 {
+                return Reply::error("ERR Number of keys can't be greater than number of args");
+            }
+            let keys = &parts[2..2 + numkeys as usize];
+            // 0, the default, is no limit
+            let limit = match &parts[2 + keys.len()..] {
+                [] => 0,
+                [option, limit] if upper(option) == "LIMIT" => match parse_num::<i64>(limit) {
+                    Some(limit) if limit >= 0 => limit as usize,
+                    Some(_) => return Reply::error("ERR LIMIT can't be negative"),
+                    None => return Reply::error("ERR value is not an integer or out of range"),
+                },
+                _ => return Reply::error("ERR syntax error"),
+            };
+            
+            // Walk the smallest set, counting members found in all the others
+            let empty = HashSet::new();
+            let mut sets = match sets_at(db, keys, &empty) {
+                Ok(sets) => sets,
+                Err(e) => return e,
+            };
+            sets.sort_by_key(|set| set.len());
+            let (smallest, rest) = sets.split_first().expect("at least one key");
+            let common = smallest.iter().filter(|member| rest.iter().all(|set| set.contains(*member)));
+            let count = if limit > 0 { common.take(limit).count() } else { common.count() };
+            Reply::Integer(count as i64)
+        }
+        
+        "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" => {
+            if parts.len() < 3 {
+                return Reply::error(format!("ERR usage: {} destination key [key ...]", cmd));
+            }
+            let op = cmd.trim_end_matches("STORE");
+            let result = match set_algebra(db, op, &parts[2..]) {
+                Ok(result) => result,
+                Err(e) => return e,
+            };
+            
+            // The destination is overwritten whatever it held before
+            let count = result.len();
+            if result.is_empty() {
+                if db.remove(&parts[1]).is_some() {
+                    notify_keyspace_event(session.db, NOTIFY_GENERIC, "del", &parts[1]);
+                }
+            } else {
+                db.insert(parts[1].clone(), Entry::new(Value::Set(result), None));
+                let event = match cmd {
+                    "SINTERSTORE" => "sinterstore",
+                    "SUNIONSTORE" => "sunionstore",
+                    _ => "sdiffstore",
+                };
+                notify_keyspace_event(session.db, NOTIFY_SET, event, &parts[1]);
+            }
+            Reply::Integer(count as i64)
+        }
+        
+        // ========== SORTED SET COMMANDS ==========
+        "ZADD" => {
+            if parts.len() < 4 || !parts.len().is_multiple_of(2) {
+                return Reply::error("ERR usage: ZADD key score member [score member ...]");
+            }
+            let mut pairs = Vec::with_capacity((parts.len() - 2) / 2);
+            for pair in parts[2..].chunks(2) {
+                match parse_num::<f64>(&pair[0]).filter(|s| !s.is_nan()) {
+                    Some(score) => pairs.push((score, pair[1].clone())),
+                    None => return Reply::error("ERR value is not a valid float"),
+                }
+            }
+            
+            purge_expired(db, &parts[1]);
+            let entry = db.get_or_insert(parts[1].clone(), || Value::ZSet(ZSet::default()));
+            
+            match &mut entry.value {
+                Value::ZSet(zset) => {
+                    let added = pairs
+                        .into_iter()
+                        .filter(|(score, member)| zset.insert(member.clone(), *score))
+                        .count();
+                    notify_keyspace_event(session.db, NOTIFY_ZSET, "zadd", &parts[1]);
+                    Reply::Integer(added as i64)
+                }
+                _ => Reply::error(WRONG_TYPE),
+            }
+        }
+        
+        "ZSCORE" => {
+            if parts.len() != 3 {
+                return Reply::error("ERR usage: ZSCORE key member");
+            }
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::ZSet(zset) => match zset.score(&parts[2]) {
+                            Some(score) => Reply::Double(score),
+                            None => Reply::Nil,
+                        },
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Nil,
+            }
+        }
+        
+        "ZSCAN" => {
+            if parts.len() < 3 {
+                return Reply::error("ERR usage: ZSCAN key cursor [MATCH pattern] [COUNT count]");
+            }
+            let args = match ScanArgs::parse(&parts[2..], false) {
+                Ok(args) => args,
+                Err(reply) => return reply,
+            };
+            let (next, members) = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::ZSet(zset) => args.page(zset.iter(), zset.len(), |(member, _)| args.matches(member)),
+                        _ => return Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => (0, Vec::new()),
+            };
+            let mut items = Vec::with_capacity(members.len() * 2);
+            for (member, score) in members {
+                items.push(Reply::bulk(member));
+                items.push(Reply::Bulk(format_float(score).into_bytes()));
+            }
+            Reply::Array(vec![Reply::Bulk(next.to_string().into_bytes()), Reply::Array(items)])
+        }
+        
+        "ZRANGE" => {
+            let with_scores = parts.len() == 5 && upper(&parts[4]) == "WITHSCORES";
+            if parts.len() != 4 && !with_scores {
+                return Reply::error("ERR usage: ZRANGE key start stop [WITHSCORES]");
+            }
+            let (start, stop) = match (parse_num::<i64>(&parts[2]), parse_num::<i64>(&parts[3])) {
+                (Some(start), Some(stop)) => (start, stop),
+                _ => return Reply::error("ERR value is not an integer or out of range"),
+            };
+            
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::ZSet(zset) => {
+                            let mut items = Vec::new();
+                            if let Some((from, to)) = resolve_range(start, stop, zset.len()) {
+                                for (member, score) in zset.iter().skip(from).take(to - from + 1) {
+                                    items.push(Reply::bulk(member));
+                                    if with_scores {
+                                        items.push(Reply::Bulk(format_float(score).into_bytes()));
+                                    }
+                                }
+                            }
+                            Reply::Array(items)
+                        }
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Array(Vec::new()),
+            }
+        }
+        
+        "ZRANGEBYSCORE" => {
+            if parts.len() < 4 {
+                return Reply::error("ERR usage: ZRANGEBYSCORE key min max [WITHSCORES] [LIMIT offset count]");
+            }
+            let (min, max) = match (ScoreBound::parse(&parts[2]), ScoreBound::parse(&parts[3])) {
+                (Some(min), Some(max)) => (min, max),
+                _ => return Reply::error("ERR min or max is not a float"),
+            };
+            
+            let mut with_scores = false;
+            let mut offset = 0usize;
+            let mut limit: Option<usize> = None;
+            let mut i = 4;
+            while i < parts.len() {
+                match upper(&parts[i]).as_str() {
+                    "WITHSCORES" => with_scores = true,
+                    "LIMIT" if i + 2 < parts.len() => {
+                        let (Some(off), Some(count)) = (parse_num::<i64>(&parts[i + 1]), parse_num::<i64>(&parts[i + 2])) else {
+                            return Reply::error("ERR value is not an integer or out of range");
+                        };
+                        // A negative offset returns nothing, a negative count means "all"
+                        offset = if off < 0 { usize::MAX } else { off as usize };
+                        limit = usize::try_from(count).ok();
+                        i += 2;
+                    }
+                    _ => return Reply::error("ERR syntax error"),
+                }
+                i += 1;
+            }
+            
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::ZSet(zset) => {
+                            let mut items = Vec::new();
+                            let matches = zset.range_by_score(min, max).skip(offset).take(limit.unwrap_or(usize::MAX));
+                            for (member, score) in matches {
+                                items.push(Reply::bulk(member));
+                                if with_scores {
+                                    items.push(Reply::Bulk(format_float(score).into_bytes()));
+                                }
+                            }
+                            Reply::Array(items)
+                        }
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Array(Vec::new()),
+            }
+        }
+        
+        "ZCOUNT" => {
+            if parts.len() != 4 {
+                return Reply::error("ERR usage: ZCOUNT key min max");
+            }
+            let (min, max) = match (ScoreBound::parse(&parts[2]), ScoreBound::parse(&parts[3])) {
+                (Some(min), Some(max)) => (min, max),
+                _ => return Reply::error("ERR min or max is not a float"),
+            };
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::ZSet(zset) => Reply::Integer(zset.range_by_score(min, max).count() as i64),
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        "ZINCRBY" => {
+            if parts.len() != 4 {
+                return Reply::error("ERR usage: ZINCRBY key increment member");
+            }
+            let increment = match parse_num::<f64>(&parts[2]).filter(|n| !n.is_nan()) {
+                Some(n) => n,
+                None => return Reply::error("ERR value is not a valid float"),
+            };
+            
+            purge_expired(db, &parts[1]);
+            let entry = db.get_or_insert(parts[1].clone(), || Value::ZSet(ZSet::default()));
+            
+            match &mut entry.value {
+                Value::ZSet(zset) => {
+                    let score = zset.score(&parts[3]).unwrap_or(0.0) + increment;
+                    if score.is_nan() {
+                        // inf + -inf: leave the set as it was
+                        if zset.is_empty() {
+                            db.remove(&parts[1]);
+                        }
+                        return Reply::error("ERR resulting score is not a number (NaN)");
+                    }
+                    zset.insert(parts[3].clone(), score);
+                    notify_keyspace_event(session.db, NOTIFY_ZSET, "zincr", &parts[1]);
+                    Reply::Double(score)
+                }
+                _ => Reply::error(WRONG_TYPE),
+            }
+        }
+        
+        "ZPOPMIN" | "ZPOPMAX" => {
+            if parts.len() != 2 && parts.len() != 3 {
+                return Reply::error(format!("ERR usage: {} key [count]", cmd));
+            }
+            let count = match parts.get(2).map(|arg| parse_num::<i64>(arg)) {
+                None => 1,
+                Some(Some(n)) if n >= 0 => n as usize,
+                Some(Some(_)) => return Reply::error("ERR value is out of range, must be positive"),
+                Some(None) => return Reply::error("ERR value is not an integer or out of range"),
+            };
+            
+            match db.get_mut(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &mut entry.value {
+                        Value::ZSet(zset) => {
+                            let popped: Vec<(Vec<u8>, f64)> = if cmd == "ZPOPMIN" {
+                                zset.iter().take(count).map(|(m, s)| (m.clone(), s)).collect()
+                            } else {
+                                zset.iter().rev().take(count).map(|(m, s)| (m.clone(), s)).collect()
+                            };
+                            
+                            let mut items = Vec::with_capacity(popped.len() * 2);
+                            for (member, score) in popped {
+                                zset.remove(&member);
+                                items.push(Reply::Bulk(member));
+                                items.push(Reply::Bulk(format_float(score).into_bytes()));
+                            }
+                            let emptied = zset.is_empty();
+                            if !items.is_empty() {
+                                let event = if cmd == "ZPOPMIN" { "zpopmin" } else { "zpopmax" };
+                                notify_keyspace_event(session.db, NOTIFY_ZSET, event, &parts[1]);
+                            }
+                            if emptied {
+                                db.remove(&parts[1]);
+                                notify_keyspace_event(session.db, NOTIFY_GENERIC, "del", &parts[1]);
+                            }
+                            Reply::Array(items)
+                        }
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Array(Vec::new()),
+            }
+        }
+        
+        "ZREM" => {
+            if parts.len() < 3 {
+                return Reply::error("ERR usage: ZREM key member [member ...]");
+            }
+            match db.get_mut(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &mut entry.value {
+                        Value::ZSet(zset) => {
+                            let removed = parts[2..].iter().filter(|member| zset.remove(member)).count();
+                            let emptied = zset.is_empty();
+                            if removed > 0 {
+                                notify_keyspace_event(session.db, NOTIFY_ZSET, "zrem", &parts[1]);
+                            }
+                            if emptied {
+                                db.remove(&parts[1]);
+                                notify_keyspace_event(session.db, NOTIFY_GENERIC, "del", &parts[1]);
+                            }
+                            Reply::Integer(removed as i64)
+                        }
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        "ZRANDMEMBER" => {
+            if parts.len() < 2 {
+                return Reply::error("ERR usage: ZRANDMEMBER key [count [WITHSCORES]]");
+            }
+            let (count, with_scores) = match random_count(parts, "WITHSCORES") {
+                Ok(parsed) => parsed,
+                Err(e) => return e,
+            };
+            let zset = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => match &entry.value {
+                    Value::ZSet(zset) => zset,
+                    _ => return Reply::error(WRONG_TYPE),
+                },
+                _ => return if count.is_some() { Reply::Array(Vec::new()) } else { Reply::Nil },
+            };
+            
+            let picked = random_picks(zset.iter().collect(), count);
+            if count.is_none() {
+                return Reply::bulk(picked[0].0);
+            }
+            let mut items = Vec::new();
+            for (member, score) in picked {
+                items.push(Reply::bulk(member));
+                if with_scores {
+                    items.push(Reply::Bulk(format_float(score).into_bytes()));
+                }
+            }
+            Reply::Array(items)
+        }
+        
+        "ZCARD" => {
+            if parts.len() != 2 {
+                return Reply::error("ERR usage: ZCARD key");
+            }
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    match &entry.value {
+                        Value::ZSet(zset) => Reply::Integer(zset.len() as i64),
+                        _ => Reply::error(WRONG_TYPE),
+                    }
+                }
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        // ========== HYPERLOGLOG COMMANDS ==========
+        "PFADD" => {
+            if parts.len() < 2 {
+                return Reply::error("ERR usage: PFADD key [element ...]");
+            }
+            purge_expired(db, &parts[1]);
+            let created = !db.contains_key(&parts[1]);
+            let entry = db.get_or_insert(parts[1].clone(), || Value::HyperLogLog(HyperLogLog::default()));
+            
+            match &mut entry.value {
+                Value::HyperLogLog(hll) => {
+                    // Not short-circuiting: every element must be added
+                    let changed = parts[2..].iter().fold(created, |changed, element| hll.add(element) | changed);
+                    if changed {
+                        notify_keyspace_event(session.db, NOTIFY_STRING, "pfadd", &parts[1]);
+                    }
+                    Reply::Integer(changed as i64)
+                }
+                _ => Reply::error(NOT_HYPERLOGLOG),
+            }
+        }
+        
+        // Several keys are counted as the union of their HyperLogLogs
+        "PFCOUNT" => {
+            if parts.len() < 2 {
+                return Reply::error("ERR usage: PFCOUNT key [key ...]");
+            }
+            let mut union = HyperLogLog::default();
+            for key in &parts[1..] {
+                match db.get(key) {
+                    Some(entry) if !is_expired(entry) => match &entry.value {
+                        Value::HyperLogLog(hll) if parts.len() == 2 => return Reply::Integer(hll.count() as i64),
+                        Value::HyperLogLog(hll) => union.merge(hll),
+                        _ => return Reply::error(NOT_HYPERLOGLOG),
+                    },
+                    _ => {}
+                }
+            }
+            Reply::Integer(union.count() as i64)
+        }
+        
+        // The destination's own registers are part of the union
+        "PFMERGE" => {
+            if parts.len() < 2 {
+                return Reply::error("ERR usage: PFMERGE destkey [sourcekey ...]");
+            }
+            let mut union = HyperLogLog::default();
+            for key in &parts[1..] {
+                match db.get(key) {
+                    Some(entry) if !is_expired(entry) => match &entry.value {
+                        Value::HyperLogLog(hll) => union.merge(hll),
+                        _ => return Reply::error(NOT_HYPERLOGLOG),
+                    },
+                    _ => {}
+                }
+            }
+            // Updated in place, so an existing destination keeps its TTL
+            purge_expired(db, &parts[1]);
+            db.get_or_insert(parts[1].clone(), || Value::HyperLogLog(HyperLogLog::default())).value = Value::HyperLogLog(union);
+            notify_keyspace_event(session.db, NOTIFY_STRING, "pfadd", &parts[1]);
+            Reply::ok()
+        }
+        
+        // ========== GEO COMMANDS ==========
+        "GEOADD" => {
+            let usage = "ERR usage: GEOADD key [NX|XX] [CH] longitude latitude member [longitude latitude member ...]";
+            if parts.len() < 5 {
+                return Reply::error(usage);
+            }
+            let (mut nx, mut xx, mut ch) = (false, false, false);
+            let mut i = 2;
+            while i < parts.len() {
+                match upper(&parts[i]).as_str() {
+                    "NX" => nx = true,
+                    "XX" => xx = true,
+                    "CH" => ch = true,
+                    _ => break,
+                }
+                i += 1;
+            }
+            if nx && xx {
+                return Reply::error("ERR XX and NX options at the same time are not compatible");
+            }
+            let triples = &parts[i..];
+            if triples.is_empty() || !triples.len().is_multiple_of(3) {
+                return Reply::error(usage);
+            }
+            let mut points = Vec::with_capacity(triples.len() / 3);
+            for triple in triples.chunks(3) {
+                match parse_coordinates(&triple[0], &triple[1]) {
+                    Ok((lon, lat)) => points.push((geohash_encode(lon, lat) as f64, triple[2].clone())),
+                    Err(e) => return e,
+                }
+            }
+            
+            purge_expired(db, &parts[1]);
+            // XX can't add anything, so don't create the key for it
+            if xx && !db.contains_key(&parts[1]) {
+                return Reply::Integer(0);
+            }
+            let entry = db.get_or_insert(parts[1].clone(), || Value::ZSet(ZSet::default()));
+            
+            match &mut entry.value {
+                Value::ZSet(zset) => {
+                    let (mut added, mut updated) = (0, 0);
+                    for (score, member) in points {
+                        match zset.score(&member) {
+                            Some(old) if !nx && old != score => {
+                                zset.insert(member, score);
+                                updated += 1;
+                            }
+                            None if !xx => {
+                                zset.insert(member, score);
+                                added += 1;
+                            }
+                            _ => {}
+                        }
+                    }
+                    if added + updated > 0 {
+                        notify_keyspace_event(session.db, NOTIFY_ZSET, "zadd", &parts[1]);
+                    }
+                    Reply::Integer(if ch { added + updated } else { added })
+                }
+                _ => Reply::error(WRONG_TYPE),
+            }
+        }
+        
+        "GEOPOS" => {
+            if parts.len() < 2 {
+                return Reply::error("ERR usage: GEOPOS key [member ...]");
+            }
+            let zset = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => match &entry.value {
+                    Value::ZSet(zset) => Some(zset),
+                    _ => return Reply::error(WRONG_TYPE),
+                },
+                _ => None,
+            };
+            Reply::Array(
+                parts[2..]
+                    .iter()
+                    .map(|member| match zset.and_then(|zset| zset.score(member)) {
+                        Some(score) => {
+                            let (lon, lat) = geohash_decode(score as u64);
+                            Reply::Array(vec![Reply::Double(lon), Reply::Double(lat)])
+                        }
+                        None => Reply::NilArray,
+                    })
+                    .collect(),
+            )
+        }
+        
+        // Distances are rounded to four decimals, in meters unless a unit is given
+        "GEODIST" => {
+            if parts.len() != 4 && parts.len() != 5 {
+                return Reply::error("ERR usage: GEODIST key member1 member2 [M|KM|FT|MI]");
+            }
+            let unit = match parts.get(4).map(|unit| geo_unit(unit)).unwrap_or(Ok(1.0)) {
+                Ok(unit) => unit,
+                Err(e) => return e,
+            };
+            let zset = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => match &entry.value {
+                    Value::ZSet(zset) => zset,
+                    _ => return Reply::error(WRONG_TYPE),
+                },
+                _ => return Reply::Nil,
+            };
+            match (zset.score(&parts[2]), zset.score(&parts[3])) {
+                (Some(a), Some(b)) => {
+                    let meters = geo_distance(geohash_decode(a as u64), geohash_decode(b as u64));
+                    Reply::Bulk(format!("{:.4}", meters / unit).into_bytes())
+                }
+                _ => Reply::Nil,
+            }
+        }
+        
+        // Members are scanned one by one; COUNT without ANY returns the
+        // nearest ones, so it implies ASC
+        "GEOSEARCH" => {
+            let usage = "ERR usage: GEOSEARCH key FROMMEMBER member|FROMLONLAT longitude latitude \
+                BYRADIUS radius unit|BYBOX width height unit [ASC|DESC] [COUNT count [ANY]] [WITHCOORD] [WITHDIST] [WITHHASH]";
+            if parts.len() < 2 {
+                return Reply::error(usage);
+            }
+            let one_origin = "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH";
+            let one_shape = "ERR exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH";
+            let (mut from_member, mut from_position) = (None, None);
+            let mut shape = None;
+            let mut unit = 1.0;
+            let mut descending = None;
+            let mut count = None;
+            let mut any = false;
+            let (mut with_coord, mut with_dist, mut with_hash) = (false, false, false);
+            let mut i = 2;
+            while i < parts.len() {
+                let left = parts.len() - i - 1;
+                match upper(&parts[i]).as_str() {
+                    "FROMMEMBER" | "FROMLONLAT" if from_member.is_some() || from_position.is_some() => {
+                        return Reply::error(one_origin);
+                    }
+                    "FROMMEMBER" if left >= 1 => {
+                        from_member = Some(&parts[i + 1]);
+                        i += 1;
+                    }
+                    "FROMLONLAT" if left >= 2 => {
+                        match parse_coordinates(&parts[i + 1], &parts[i + 2]) {
+                            Ok(position) => from_position = Some(position),
+                            Err(e) => return e,
+                        }
+                        i += 2;
+                    }
+                    "BYRADIUS" | "BYBOX" if shape.is_some() => return Reply::error(one_shape),
+                    "BYRADIUS" if left >= 2 => {
+                        let Some(radius) = parse_num::<f64>(&parts[i + 1]).filter(|r| !r.is_nan()) else {
+                            return Reply::error("ERR need numeric radius");
+                        };
+                        if radius < 0.0 {
+                            return Reply::error("ERR radius cannot be negative");
+                        }
+                        unit = match geo_unit(&parts[i + 2]) {
+                            Ok(unit) => unit,
+                            Err(e) => return e,
+                        };
+                        shape = Some(GeoShape::Radius(radius * unit));
+                        i += 2;
+                    }
+                    "BYBOX" if left >= 3 => {
+                        let (Some(width), Some(height)) = (parse_num::<f64>(&parts[i + 1]), parse_num::<f64>(&parts[i + 2])) else {
+                            return Reply::error("ERR need numeric width and height");
+                        };
+                        if !(width >= 0.0 && height >= 0.0) {
+                            return Reply::error("ERR height or width cannot be negative");
+                        }
+                        unit = match geo_unit(&parts[i + 3]) {
+                            Ok(unit) => unit,
+                            Err(e) => return e,
+                        };
+                        shape = Some(GeoShape::Box { width: width * unit, height: height * unit });
+                        i += 3;
+                    }
+                    "ASC" => descending = Some(false),
+                    "DESC" => descending = Some(true),
+                    "COUNT" if left >= 1 => {
+                        match parse_num::<i64>(&parts[i + 1]) {
+                            Some(n) if n > 0 => count = Some(n as usize),
+                            Some(_) => return Reply::error("ERR COUNT must be > 0"),
+                            None => return Reply::error("ERR value is not an integer or out of range"),
+                        }
+                        i += 1;
+                        if parts.get(i + 1).is_some_and(|arg| upper(arg) == "ANY") {
+                            any = true;
+                            i += 1;
+                        }
+                    }
+                    "WITHCOORD" => with_coord = true,
+                    "WITHDIST" => with_dist = true,
+                    "WITHHASH" => with_hash = true,
+                    _ => return Reply::error("ERR syntax error"),
+                }
+                i += 1;
+            }
+            if from_member.is_none() && from_position.is_none() {
+                return Reply::error(one_origin);
+            }
+            let Some(shape) = shape else {
+                return Reply::error(one_shape);
+            };
+            
+            let zset = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => match &entry.value {
+                    Value::ZSet(zset) => zset,
+                    _ => return Reply::error(WRONG_TYPE),
+                },
+                _ => return Reply::Array(Vec::new()),
+            };
+            let centre = match (from_position, from_member) {
+                (Some(position), _) => position,
+                (None, member) => match member.and_then(|member| zset.score(member)) {
+                    Some(score) => geohash_decode(score as u64),
+                    None => return Reply::error("ERR could not decode requested zset member"),
+                },
+            };
+            let mut found = Vec::new();
+            for (member, score) in zset.iter() {
+                let position = geohash_decode(score as u64);
+                if let Some(distance) = shape.distance_within(centre, position) {
+                    found.push((member, distance, score as i64, position));
+                    // ANY settles for the first matches, not the nearest
+                    if any && Some(found.len()) == count {
+                        break;
+                    }
+                }
+            }
+            let descending = descending.or((count.is_some() && !any).then_some(false));
+            if let Some(descending) = descending {
+                found.sort_by(|a, b| if descending { b.1.total_cmp(&a.1) } else { a.1.total_cmp(&b.1) });
+            }
+            if let Some(count) = count {
+                found.truncate(count);
+            }
+            
+            Reply::Array(
+                found
+                    .into_iter()
+                    .map(|(member, distance, hash, (lon, lat))| {
+                        if !with_dist && !with_hash && !with_coord {
+                            return Reply::bulk(member);
+                        }
+                        let mut item = vec![Reply::bulk(member)];
+                        if with_dist {
+                            item.push(Reply::Bulk(format!("{:.4}", distance / unit).into_bytes()));
+                        }
+                        if with_hash {
+                            item.push(Reply::Integer(hash));
+                        }
+                        if with_coord {
+                            item.push(Reply::Array(vec![Reply::Double(lon), Reply::Double(lat)]));
+                        }
+                        Reply::Array(item)
+                    })
+                    .collect(),
+            )
+        }
+        
+        // ========== STREAM COMMANDS ==========
+        // The ID is "*" to generate one, "<ms>-*" to pick only the sequence
+        // number, or given in full
+        "XADD" => {
+            if parts.len() < 5 || !(parts.len() - 3).is_multiple_of(2) {
+                return Reply::error("ERR usage: XADD key <* | id> field value [field value ...]");
+            }
+            let invalid = "ERR Invalid stream ID specified as stream command argument";
+            let (ms, seq) = match parts[2].as_slice() {
+                b"*" => (None, None),
+                id => match id.strip_suffix(b"-*").map(parse_num::<u64>) {
+                    Some(Some(ms)) => (Some(ms), None),
+                    Some(None) => return Reply::error(invalid),
+                    None => match StreamId::parse(id, 0) {
+                        Some(id) => (Some(id.ms), Some(id.seq)),
+                        None => return Reply::error(invalid),
+                    },
+                },
+            };
+            if (ms, seq) == (Some(0), Some(0)) {
+                return Reply::error("ERR The ID specified in XADD must be greater than 0-0");
+            }
+            let fields: StreamFields = parts[3..].chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect();
+            
+            purge_expired(db, &parts[1]);
+            let entry = db.get_or_insert(parts[1].clone(), || Value::Stream(Stream::default()));
+            
+            match &mut entry.value {
+                Value::Stream(stream) => match stream.next_id(ms, seq) {
+                    Ok(id) => {
+                        stream.add(id, fields);
+                        notify_keyspace_event(session.db, NOTIFY_STREAM, "xadd", &parts[1]);
+                        Reply::Bulk(id.to_string().into_bytes())
+                    }
+                    Err(e) => Reply::error(e),
+                },
+                _ => Reply::error(WRONG_TYPE),
+            }
+        }
+        
+        "XLEN" => {
+            if parts.len() != 2 {
+                return Reply::error("ERR usage: XLEN key");
+            }
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => match &entry.value {
+                    Value::Stream(stream) => Reply::Integer(stream.entries.len() as i64),
+                    _ => Reply::error(WRONG_TYPE),
+                },
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        // XREVRANGE takes the same interval with its ends swapped, and
+        // walks it from the end
+        "XRANGE" | "XREVRANGE" => {
+            let reverse = cmd == "XREVRANGE";
+            if parts.len() != 4 && !(parts.len() == 6 && upper(&parts[4]) == "COUNT") {
+                let ends = if reverse { "end start" } else { "start end" };
+                return Reply::error(format!("ERR usage: {} key {} [COUNT count]", cmd, ends));
+            }
+            let (start, end) = if reverse { (&parts[3], &parts[2]) } else { (&parts[2], &parts[3]) };
+            let (start, end) = match (StreamId::parse_bound(start, true), StreamId::parse_bound(end, false)) {
+                (Ok(start), Ok(end)) => (start, end),
+                (Err(e), _) | (_, Err(e)) => return Reply::error(e),
+            };
+            // A COUNT of 0 or less returns nothing
+            let count = match parts.get(5).map(|n| parse_num::<i64>(n)) {
+                None => usize::MAX,
+                Some(Some(n)) => n.max(0) as usize,
+                Some(None) => return Reply::error("ERR value is not an integer or out of range"),
+            };
+            let stream = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => match &entry.value {
+                    Value::Stream(stream) => stream,
+                    _ => return Reply::error(WRONG_TYPE),
+                },
+                _ => return Reply::Array(Vec::new()),
+            };
+            if start > end {
+                return Reply::Array(Vec::new());
+            }
+            let range = stream.entries.range(start..=end);
+            let entries: Box<dyn Iterator<Item = _>> = if reverse { Box::new(range.rev()) } else { Box::new(range) };
+            Reply::Array(
+                entries
+                    .take(count)
+                    .map(|(id, fields)| {
+                        let fields = fields.iter().flat_map(|(field, value)| [Reply::bulk(field), Reply::bulk(value)]);
+                        Reply::Array(vec![Reply::Bulk(id.to_string().into_bytes()), Reply::Array(fields.collect())])
+                    })
+                    .collect(),
+            )
+        }
+        
+        // ========== PERSISTENCE COMMANDS ==========
+        "SAVE" => {
+            let snapshot = encode_snapshot(shards);
+            let dirty = DIRTY.load(Ordering::Relaxed);
+            let filename = config().dbfilename.clone();
+            match write_snapshot(&snapshot, &filename) {
+                Ok(()) => {
+                    clear_dirty(dirty);
+                    Reply::ok()
+                }
+                Err(e) => Reply::error(format!("ERR {}", e)),
+            }
+        }
+        
+        "BGSAVE" => {
+            if BGSAVE_RUNNING.swap(true, Ordering::SeqCst) {
+                return Reply::error("ERR Background save already in progress");
+            }
+            let store_clone = Arc::clone(store);
+            let filename = config().dbfilename.clone();
+            std::thread::spawn(move || {
+                match save_data(&store_clone, &filename) {
+                    Ok(()) => println!("Background save completed"),
+                    Err(e) => eprintln!("Background save failed: {}", e),
+                }
+                BGSAVE_RUNNING.store(false, Ordering::SeqCst);
+            });
+            Reply::Simple("Background saving started".to_string())
+        }
+        
+        "LASTSAVE" => Reply::Integer(last_save_time()),
+        
+        // Exits without a reply; only answers if the final save fails
+        "SHUTDOWN" => {
+            // By default save only when snapshots are configured
+            let save = match parts.get(1).map(|p| upper(p)).as_deref() {
+                None if parts.len() == 1 => !config().save_points.is_empty(),
+                Some("SAVE") if parts.len() == 2 => true,
+                Some("NOSAVE") if parts.len() == 2 => false,
+                _ => return Reply::error("ERR syntax error"),
+            };
+            eprintln!("Error: {}", shutdown(store, shards, save));
+            Reply::error("ERR Errors trying to SHUTDOWN. Check logs.")
+        }
+        
+        "BGREWRITEAOF" => {
+            if AOF_REWRITING.swap(true, Ordering::SeqCst) {
+                return Reply::error("ERR Background append only file rewriting already in progress");
+            }
+            let store_clone = Arc::clone(store);
+            let filename = config().appendfilename.clone();
+            std::thread::spawn(move || {
+                match rewrite_aof(&store_clone, &filename) {
+                    Ok(()) => println!("Background AOF rewrite finished successfully"),
+                    Err(e) => eprintln!("Background AOF rewrite failed: {}", e),
+                }
+                AOF_REWRITING.store(false, Ordering::SeqCst);
+            });
+            Reply::Simple("Background append only file rewriting started".to_string())
+        }
+        
+        // ========== OTHER COMMANDS ==========
+        "EXPIRE" => {
+            if parts.len() != 3 {
+                return Reply::error("ERR usage: EXPIRE key seconds");
+            }
+            let seconds = match parse_num::<u64>(&parts[2]) {
+                Some(s) => s,
+                None => return Reply::Integer(0),
+            };
+            let Some(expires_at) = Instant::now().checked_add(Duration::from_secs(seconds)) else {
+                return Reply::error("ERR invalid expire time in 'expire' command");
+            };
+            
+            match db.peek_mut(&parts[1]) {
+                Some(entry) => {
+                    entry.expires_at = Some(expires_at);
+                    notify_keyspace_event(session.db, NOTIFY_GENERIC, "expire", &parts[1]);
+                    Reply::Integer(1)
+                }
+                None => Reply::Integer(0),
+            }
+        }
+        
+        "EXPIREAT" | "PEXPIREAT" => {
+            if parts.len() != 3 {
+                return Reply::error(format!("ERR usage: {} key timestamp", cmd));
+            }
+            let timestamp = match parse_num::<i64>(&parts[2]) {
+                Some(t) => t,
+                None => return Reply::error("ERR value is not an integer or out of range"),
+            };
+            let target_millis = if cmd == "EXPIREAT" { timestamp.saturating_mul(1000) } else { timestamp };
+            let now_millis = unix_time().as_millis() as i64;
+            
+            match db.peek_mut(&parts[1]) {
+                Some(entry) if !is_expired(entry) => {
+                    if target_millis <= now_millis {
+                        // Already in the past: the key expires right away
+                        db.remove(&parts[1]);
+                        notify_keyspace_event(session.db, NOTIFY_GENERIC, "del", &parts[1]);
+                    } else {
+                        let remaining = Duration::from_millis((target_millis - now_millis) as u64);
+                        entry.expires_at = Some(Instant::now() + remaining);
+                        notify_keyspace_event(session.db, NOTIFY_GENERIC, "expire", &parts[1]);
+                    }
+                    Reply::Integer(1)
+                }
+                _ => Reply::Integer(0),
+            }
+        }
+        
+        "EXPIRETIME" | "PEXPIRETIME" => {
+            if parts.len() != 2 {
+                return Reply::error(format!("ERR usage: {} key", cmd));
+            }
+            match db.peek(&parts[1]) {
+                Some(entry) if !is_expired(entry) => match entry.expires_at {
+                    Some(exp) => {
+                        // Anchor the Instant against the wall clock at call time,
+                        // rounding to the nearest millisecond to absorb the drift
+                        let at = unix_time() + exp.duration_since(Instant::now());
+                        let millis = (at.as_micros() + 500) / 1000;
+                        if cmd == "EXPIRETIME" {
+                            Reply::Integer((millis / 1000) as i64)
+                        } else {
+                            Reply::Integer(millis as i64)
+                        }
+                    }
+                    None => Reply::Integer(-1),
+                },
+                _ => Reply::Integer(-2),
+            }
+        }
+        
+        "TTL" => {
+            if parts.len() != 2 {
+                return Reply::error("ERR usage: TTL key");
+            }
+            match db.peek(&parts[1]) {
+                Some(entry) => match entry.expires_at {
+                    Some(exp) => {
+                        let remaining = exp.duration_since(Instant::now()).as_secs();
+                        Reply::Integer(remaining as i64)
+                    }
+                    None => Reply::Integer(-1),
+                },
+                None => Reply::Integer(-2),
+            }
+        }
+        
+        "DEL" | "UNLINK" => {
+            if parts.len() < 2 {
+                return Reply::error(format!("ERR usage: {} key [key ...]", cmd));
+            }
+            let mut removed = Vec::new();
+            for key in &parts[1..] {
+                if let Some(entry) = db.remove(key).filter(|entry| !is_expired(entry)) {
+                    notify_keyspace_event(session.db, NOTIFY_GENERIC, "del", key);
+                    removed.push(entry);
+                }
+            }
+            let count = removed.len() as i64;
+            if cmd == "UNLINK" {
+                // The keys are already gone; freeing big collections can happen later
+                std::thread::spawn(move || drop(removed));
+            }
+            Reply::Integer(count)
+        }
+        
+        "TOUCH" => {
+            if parts.len() < 2 {
+                return Reply::error("ERR usage: TOUCH key [key ...]");
+            }
+            let touched = parts[1..]
+                .iter()
+                .filter(|key| db.get(key).is_some_and(|entry| !is_expired(entry)))
+                .count();
+            Reply::Integer(touched as i64)
+        }
+        
+        "COPY" => {
             if parts.len() < 3 {
-                return "-ERR usage: SET key value [EX seconds]\r\n".to_string();
+                return Reply::error("ERR usage: COPY source destination [DB destination-db] [REPLACE]");
             }
-            let key = parts[1].to_string();
-            let value = Value::String(parts[2].to_string());
-            let expires_at = if parts.len() >= 5 && parts[3].to_uppercase() == "EX" {
-                match parts[4].parse::<u64>() {
-                    Ok(secs) => Some(Instant::now() + Duration::from_secs(secs)),
-                    Err(_) => return "-ERR invalid expire time\r\n".to_string(),
+            let mut dest_db = session.db;
+            let mut replace = false;
+            let mut i = 3;
+            while i < parts.len() {
+                match upper(&parts[i]).as_str() {
+                    "REPLACE" => replace = true,
+                    "DB" if i + 1 < parts.len() => {
+                        dest_db = match parse_num::<usize>(&parts[i + 1]) {
+                            Some(index) if index < DATABASES => index,
+                            Some(_) => return Reply::error("ERR DB index is out of range"),
+                            None => return Reply::error("ERR value is not an integer or out of range"),
+                        };
+                        i += 1;
+                    }
+                    _ => return Reply::error("ERR syntax error"),
                 }
-            } else {
-                None
-            };
+                i += 1;
+            }
+            if dest_db == session.db && parts[1] == parts[2] {
+                return Reply::error("ERR source and destination objects are the same");
+            }
             
-            db.insert(key, Entry { value, expires_at });
-            "+OK\r\n".to_string()
+            // Value is a plain owned tree, so cloning it is a deep copy
+            let copy = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => Entry::new(entry.value.clone(), entry.expires_at),
+                _ => return Reply::Integer(0),
+            };
+            let dest = &mut shards.db(dest_db);
+            purge_expired(dest, &parts[2]);
+            if dest.contains_key(&parts[2]) && !replace {
+                return Reply::Integer(0);
+            }
+            if matches!(copy.value, Value::List(_)) {
+                store.notify_list_pushed();
+            }
+            dest.insert(parts[2].clone(), copy);
+            notify_keyspace_event(dest_db, NOTIFY_GENERIC, "copy_to", &parts[2]);
+            Reply::Integer(1)
         }
         
-        "GET" => {
-            if parts.len() != 2 {
-                return "-ERR usage: GET key\r\n".to_string();
+        "RENAME" | "RENAMENX" => {
+            if parts.len() != 3 {
+                return Reply::error(format!("ERR usage: {} key newkey", cmd));
             }
-            match db.get(parts[1]) {
-                Some(entry) if !is_expired(entry) => {
-                    match &entry.value {
-                        Value::String(s) => format!("${}\r\n{}\r\n", s.len(), s),
-                        Value::List(_) => "-ERR Operation against a key holding the wrong kind of value\r\n".to_string(),
+            purge_expired(db, &parts[1]);
+            purge_expired(db, &parts[2]);
+            if !db.contains_key(&parts[1]) {
+                return Reply::error("ERR no such key");
+            }
+            let renamed = if cmd == "RENAMENX" && db.contains_key(&parts[2]) {
+                false
+            } else {
+                // The whole entry moves, so its expires_at goes with it
+                if let Some(entry) = db.remove(&parts[1]) {
+                    if matches!(entry.value, Value::List(_)) {
+                        store.notify_list_pushed();
                     }
+                    db.insert(parts[2].clone(), entry);
+                    notify_keyspace_event(session.db, NOTIFY_GENERIC, "rename_from", &parts[1]);
+                    notify_keyspace_event(session.db, NOTIFY_GENERIC, "rename_to", &parts[2]);
                 }
-                _ => "$-1\r\n".to_string(),
+                true
+            };
+            if cmd == "RENAME" {
+                Reply::ok()
+            } else {
+                Reply::Integer(if renamed { 1 } else { 0 })
             }
         }
         
-        // ========== LIST COMMANDS ==========
-        "LPUSH" => {
-            if parts.len() < 3 {
-                return "-ERR usage: LPUSH key value [value ...]\r\n".to_string();
+        "DUMP" => {
+            if parts.len() != 2 {
+                return Reply::error("ERR usage: DUMP key");
             }
-            let key = parts[1];
-            
-            let entry = db.entry(key.to_string()).or_insert_with(|| Entry {
-                value: Value::List(Vec::new()),
-                expires_at: None,
-            });
-            
-            match &mut entry.value {
-                Value::List(list) => {
-                    for value in parts[2..].iter().rev() {
-                        list.insert(0, value.to_string());
-                    }
-                    format!(":{}\r\n", list.len())
-                }
-                _ => "-ERR Operation against a key holding the wrong kind of value\r\n".to_string(),
+            match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => Reply::Bulk(encode_dump(&entry.value)),
+                _ => Reply::Nil,
             }
         }
         
-        "RPUSH" => {
-            if parts.len() < 3 {
-                return "-ERR usage: RPUSH key value [value ...]\r\n".to_string();
+        "RESTORE" => {
+            if parts.len() < 4 {
+                return Reply::error("ERR usage: RESTORE key ttl serialized-value [REPLACE]");
             }
-            let key = parts[1];
-            
-            let entry = db.entry(key.to_string()).or_insert_with(|| Entry {
-                value: Value::List(Vec::new()),
-                expires_at: None,
-            });
-            
-            match &mut entry.value {
-                Value::List(list) => {
-                    for value in &parts[2..] {
-                        list.push(value.to_string());
-                    }
-                    format!(":{}\r\n", list.len())
+            let mut replace = false;
+            for option in &parts[4..] {
+                match upper(option).as_str() {
+                    "REPLACE" => replace = true,
+                    _ => return Reply::error("ERR syntax error"),
                 }
-                _ => "-ERR Operation against a key holding the wrong kind of value\r\n".to_string(),
             }
+            // A TTL of 0 restores the key without one
+            let ttl = match parse_num::<i64>(&parts[2]) {
+                Some(ttl) if ttl >= 0 => ttl as u64,
+                Some(_) => return Reply::error("ERR Invalid TTL value, must be >= 0"),
+                None => return Reply::error("ERR value is not an integer or out of range"),
+            };
+            let value = match decode_dump(&parts[3]) {
+                Ok(value) => value,
+                Err(e) => return Reply::error(e),
+            };
+            purge_expired(db, &parts[1]);
+            if db.contains_key(&parts[1]) && !replace {
+                return Reply::error("BUSYKEY Target key name already exists.");
+            }
+            if matches!(value, Value::List(_)) {
+                store.notify_list_pushed();
+            }
+            let expires_at = (ttl > 0).then(|| Instant::now() + Duration::from_millis(ttl));
+            db.insert(parts[1].clone(), Entry::new(value, expires_at));
+            notify_keyspace_event(session.db, NOTIFY_GENERIC, "restore", &parts[1]);
+            Reply::ok()
+        }
+        
+        "KEYS" => {
+            if parts.len() > 2 {
+                return Reply::error("ERR usage: KEYS [pattern]");
+            }
+            // Without a pattern every key matches, as before
+            let pattern: &[u8] = parts.get(1).map(|p| p.as_slice()).unwrap_or(b"*");
+            let now = Instant::now();
+            let keys: Vec<Reply> = db
+                .iter()
+                .filter(|(_, entry)| entry.expires_at.map(|exp| exp > now).unwrap_or(true))
+                .filter(|(key, _)| glob_match(pattern, key))
+                .map(|(key, _)| Reply::bulk(key))
+                .collect();
+            Reply::Array(keys)
+        }
+        
+        "SCAN" => {
+            if parts.len() < 2 {
+                return Reply::error("ERR usage: SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]");
+            }
+            let args = match ScanArgs::parse(&parts[1..], true) {
+                Ok(args) => args,
+                Err(reply) => return reply,
+            };
+            let now = Instant::now();
+            let (next, keys) = args.page(db.iter(), db.len(), |(key, entry)| {
+                entry.expires_at.is_none_or(|exp| exp > now)
+                    && args.matches(key)
+                    && args.kind.as_ref().is_none_or(|t| t == entry.value.type_name())
+            });
+            let keys = keys.into_iter().map(|(key, _)| Reply::bulk(key)).collect();
+            Reply::Array(vec![Reply::Bulk(next.to_string().into_bytes()), Reply::Array(keys)])
         }
         
-        "LPOP" => {
+        "TYPE" => {
             if parts.len() != 2 {
-                return "-ERR usage: LPOP key\r\n".to_string();
+                return Reply::error("ERR usage: TYPE key");
             }
-            match db.get_mut(parts[1]) {
-                Some(ref mut entry) if !is_expired(entry) => {
-                    match &mut entry.value {
-                        Value::List(list) => {
-                            if list.is_empty() {
-                                "$-1\r\n".to_string()
-                            } else {
-                                let val = list.remove(0);
-                                let response = format!("${}\r\n{}\r\n", val.len(), val);
-                                if list.is_empty() {
-                                    db.remove(parts[1]);
-                                }
-                                response
-                            }
+            match db.peek(&parts[1]) {
+                Some(entry) if !is_expired(entry) => Reply::Simple(entry.value.type_name().to_string()),
+                _ => Reply::Simple("none".to_string()),
+            }
+        }
+        
+        "SORT" => {
+            let usage = "ERR usage: SORT key [LIMIT offset count] [ASC|DESC] [ALPHA]";
+            if parts.len() < 2 {
+                return Reply::error(usage);
+            }
+            let mut limit = None;
+            let mut desc = false;
+            let mut alpha = false;
+            let mut i = 2;
+            while i < parts.len() {
+                match upper(&parts[i]).as_str() {
+                    "ASC" => desc = false,
+                    "DESC" => desc = true,
+                    "ALPHA" => alpha = true,
+                    "LIMIT" if i + 2 < parts.len() => {
+                        match (parse_num::<i64>(&parts[i + 1]), parse_num::<i64>(&parts[i + 2])) {
+                            (Some(offset), Some(count)) => limit = Some((offset, count)),
+                            _ => return Reply::error("ERR value is not an integer or out of range"),
                         }
-                        _ => "-ERR Operation against a key holding the wrong kind of value\r\n".to_string(),
+                        i += 2;
+                    }
+                    _ => return Reply::error("ERR syntax error"),
+                }
+                i += 1;
+            }
+            
+            let mut items: Vec<&Vec<u8>> = match db.get(&parts[1]) {
+                Some(entry) if !is_expired(entry) => match &entry.value {
+                    Value::List(list) => list.iter().collect(),
+                    Value::Set(set) => set.iter().collect(),
+                    Value::ZSet(zset) => zset.iter().map(|(member, _)| member).collect(),
+                    _ => return Reply::error(WRONG_TYPE),
+                },
+                _ => Vec::new(),
+            };
+            if alpha {
+                items.sort();
+            } else {
+                let mut scored = Vec::with_capacity(items.len());
+                for item in items {
+                    match parse_num::<f64>(item).filter(|score| !score.is_nan()) {
+                        Some(score) => scored.push((score, item)),
+                        None => return Reply::error("ERR One or more scores can't be converted into double"),
                     }
                 }
-                _ => "$-1\r\n".to_string(),
+                // Equal scores fall back to comparing the bytes, as in Redis
+                scored.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(b.1)));
+                items = scored.into_iter().map(|(_, item)| item).collect();
+            }
+            if desc {
+                items.reverse();
+            }
+            
+            // A negative count means everything after the offset
+            let (offset, count) = limit.unwrap_or((0, -1));
+            let offset = offset.max(0) as usize;
+            let count = if count < 0 { usize::MAX } else { count as usize };
+            Reply::Array(items.into_iter().skip(offset).take(count).map(|item| Reply::bulk(item)).collect())
+        }
+        
+        "DBSIZE" => {
+            // Expired keys the cleanup thread hasn't reached yet don't count
+            let live = db.values().filter(|entry| !is_expired(entry)).count();
+            Reply::Integer(live as i64)
+        }
+        
+        "RANDOMKEY" => {
+            // Pick among live keys only, so an expired key is never handed out
+            let live = db.values().filter(|entry| !is_expired(entry)).count();
+            if live == 0 {
+                return Reply::Nil;
+            }
+            let index = rng().below(live);
+            match db.iter().filter(|(_, entry)| !is_expired(entry)).nth(index) {
+                Some((key, _)) => Reply::bulk(key),
+                None => Reply::Nil,
+            }
+        }
+        
+        "FLUSHDB" | "FLUSHALL" => {
+            let asynchronous = match parts.get(1).map(|arg| upper(arg)) {
+                None => false,
+                Some(mode) if parts.len() == 2 && mode == "SYNC" => false,
+                Some(mode) if parts.len() == 2 && mode == "ASYNC" => true,
+                _ => return Reply::error(format!("ERR usage: {} [ASYNC|SYNC]", cmd)),
+            };
+            // The maps are swapped out while the lock is held, so the cleanup
+            // thread only ever sees either the old contents or the empty maps
+            let flushed: Vec<DbShard> = if cmd == "FLUSHDB" {
+                db.take()
+            } else {
+                shards.take_all()
+            };
+            if asynchronous {
+                // Freeing a big keyspace takes a while; do it off the client's thread
+                std::thread::spawn(move || drop(flushed));
             }
+            Reply::ok()
         }
         
-        "RPOP" => {
+        "SELECT" => {
             if parts.len() != 2 {
-                return "-ERR usage: RPOP key\r\n".to_string();
+                return Reply::error("ERR usage: SELECT index");
             }
-            match db.get_mut(parts[1]) {
-                Some(ref mut entry) if !is_expired(entry) => {
-                    match &mut entry.value {
-                        Value::List(list) => {
-                            if let Some(val) = list.pop() {
-                                let response = format!("${}\r\n{}\r\n", val.len(), val);
-                                if list.is_empty() {
-                                    db.remove(parts[1]);
-                                }
-                                response
-                            } else {
-                                "$-1\r\n".to_string()
-                            }
-                        }
-                        _ => "-ERR Operation against a key holding the wrong kind of value\r\n".to_string(),
-                    }
+            match parse_num::<usize>(&parts[1]) {
+                Some(index) if index < DATABASES => {
+                    session.db = index;
+                    Reply::ok()
                 }
-                _ => "$-1\r\n".to_string(),
+                Some(_) => Reply::error("ERR DB index is out of range"),
+                None => Reply::error("ERR value is not an integer or out of range"),
             }
         }
         
-        "LLEN" => {
-            if parts.len() != 2 {
-                return "-ERR usage: LLEN key\r\n".to_string();
+        "SWAPDB" => {
+            if parts.len() != 3 {
+                return Reply::error("ERR usage: SWAPDB index1 index2");
             }
-            match db.get(parts[1]) {
-                Some(entry) if !is_expired(entry) => {
-                    match &entry.value {
-                        Value::List(list) => format!(":{}\r\n", list.len()),
-                        _ => "-ERR Operation against a key holding the wrong kind of value\r\n".to_string(),
-                    }
+            match (parse_num::<usize>(&parts[1]), parse_num::<usize>(&parts[2])) {
+                (Some(a), Some(b)) if a < DATABASES && b < DATABASES => {
+                    shards.swap(a, b);
+                    mark_dirty(1);
+                    // Clients blocked on either database may now find their list
+                    store.notify_list_pushed();
+                    Reply::ok()
                 }
-                _ => ":0\r\n".to_string(),
+                (Some(_), Some(_)) => Reply::error("ERR DB index is out of range"),
+                _ => Reply::error("ERR invalid first DB index"),
             }
         }
         
-        "LRANGE" => {
-            if parts.len() != 4 {
-                return "-ERR usage: LRANGE key start stop\r\n".to_string();
+        "INFO" => {
+            if parts.len() > 2 {
+                return Reply::error("ERR usage: INFO [section]");
             }
-            let start: i64 = parts[2].parse().unwrap_or(0);
-            let stop: i64 = parts[3].parse().unwrap_or(-1);
-            
-            match db.get(parts[1]) {
-                Some(entry) if !is_expired(entry) => {
-                    match &entry.value {
-                        Value::List(list) => {
-                            let len = list.len() as i64;
-                            let actual_start = if start < 0 { len + start } else { start }.max(0) as usize;
-                            let actual_stop = if stop < 0 { len + stop } else { stop }.min(len - 1) as usize;
-                            
-                            let mut response = format!("*{}\r\n", if actual_start <= actual_stop { actual_stop - actual_start + 1 } else { 0 });
-                            
-                            for i in actual_start..=actual_stop.min(list.len().saturating_sub(1)) {
-                                if i < list.len() {
-                                    let val = &list[i];
-                                    response.push_str(&format!("${}\r\n{}\r\n", val.len(), val));
-                                }
-                            }
-                            response
-                        }
-                        _ => "-ERR Operation against a key holding the wrong kind of value\r\n".to_string(),
+            let section = parts.get(1).map(|s| String::from_utf8_lossy(s).to_lowercase());
+            Reply::Verbatim(info(store, shards, section.as_deref().unwrap_or("default")))
+        }
+        
+        // Redis draws art here; all this prints is which build is running,
+        // one "name: value" line after the version line, for scripts
+        // checking they reached the server they expected
+        "LOLWUT" => {
+            match &parts[1..] {
+                [] => {}
+                [option, version] if upper(option) == "VERSION" => {
+                    if parse_num::<u64>(version).is_none() {
+                        return Reply::error("ERR value is not an integer or out of range");
                     }
                 }
-                _ => "*0\r\n".to_string(),
+                _ => return Reply::error("ERR usage: LOLWUT [VERSION version]"),
             }
+            Reply::Verbatim(format!(
+                "RedRust ver. {}\nbuild: {}\ntarget: {}-{}\n",
+                env!("CARGO_PKG_VERSION"),
+                if cfg!(debug_assertions) { "debug" } else { "release" },
+                std::env::consts::ARCH,
+                std::env::consts::OS,
+            ))
         }
         
-        // ========== PERSISTENCE COMMANDS ==========
-        "SAVE" => {
-            drop(db); // Release lock before saving
-            match save_data(store, "redrust.rdb") {
-                Ok(()) => "+OK\r\n".to_string(),
-                Err(e) => format!("-ERR {}\r\n", e),
+        "MEMORY" => {
+            let usage = "ERR usage: MEMORY USAGE key [SAMPLES count]";
+            if parts.len() < 3 || upper(&parts[1]) != "USAGE" {
+                return Reply::error(usage);
+            }
+            // Like Redis, collections are measured from 5 elements by default
+            let samples = match &parts[3..] {
+                [] => 5,
+                [option, count] if upper(option) == "SAMPLES" => match parse_num::<usize>(count) {
+                    Some(count) => count,
+                    None => return Reply::error("ERR value is not an integer or out of range"),
+                },
+                _ => return Reply::error(usage),
+            };
+            match db.peek(&parts[2]) {
+                Some(entry) if !is_expired(entry) => Reply::Integer((parts[2].len() + sampled_size(entry, samples)) as i64),
+                _ => Reply::Nil,
             }
         }
         
-        "BGSAVE" => {
-            let store_clone = Arc::clone(store);
-            std::thread::spawn(move || {
-                match save_data(&store_clone, "redrust.rdb") {
-                    Ok(()) => println!("Background save completed"),
-                    Err(e) => eprintln!("Background save failed: {}", e),
+        "OBJECT" => {
+            let usage = "ERR usage: OBJECT ENCODING|IDLETIME|REFCOUNT key";
+            if parts.len() != 3 {
+                return Reply::error(usage);
+            }
+            let Some(entry) = db.peek(&parts[2]).filter(|entry| !is_expired(entry)) else {
+                return Reply::error("ERR no such key");
+            };
+            match upper(&parts[1]).as_str() {
+                "ENCODING" => Reply::bulk(entry.value.encoding().as_bytes()),
+                "IDLETIME" => Reply::Integer(idle_seconds(entry) as i64),
+                "REFCOUNT" => Reply::Integer(entry.value.refcount()),
+                _ => Reply::error(usage),
+            }
+        }
+        
+        "DEBUG" => {
+            let usage = "ERR usage: DEBUG SET-ACTIVE-EXPIRE 0|1 | DEBUG SLEEP seconds | DEBUG OBJECT key | DEBUG LISTPACK-ENTRIES key | DEBUG RELOAD";
+            match (parts.get(1).map(|s| upper(s)).as_deref(), parts.len()) {
+                // Laid out like Redis' line, with the length of the value in
+                // a snapshot and, for collections, their element count
+                (Some("OBJECT"), 3) => {
+                    let Some(entry) = db.peek(&parts[2]).filter(|entry| !is_expired(entry)) else {
+                        return Reply::error("ERR no such key");
+                    };
+                    let mut serialized = Vec::new();
+                    put_value(&mut serialized, &entry.value);
+                    let mut line = format!(
+                        "Value at:{:p} refcount:{} encoding:{} serializedlength:{} lru_seconds_idle:{}",
+                        entry,
+                        entry.value.refcount(),
+                        entry.value.encoding(),
+                        serialized.len(),
+                        idle_seconds(entry),
+                    );
+                    if let Some(elements) = entry.value.elements() {
+                        line.push_str(&format!(" length:{}", elements));
+                    }
+                    Reply::Simple(line)
                 }
-            });
-            "+Background saving started\r\n".to_string()
+                // A list's encoding and length together, so a test can see
+                // the switch to quicklist happen at the configured size
+                (Some("LISTPACK-ENTRIES"), 3) => match db.peek(&parts[2]).filter(|entry| !is_expired(entry)) {
+                    Some(Entry { value: value @ Value::List(items), .. }) => {
+                        Reply::Array(vec![Reply::bulk(value.encoding().as_bytes()), Reply::Integer(items.len() as i64)])
+                    }
+                    Some(_) => Reply::error(WRONG_TYPE),
+                    None => Reply::error("ERR no such key"),
+                },
+                // SAVE, then the startup load, all under every shard's lock so
+                // other clients see the old dataset or the reloaded one
+                (Some("RELOAD"), 2) => {
+                    let writes = WRITES.get();
+                    let filename = config().dbfilename.clone();
+                    if let Err(e) = write_snapshot(&encode_snapshot(shards), &filename) {
+                        return Reply::error(format!("ERR {}", e));
+                    }
+                    let loaded = match std::fs::read(&filename).map_err(|e| e.to_string()).and_then(|data| decode_snapshot(&data)) {
+                        Ok(loaded) => loaded,
+                        Err(e) => return Reply::error(format!("ERR Error trying to load the RDB dump: {}", e)),
+                    };
+                    drop(shards.take_all());
+                    install_dataset(shards, loaded);
+                    // The dataset now matches the file, and there is nothing
+                    // for the AOF or replicas to replay
+                    WRITES.set(writes);
+                    clear_dirty(DIRTY.load(Ordering::Relaxed));
+                    Reply::ok()
+                }
+                (Some("SET-ACTIVE-EXPIRE"), 3) => match parts[2].as_slice() {
+                    b"0" | b"1" => {
+                        ACTIVE_EXPIRE.store(parts[2] == b"1", Ordering::Relaxed);
+                        Reply::ok()
+                    }
+                    _ => Reply::error(usage),
+                },
+                // Only reached inside EXEC (see process_command), where
+                // the whole transaction waits with its locks held, as in Redis
+                (Some("SLEEP"), 3) => match parse_num::<f64>(&parts[2]).and_then(|s| Duration::try_from_secs_f64(s).ok()) {
+                    Some(duration) => {
+                        std::thread::sleep(duration);
+                        Reply::ok()
+                    }
+                    None => Reply::error("ERR value is not a valid float or out of range"),
+                },
+                _ => Reply::error(usage),
+            }
         }
         
-        "LASTSAVE" => {
-            let timestamp = std::fs::metadata("redrust.rdb")
-                .ok()
-                .and_then(|m| m.modified().ok())
-                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
-                .map(|d| d.as_secs() as i64)
-                .unwrap_or(-1);
-            format!(":{}\r\n", timestamp)
+        // Inside MULTI, WAIT reports without blocking; outside,
+        // wait_for_replicas handles it
+        "WAIT" => {
+            if parts.len() != 3 {
+                return Reply::error("ERR usage: WAIT numreplicas timeout");
+            }
+            let replication = store.replication.lock().unwrap();
+            Reply::Integer(replication.acknowledged(replication.offset) as i64)
         }
         
-        // ========== OTHER COMMANDS ==========
-        "EXPIRE" => {
+        "REPLICAOF" | "SLAVEOF" => {
             if parts.len() != 3 {
-                return "-ERR usage: EXPIRE key seconds\r\n".to_string();
+                return Reply::error(format!("ERR usage: {} host port | {} NO ONE", cmd, cmd));
+            }
+            let mut replication = store.replication.lock().unwrap();
+            if upper(&parts[1]) == "NO" && upper(&parts[2]) == "ONE" {
+                if let Some(master) = replication.master.take() {
+                    master.task.abort();
+                    println!("MASTER MODE enabled");
+                }
+                return Reply::ok();
             }
-            let seconds = match parts[2].parse::<u64>() {
-                Ok(s) => s,
-                Err(_) => return ":0\r\n".to_string(),
+            let Some(port) = parse_num::<u16>(&parts[2]) else {
+                return Reply::error("ERR Invalid master port");
             };
-            
-            match db.get_mut(parts[1]) {
-                Some(entry) => {
-                    entry.expires_at = Some(Instant::now() + Duration::from_secs(seconds));
-                    ":1\r\n".to_string()
+            let host = String::from_utf8_lossy(&parts[1]).into_owned();
+            if let Some(master) = &replication.master {
+                if master.host == host && master.port == port {
+                    return Reply::Simple("OK Already connected to specified master".to_string());
                 }
-                None => ":0\r\n".to_string(),
+                master.task.abort();
             }
+            println!("REPLICAOF {}:{} enabled", host, port);
+            let link_up = Arc::new(AtomicBool::new(false));
+            let task = tokio::spawn(replicate(Arc::clone(store), host.clone(), port, Arc::clone(&link_up)));
+            replication.master = Some(MasterLink { host, port, link_up, task: task.abort_handle() });
+            Reply::ok()
         }
         
-        "TTL" => {
-            if parts.len() != 2 {
-                return "-ERR usage: TTL key\r\n".to_string();
+        // A replica asking for the dataset. It gets a snapshot, then the
+        // stream of writes from this point on; every shard is locked, so no
+        // write can fall between the two.
+        "SYNC" | "PSYNC" => {
+            let snapshot = encode_snapshot(shards);
+            let mut replication = store.replication.lock().unwrap();
+            let mut out = match cmd {
+                "PSYNC" => format!("+FULLRESYNC {} {}\r\n", replication.replid, replication.offset).into_bytes(),
+                _ => Vec::new(),
+            };
+            out.extend(format!("${}\r\n", snapshot.len()).as_bytes());
+            out.extend(snapshot);
+            let _ = session.outbox.send(out);
+            replication.db = None;
+            let link = ReplicaLink { outbox: session.outbox.clone(), acked: replication.offset };
+            replication.replicas.insert(session.id, link);
+            Reply::Sent
+        }
+        
+        // Sent by replicas. ACKs report how far they got and get no reply;
+        // anything else (listening-port, capa) is accepted and ignored.
+        "REPLCONF" => {
+            if parts.len() == 3 && upper(&parts[1]) == "ACK" {
+                let offset = parse_num::<u64>(&parts[2]).unwrap_or(0);
+                if let Some(replica) = store.replication.lock().unwrap().replicas.get_mut(&session.id) {
+                    replica.acked = offset;
+                }
+                store.replica_acks.send_modify(|acks| *acks += 1);
+                return Reply::Sent;
             }
-            match db.get(parts[1]) {
-                Some(entry) => match entry.expires_at {
-                    Some(exp) => {
-                        let remaining = exp.duration_since(Instant::now()).as_secs();
-                        format!(":{}\r\n", remaining)
-                    }
-                    None => ":-1\r\n".to_string(),
+            Reply::ok()
+        }
+        
+        "MONITOR" => {
+            let monitor = Subscriber { outbox: session.outbox.clone(), protocol: session.protocol };
+            store.monitors.lock().unwrap().insert(session.id, monitor);
+            session.monitoring = true;
+            Reply::ok()
+        }
+        
+        "SLOWLOG" => {
+            let usage = "ERR usage: SLOWLOG GET [count]|LEN|RESET";
+            let mut slowlog = store.slowlog.lock().unwrap();
+            match (parts.get(1).map(|s| upper(s)).as_deref(), parts.len()) {
+                (Some("GET"), 2 | 3) => {
+                    // Ten entries unless asked, and a negative count for all of them
+                    let count = match parts.get(2).map(|count| parse_num::<i64>(count)) {
+                        None => 10,
+                        Some(Some(count)) if count < 0 => usize::MAX,
+                        Some(Some(count)) => count as usize,
+                        Some(None) => return Reply::error("ERR count should be greater than or equal to -1"),
+                    };
+                    let entries = slowlog.entries.iter().take(count).map(|entry| {
+                        Reply::Array(vec![
+                            Reply::Integer(entry.id as i64),
+                            Reply::Integer(entry.time as i64),
+                            Reply::Integer(entry.micros as i64),
+                            Reply::Array(entry.args.iter().map(|arg| Reply::bulk(arg)).collect()),
+                            Reply::bulk(entry.addr.as_bytes()),
+                            Reply::bulk(&entry.name),
+                        ])
+                    });
+                    Reply::Array(entries.collect())
+                }
+                (Some("LEN"), 2) => Reply::Integer(slowlog.entries.len() as i64),
+                (Some("RESET"), 2) => {
+                    slowlog.entries.clear();
+                    Reply::ok()
+                }
+                _ => Reply::error(usage),
+            }
+        }
+        
+        "LATENCY" => {
+            let usage = "ERR usage: LATENCY LATEST|HISTORY event|RESET [event ...]";
+            let mut latency = store.latency.lock().unwrap();
+            match (parts.get(1).map(|s| upper(s)).as_deref(), parts.len()) {
+                // Per event: name, time of the latest sample, its latency and the worst
+                (Some("LATEST"), 2) => Reply::Array(
+                    latency
+                        .events
+                        .iter()
+                        .filter_map(|(name, event)| {
+                            let &(time, ms) = event.samples.back()?;
+                            Some(Reply::Array(vec![
+                                Reply::bulk(name.as_bytes()),
+                                Reply::Integer(time as i64),
+                                Reply::Integer(ms as i64),
+                                Reply::Integer(event.max as i64),
+                            ]))
+                        })
+                        .collect(),
+                ),
+                (Some("HISTORY"), 3) => match latency.events.get(String::from_utf8_lossy(&parts[2]).as_ref()) {
+                    Some(event) => Reply::Array(
+                        event
+                            .samples
+                            .iter()
+                            .map(|&(time, ms)| Reply::Array(vec![Reply::Integer(time as i64), Reply::Integer(ms as i64)]))
+                            .collect(),
+                    ),
+                    None => Reply::Array(Vec::new()),
                 },
-                None => ":-2\r\n".to_string(),
+                // Every event, or just the named ones; replies how many were reset
+                (Some("RESET"), 2) => {
+                    let reset = latency.events.len();
+                    latency.events.clear();
+                    Reply::Integer(reset as i64)
+                }
+                (Some("RESET"), _) => {
+                    let names: HashSet<String> = parts[2..].iter().map(|name| String::from_utf8_lossy(name).into_owned()).collect();
+                    let reset = names.iter().filter(|name| latency.events.remove(name.as_str()).is_some()).count();
+                    Reply::Integer(reset as i64)
+                }
+                _ => Reply::error(usage),
             }
         }
         
-        "DEL" => {
+        "WATCH" => {
+            if parts.len() < 2 {
+                return Reply::error("ERR usage: WATCH key [key ...]");
+            }
+            for key in &parts[1..] {
+                let seen = watch_key(db, key);
+                session.watched.push((session.db, key.clone(), seen));
+            }
+            Reply::ok()
+        }
+        
+        "UNWATCH" => {
+            session.watched.clear();
+            Reply::ok()
+        }
+        
+        "CONFIG" => {
+            let subcommand = parts.get(1).map(|s| upper(s)).unwrap_or_default();
+            match (subcommand.as_str(), parts.len()) {
+                ("GET", 3) => {
+                    let pattern = String::from_utf8_lossy(&parts[2]).to_lowercase();
+                    let config = config();
+                    let mut items = Vec::new();
+                    for name in CONFIG_PARAMETERS {
+                        if glob_match(pattern.as_bytes(), name.as_bytes())
+                            && let Some(value) = config.get(name)
+                        {
+                            items.push((Reply::bulk(name.as_bytes()), Reply::Bulk(value.into_bytes())));
+                        }
+                    }
+                    Reply::Map(items)
+                }
+                ("SET", 4) => {
+                    let name = String::from_utf8_lossy(&parts[2]).to_lowercase();
+                    let value = String::from_utf8_lossy(&parts[3]);
+                    let mut config = CONFIG.get().expect("config is set in main").write().unwrap();
+                    match config.set(&name, &value, false) {
+                        Ok(()) => Reply::ok(),
+                        Err(e) => Reply::error(format!("ERR CONFIG SET failed: {}", e)),
+                    }
+                }
+                _ => Reply::error("ERR usage: CONFIG GET parameter | CONFIG SET parameter value"),
+            }
+        }
+        
+        "AUTH" => {
             if parts.len() != 2 {
-                return "-ERR usage: DEL key\r\n".to_string();
+                return Reply::error("ERR usage: AUTH password");
+            }
+            match &config().requirepass {
+                None => Reply::error("ERR AUTH called without any password configured"),
+                Some(password) if password.as_bytes() == parts[1].as_slice() => {
+                    session.authenticated = true;
+                    Reply::ok()
+                }
+                Some(_) => Reply::error("WRONGPASS invalid password"),
             }
-            let removed = db.remove(parts[1]).is_some();
-            format!(":{}\r\n", if removed { 1 } else { 0 })
         }
         
-        "KEYS" => {
-            let now = Instant::now();
-            let keys: Vec<&String> = db
-                .iter()
-                .filter(|(_, entry)| entry.expires_at.map(|exp| exp > now).unwrap_or(true))
-                .map(|(key, _)| key)
-                .collect();
+        "HELLO" => {
+            let protocol = match parts.get(1).map(|arg| parse_num::<i64>(arg)) {
+                None => session.protocol,
+                Some(Some(version @ (2 | 3))) => version as u8,
+                Some(Some(_)) => return Reply::error("NOPROTO unsupported protocol version"),
+                Some(None) => return Reply::error("ERR Protocol version is not an integer or out of range"),
+            };
+            let mut name = None;
+            let mut i = 2;
+            while i < parts.len() {
+                match upper(&parts[i]).as_str() {
+                    "AUTH" if i + 2 < parts.len() => {
+                        // Only the default user exists, guarded by requirepass
+                        let password = config().requirepass.clone();
+                        match password {
+                            None => return Reply::error("ERR AUTH called without any password configured"),
+                            Some(password) if parts[i + 1] == b"default" && password.as_bytes() == parts[i + 2].as_slice() => {
+                                session.authenticated = true;
+                            }
+                            Some(_) => return Reply::error("WRONGPASS invalid username-password pair or user is disabled."),
+                        }
+                        i += 2;
+                    }
+                    "SETNAME" if i + 1 < parts.len() => {
+                        name = Some(&parts[i + 1]);
+                        i += 1;
+                    }
+                    _ => return Reply::error("ERR syntax error"),
+                }
+                i += 1;
+            }
+            if !session.authenticated {
+                return Reply::error("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time");
+            }
             
-            let mut response = format!("*{}\r\n", keys.len());
-            for key in keys {
-                response.push_str(&format!("${}\r\n{}\r\n", key.len(), key));
+            if let Some(name) = name
+                && let Err(e) = set_client_name(store, session, name)
+            {
+                return e;
             }
-            response
+            session.protocol = protocol;
+            let field = |name: &str| Reply::bulk(name.as_bytes());
+            Reply::Map(vec![
+                (field("server"), field("redrust")),
+                (field("version"), field(env!("CARGO_PKG_VERSION"))),
+                (field("proto"), Reply::Integer(protocol as i64)),
+                (field("id"), Reply::Integer(session.id as i64)),
+                (field("mode"), field("standalone")),
+                (field("role"), field(if store.replication.lock().unwrap().master.is_some() { "replica" } else { "master" })),
+                (field("modules"), Reply::Array(Vec::new())),
+            ])
         }
         
-        "TYPE" => {
-            if parts.len() != 2 {
-                return "-ERR usage: TYPE key\r\n".to_string();
+        "CLIENT" => {
+            let usage = "ERR usage: CLIENT ID|GETNAME|SETNAME name|LIST|KILL ip:port|KILL [ID id] [ADDR ip:port] [SKIPME yes|no]";
+            match (parts.get(1).map(|s| upper(s)).as_deref(), parts.len()) {
+                (Some("ID"), 2) => Reply::Integer(session.id as i64),
+                (Some("GETNAME"), 2) => {
+                    let clients = store.clients.lock().unwrap();
+                    match clients.get(&session.id).filter(|client| !client.name.is_empty()) {
+                        Some(client) => Reply::bulk(&client.name),
+                        None => Reply::Nil,
+                    }
+                }
+                (Some("SETNAME"), 3) => match set_client_name(store, session, &parts[2]) {
+                    Ok(()) => Reply::ok(),
+                    Err(e) => e,
+                },
+                (Some("LIST"), 2) => {
+                    let clients = store.clients.lock().unwrap();
+                    let list: String = clients
+                        .iter()
+                        .map(|(id, client)| {
+                            format!(
+                                "id={} addr={} name={} age={} cmd={}\n",
+                                id,
+                                client.addr,
+                                String::from_utf8_lossy(&client.name),
+                                client.connected_at.elapsed().as_secs(),
+                                client.last_command,
+                            )
+                        })
+                        .collect();
+                    Reply::Verbatim(list)
+                }
+                // The old form names one address and must find it
+                (Some("KILL"), 3) => match kill_clients(store, session, None, Some(&parts[2]), false) {
+                    0 => Reply::error("ERR No such client"),
+                    _ => Reply::ok(),
+                },
+                (Some("KILL"), n) if n > 3 && n.is_multiple_of(2) => {
+                    let mut id = None;
+                    let mut addr = None;
+                    let mut skip_me = true;
+                    for pair in parts[2..].chunks(2) {
+                        match (upper(&pair[0]).as_str(), pair[1].as_slice()) {
+                            ("ID", value) => match parse_num::<u64>(value) {
+                                Some(value) => id = Some(value),
+                                None => return Reply::error("ERR client-id should be greater than 0"),
+                            },
+                            ("ADDR", value) => addr = Some(value),
+                            ("SKIPME", b"yes") => skip_me = true,
+                            ("SKIPME", b"no") => skip_me = false,
+                            _ => return Reply::error("ERR syntax error"),
+                        }
+                    }
+                    Reply::Integer(kill_clients(store, session, id, addr, skip_me) as i64)
+                }
+                _ => Reply::error(usage),
             }
-            match db.get(parts[1]) {
-                Some(entry) if !is_expired(entry) => {
-                    let type_str = match &entry.value {
-                        Value::String(_) => "string",
-                        Value::List(_) => "list",
+        }
+        
+        "SUBSCRIBE" | "PSUBSCRIBE" => {
+            if parts.len() < 2 {
+                let what = if cmd == "SUBSCRIBE" { "channel" } else { "pattern" };
+                return Reply::error(format!("ERR usage: {} {} [{} ...]", cmd, what, what));
+            }
+            subscribe(store, session, &parts[1..], cmd == "PSUBSCRIBE");
+            Reply::Sent
+        }
+        
+        "UNSUBSCRIBE" | "PUNSUBSCRIBE" => {
+            let patterns = cmd == "PUNSUBSCRIBE";
+            let current = if patterns { &session.patterns } else { &session.channels };
+            if parts.len() == 1 && current.is_empty() {
+                let kind = cmd.to_lowercase();
+                return Reply::Array(vec![Reply::bulk(kind.as_bytes()), Reply::Nil, session.subscription_count()]);
+            }
+            unsubscribe(store, session, &parts[1..], patterns, true);
+            Reply::Sent
+        }
+        
+        "PUBLISH" => {
+            if parts.len() != 3 {
+                return Reply::error("ERR usage: PUBLISH channel message");
+            }
+            Reply::Integer(publish(store, &parts[1], &parts[2]) as i64)
+        }
+        
+        // Subscribed connections get PING answered in the pub/sub shape
+        "PING" if session.subscribed() => {
+            let payload = parts.get(1).map(|p| p.as_slice()).unwrap_or(b"");
+            Reply::Array(vec![Reply::bulk(b"pong"), Reply::bulk(payload)])
+        }
+        
+        "PING" => Reply::Simple("PONG".to_string()),
+        
+        "COMMAND" => {
+            let subcommand = parts.get(1).map(|s| upper(s)).unwrap_or_default();
+            match subcommand.as_str() {
+                "" => Reply::Array(COMMANDS.iter().map(|&(name, arity)| command_info(name, arity)).collect()),
+                "COUNT" if parts.len() == 2 => Reply::Integer(COMMANDS.len() as i64),
+                "INFO" => Reply::Array(
+                    parts[2..]
+                        .iter()
+                        .map(|name| {
+                            let name = upper(name);
+                            match command_arity(&name) {
+                                Some(arity) => command_info(&name, arity),
+                                None => Reply::Nil,
+                            }
+                        })
+                        .collect(),
+                ),
+                "DOCS" => {
+                    let names: Vec<String> = if parts.len() > 2 {
+                        parts[2..].iter().map(|name| upper(name)).collect()
+                    } else {
+                        COMMANDS.iter().map(|(name, _)| name.to_string()).collect()
+                    };
+                    let docs = names
+                        .iter()
+                        .filter(|name| command_arity(name).is_some())
+                        .map(|name| {
+                            let doc = vec![(Reply::bulk(b"group"), Reply::bulk(command_group(name).as_bytes()))];
+                            (Reply::bulk(name.to_lowercase().as_bytes()), Reply::Map(doc))
+                        })
+                        .collect();
+                    Reply::Map(docs)
+                }
+                "GETKEYS" if parts.len() > 2 => {
+                    let name = upper(&parts[2]);
+                    let Some(arity) = command_arity(&name) else {
+                        return Reply::error("ERR Invalid command specified");
                     };
-                    format!("+{}\r\n", type_str)
+                    if !arity_allows(arity, parts.len() - 2) {
+                        return Reply::error("ERR Invalid number of arguments specified for command");
+                    }
+                    match command_keys(&name, &parts[2..]) {
+                        Some(keys) if !keys.is_empty() => Reply::Array(keys.into_iter().map(Reply::bulk).collect()),
+                        _ => Reply::error("ERR The command has no key arguments"),
+                    }
                 }
-                _ => "+none\r\n".to_string(),
+                _ => Reply::error("ERR usage: COMMAND [COUNT|INFO name ...|DOCS [name ...]|GETKEYS command arg ...]"),
             }
         }
         
-        "PING" => "+PONG\r\n".to_string(),
-        
-        _ => "-ERR unknown command\r\n".to_string(),
+        _ => Reply::error("ERR unknown command"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"h*llo", b"hllo"));
+        assert!(glob_match(b"h*llo", b"heeeello"));
+        assert!(glob_match(b"user:*:name", b"user:42:name"));
+        assert!(!glob_match(b"user:*:name", b"user:42:email"));
+        assert!(glob_match(b"a**b", b"axxb"));
+    }
+    
+    #[test]
+    fn glob_is_anchored() {
+        assert!(!glob_match(b"a*", b"ba"));
+        assert!(!glob_match(b"*a", b"ab"));
+        assert!(!glob_match(b"abc", b"abcd"));
+        assert!(!glob_match(b"abc", b"xabc"));
+        assert!(!glob_match(b"", b"a"));
+        assert!(glob_match(b"", b""));
+        assert!(glob_match(b"*b", b"abab"));
+        assert!(!glob_match(b"*b*c", b"abab"));
+    }
+    
+    #[test]
+    fn glob_classes() {
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(glob_match(b"h[b-a]llo", b"hallo"));
+        assert!(!glob_match(b"h[a-b]llo", b"hcllo"));
+        assert!(glob_match(b"[a-]", b"-"));
+        // An unclosed class runs to the end of the pattern
+        assert!(glob_match(b"a[bc", b"ac"));
+    }
+    
+    #[test]
+    fn glob_escapes() {
+        assert!(glob_match(b"\\[a\\]", b"[a]"));
+        assert!(!glob_match(b"\\[a\\]", b"a"));
+        assert!(glob_match(b"[\\]]", b"]"));
+        assert!(glob_match(b"[\\^x]", b"^"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+        assert!(glob_match(b"what\\?", b"what?"));
+        assert!(!glob_match(b"what\\?", b"whats"));
+        // A trailing backslash is literal
+        assert!(glob_match(b"a\\", b"a\\"));
+    }
+    
+    #[test]
+    fn glob_many_stars_is_fast() {
+        let started = Instant::now();
+        assert!(!glob_match(b"*a*a*a*a*a*a*a*a*a*a*a*a*b", &[b'a'; 30]));
+        assert!(!glob_match(&b"*a".repeat(200), &[b'a'; 150]));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+    
+    #[test]
+    fn reads_of_one_key_share_its_shard() {
+        const READERS: usize = 8;
+        let store = Keyspace::new();
+        let parts = vec![b"GET".to_vec(), b"key".to_vec()];
+        let held = std::sync::atomic::AtomicUsize::new(0);
+        let all_held = std::thread::scope(|scope| {
+            let readers: Vec<_> = (0..READERS)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let _shards = store.lock(command_keys("GET", &parts).as_deref(), !read_only("GET"));
+                        held.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        // Hold the lock until every reader has it, which only
+                        // happens if they aren't serialized
+                        let deadline = Instant::now() + Duration::from_secs(5);
+                        while held.load(std::sync::atomic::Ordering::SeqCst) < READERS {
+                            if Instant::now() > deadline {
+                                return false;
+                            }
+                            std::thread::yield_now();
+                        }
+                        // A writer to the same shard has to wait meanwhile
+                        store.shards[shard_of(b"key")].try_write().is_err()
+                    })
+                })
+                .collect();
+            readers.into_iter().all(|reader| reader.join().unwrap())
+        });
+        assert!(all_held);
     }
 }
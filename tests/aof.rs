@@ -0,0 +1,25 @@
+mod common;
+
+use common::{bulk, ok, Resp, Server};
+
+#[test]
+fn bitop_is_logged_and_replayed() {
+    let mut server = Server::with_args(&["--appendonly", "yes"]);
+    let mut client = server.client();
+    assert_eq!(client.cmd(&["SET", "src", "abc", "EX", "100"]), ok());
+    assert_eq!(client.cmd(&["SET", "other", "ABC"]), ok());
+    // The operator comes before the keys, and isn't one
+    assert_eq!(client.cmd(&["BITOP", "AND", "and", "src", "other"]), Resp::Integer(3));
+    assert_eq!(client.cmd(&["BITOP", "NOT", "not", "src"]), Resp::Integer(3));
+    assert_eq!(client.cmd(&["GET", "and"]), bulk("ABC"));
+    
+    server.restart();
+    let mut client = server.client();
+    assert_eq!(client.cmd(&["GET", "and"]), bulk("ABC"));
+    assert_eq!(client.cmd(&["GET", "not"]), Resp::Bulk(b"abc".iter().map(|b| !b).collect()));
+    assert_eq!(client.cmd(&["GET", "src"]), bulk("abc"));
+    match client.cmd(&["TTL", "src"]) {
+        Resp::Integer(ttl) => assert!((1..=100).contains(&ttl), "TTL is {}", ttl),
+        other => panic!("TTL replied {:?}", other),
+    }
+}
@@ -0,0 +1,30 @@
+mod common;
+
+use common::{bulk, Resp, Server};
+use std::time::Duration;
+
+#[test]
+fn timeouts_must_fit_a_duration() {
+    let server = Server::start();
+    let mut client = server.client();
+    let error = |message: &str| Resp::Error(message.to_string());
+    
+    for cmd in ["BLPOP", "BRPOP"] {
+        assert_eq!(client.cmd(&[cmd, "list", "1e20"]), error("ERR timeout is out of range"));
+        assert_eq!(client.cmd(&[cmd, "list", "1e300"]), error("ERR timeout is out of range"));
+        assert_eq!(client.cmd(&[cmd, "list", "inf"]), error("ERR timeout is not a float or out of range"));
+        assert_eq!(client.cmd(&[cmd, "list", "-1"]), error("ERR timeout is negative"));
+    }
+    assert_eq!(client.cmd(&["BLPOP", "list", "0.05"]), Resp::Nil);
+}
+
+#[test]
+fn a_huge_timeout_still_wakes_on_push() {
+    let server = Server::start();
+    let mut client = server.client();
+    
+    client.send(&["BLPOP", "list", "1e18"]);
+    std::thread::sleep(Duration::from_millis(100));
+    server.client().cmd(&["RPUSH", "list", "item"]);
+    assert_eq!(client.read(), Resp::Array(vec![bulk("list"), bulk("item")]));
+}
@@ -0,0 +1,157 @@
+// Helpers shared by the integration tests: a server started from the built
+// binary on a free port in a scratch directory, and a blocking RESP client
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+pub struct Server {
+    child: Child,
+    pub port: u16,
+    dir: PathBuf,
+    args: Vec<String>,
+}
+
+impl Server {
+    pub fn start() -> Server {
+        Server::with_args(&[])
+    }
+    
+    pub fn with_args(args: &[&str]) -> Server {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let dir = std::env::temp_dir().join(format!("redrust-test-{}-{}", std::process::id(), port));
+        std::fs::create_dir_all(&dir).unwrap();
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let child = spawn(port, &dir, &args);
+        Server { child, port, dir, args }
+    }
+    
+    // Stop the server and start it again in the same directory, so it loads
+    // whatever it persisted
+    pub fn restart(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        self.child = spawn(self.port, &self.dir, &self.args);
+    }
+    
+    pub fn client(&self) -> Client {
+        Client::connect(self.port)
+    }
+}
+
+fn spawn(port: u16, dir: &PathBuf, args: &[String]) -> Child {
+    let child = Command::new(env!("CARGO_BIN_EXE_redrust"))
+        .args(["--port", &port.to_string(), "--save", ""])
+        .args(args)
+        .current_dir(dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("can't start redrust");
+    let started = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(started.elapsed() < Duration::from_secs(10), "redrust didn't start listening");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    child
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Resp {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Resp>),
+    // RESP3 only
+    Double(String),
+}
+
+pub fn ok() -> Resp {
+    Resp::Simple("OK".to_string())
+}
+
+pub fn bulk(s: &str) -> Resp {
+    Resp::Bulk(s.as_bytes().to_vec())
+}
+
+pub fn encode(args: &[&str]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+pub struct Client {
+    pub stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    pub fn connect(port: u16) -> Client {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        Client { stream, reader }
+    }
+    
+    pub fn send(&mut self, args: &[&str]) {
+        self.stream.write_all(&encode(args)).unwrap();
+    }
+    
+    pub fn cmd(&mut self, args: &[&str]) -> Resp {
+        self.send(args);
+        self.read()
+    }
+    
+    pub fn read(&mut self) -> Resp {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let line = line.trim_end_matches("\r\n");
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Resp::Simple(rest.to_string()),
+            "-" => Resp::Error(rest.to_string()),
+            ":" => Resp::Integer(rest.parse().unwrap()),
+            "$" | "*" if rest == "-1" => Resp::Nil,
+            "$" => {
+                let mut data = vec![0; rest.parse::<usize>().unwrap() + 2];
+                self.reader.read_exact(&mut data).unwrap();
+                data.truncate(data.len() - 2);
+                Resp::Bulk(data)
+            }
+            "*" => Resp::Array((0..rest.parse::<usize>().unwrap()).map(|_| self.read()).collect()),
+            // RESP3, after HELLO 3; maps come back flattened as in RESP2
+            "_" => Resp::Nil,
+            "," => Resp::Double(rest.to_string()),
+            "%" => Resp::Array((0..rest.parse::<usize>().unwrap() * 2).map(|_| self.read()).collect()),
+            _ => panic!("unexpected reply line {:?}", line),
+        }
+    }
+    
+    // Whether a reply arrives within `wait`, without consuming it
+    pub fn reply_pending(&mut self, wait: Duration) -> bool {
+        if !self.reader.buffer().is_empty() {
+            return true;
+        }
+        self.stream.set_read_timeout(Some(wait)).unwrap();
+        let pending = self.reader.fill_buf().map(|data| !data.is_empty()).unwrap_or(false);
+        self.stream.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+        pending
+    }
+}
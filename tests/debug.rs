@@ -0,0 +1,20 @@
+mod common;
+
+use common::{ok, Resp, Server};
+
+#[test]
+fn sleep_rejects_unusable_durations() {
+    let server = Server::start();
+    let mut client = server.client();
+    let error = Resp::Error("ERR value is not a valid float or out of range".to_string());
+    
+    for seconds in ["1e20", "1e300", "inf", "nan", "-1", "soon"] {
+        assert_eq!(client.cmd(&["DEBUG", "SLEEP", seconds]), error, "{}", seconds);
+    }
+    // The same inside a transaction, where the sleep holds the locks
+    client.cmd(&["MULTI"]);
+    client.cmd(&["DEBUG", "SLEEP", "1e20"]);
+    assert_eq!(client.cmd(&["EXEC"]), Resp::Array(vec![error]));
+    
+    assert_eq!(client.cmd(&["DEBUG", "SLEEP", "0.01"]), ok());
+}
@@ -0,0 +1,37 @@
+mod common;
+
+use common::{ok, Client, Resp, Server};
+
+fn add_range(client: &mut Client, key: &str, from: usize, to: usize) {
+    let elements: Vec<String> = (from..to).map(|i| format!("element:{}", i)).collect();
+    for chunk in elements.chunks(1000) {
+        let mut args = vec!["PFADD", key];
+        args.extend(chunk.iter().map(String::as_str));
+        client.cmd(&args);
+    }
+}
+
+fn count(client: &mut Client, keys: &[&str]) -> i64 {
+    let mut args = vec!["PFCOUNT"];
+    args.extend_from_slice(keys);
+    match client.cmd(&args) {
+        Resp::Integer(n) => n,
+        other => panic!("PFCOUNT replied {:?}", other),
+    }
+}
+
+#[test]
+fn merging_disjoint_sets_estimates_their_union() {
+    let server = Server::start();
+    let mut client = server.client();
+    add_range(&mut client, "first", 0, 50_000);
+    add_range(&mut client, "second", 50_000, 100_000);
+    
+    assert_eq!(client.cmd(&["PFMERGE", "union", "first", "second"]), ok());
+    let estimate = count(&mut client, &["union"]);
+    // The standard error is 0.81%; allow about three times that
+    let error = (estimate - 100_000).abs() as f64 / 100_000.0;
+    assert!(error < 0.025, "estimate {} is {:.2}% off", estimate, error * 100.0);
+    // The same as counting over the sources
+    assert_eq!(count(&mut client, &["first", "second"]), estimate);
+}
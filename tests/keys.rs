@@ -0,0 +1,18 @@
+mod common;
+
+use common::{bulk, ok, Resp, Server};
+
+#[test]
+fn expire_rejects_times_too_far_away() {
+    let server = Server::start();
+    let mut client = server.client();
+    let invalid = Resp::Error("ERR invalid expire time in 'expire' command".to_string());
+    assert_eq!(client.cmd(&["SET", "key", "value"]), ok());
+    assert_eq!(client.cmd(&["EXPIRE", "key", "9223372036854775807"]), invalid);
+    assert_eq!(client.cmd(&["EXPIRE", "key", "18446744073709551615"]), invalid);
+    
+    // The key is left as it was, and the server still answers for its shard
+    assert_eq!(client.cmd(&["TTL", "key"]), Resp::Integer(-1));
+    assert_eq!(client.cmd(&["GET", "key"]), bulk("value"));
+    assert_eq!(client.cmd(&["EXPIRE", "key", "100"]), Resp::Integer(1));
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use common::{bulk, encode, ok, Resp, Server};
+use std::io::Write;
+use std::time::Duration;
+
+#[test]
+fn pipelined_replies_come_back_in_order() {
+    let server = Server::start();
+    let mut client = server.client();
+    
+    let sets: Vec<u8> = (0..1000).flat_map(|i| encode(&["SET", &format!("key:{}", i), &format!("value:{}", i)])).collect();
+    client.stream.write_all(&sets).unwrap();
+    for _ in 0..1000 {
+        assert_eq!(client.read(), ok());
+    }
+    
+    let gets: Vec<u8> = (0..1000).flat_map(|i| encode(&["GET", &format!("key:{}", i)])).collect();
+    client.stream.write_all(&gets).unwrap();
+    for i in 0..1000 {
+        assert_eq!(client.read(), bulk(&format!("value:{}", i)));
+    }
+}
+
+#[test]
+fn partial_command_waits_for_the_rest() {
+    let server = Server::start();
+    let mut client = server.client();
+    
+    // Cut inside a bulk string, then inside its trailing \r\n
+    let command = encode(&["SET", "greeting", "hello"]);
+    let (head, tail) = command.split_at(command.len() - 4);
+    client.stream.write_all(head).unwrap();
+    assert!(!client.reply_pending(Duration::from_millis(200)));
+    client.stream.write_all(&tail[..3]).unwrap();
+    assert!(!client.reply_pending(Duration::from_millis(200)));
+    // The rest arrives together with a whole second command
+    client.stream.write_all(&[&tail[3..], &encode(&["GET", "greeting"])[..]].concat()).unwrap();
+    assert_eq!(client.read(), ok());
+    assert_eq!(client.read(), bulk("hello"));
+}
+
+#[test]
+fn partial_inline_command_waits_for_its_newline() {
+    let server = Server::start();
+    let mut client = server.client();
+    
+    client.stream.write_all(b"SET inline").unwrap();
+    assert!(!client.reply_pending(Duration::from_millis(200)));
+    client.stream.write_all(b" 42\r\nGET inline\r\nINCR inline\r\n").unwrap();
+    assert_eq!(client.read(), ok());
+    assert_eq!(client.read(), bulk("42"));
+    assert_eq!(client.read(), Resp::Integer(43));
+}
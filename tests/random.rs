@@ -0,0 +1,29 @@
+mod common;
+
+use common::{Resp, Server};
+
+fn out_of_range() -> Resp {
+    Resp::Error("ERR value is out of range".to_string())
+}
+
+#[test]
+fn extreme_counts_are_rejected() {
+    let server = Server::start();
+    let mut client = server.client();
+    client.cmd(&["SADD", "set", "a", "b"]);
+    client.cmd(&["HSET", "hash", "field", "value"]);
+    client.cmd(&["ZADD", "zset", "1", "member"]);
+    
+    for (cmd, key) in [("SRANDMEMBER", "set"), ("HRANDFIELD", "hash"), ("ZRANDMEMBER", "zset")] {
+        assert_eq!(client.cmd(&[cmd, key, "-9223372036854775808"]), out_of_range(), "{}", cmd);
+        assert_eq!(client.cmd(&[cmd, key, "9223372036854775807"]), out_of_range(), "{}", cmd);
+        match client.cmd(&[cmd, key, "-5"]) {
+            Resp::Array(picks) => assert_eq!(picks.len(), 5, "{}", cmd),
+            other => panic!("{} replied {:?}", cmd, other),
+        }
+    }
+    
+    // The random generator is still usable by everyone
+    assert!(matches!(client.cmd(&["SPOP", "set"]), Resp::Bulk(_)));
+    assert!(matches!(server.client().cmd(&["RANDOMKEY"]), Resp::Bulk(_)));
+}
@@ -0,0 +1,24 @@
+mod common;
+
+use common::{bulk, Resp, Server};
+
+#[test]
+fn float_increments_reply_with_doubles() {
+    let server = Server::start();
+    let mut client = server.client();
+    // Bulk strings over RESP2
+    assert_eq!(client.cmd(&["INCRBYFLOAT", "counter", "1.5"]), bulk("1.5"));
+    assert_eq!(client.cmd(&["HINCRBYFLOAT", "hash", "field", "1.5"]), bulk("1.5"));
+    
+    client.cmd(&["HELLO", "3"]);
+    assert_eq!(client.cmd(&["INCRBYFLOAT", "counter", "1"]), Resp::Double("2.5".to_string()));
+    assert_eq!(client.cmd(&["HINCRBYFLOAT", "hash", "field", "-0.5"]), Resp::Double("1".to_string()));
+    assert_eq!(client.cmd(&["ZADD", "zset", "1.25", "member"]), Resp::Integer(1));
+    assert_eq!(client.cmd(&["ZSCORE", "zset", "member"]), Resp::Double("1.25".to_string()));
+    // Stored as text either way
+    assert_eq!(client.cmd(&["GET", "counter"]), bulk("2.5"));
+    assert_eq!(client.cmd(&["HGET", "hash", "field"]), bulk("1"));
+    // Yes/no answers stay integers, as in Redis
+    assert_eq!(client.cmd(&["HEXISTS", "hash", "field"]), Resp::Integer(1));
+    assert_eq!(client.cmd(&["SISMEMBER", "nothing", "member"]), Resp::Integer(0));
+}
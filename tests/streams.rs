@@ -0,0 +1,18 @@
+mod common;
+
+use common::{bulk, Resp, Server};
+
+#[test]
+fn xadd_refuses_ids_past_the_largest() {
+    let server = Server::start();
+    let mut client = server.client();
+    let exhausted = Resp::Error("ERR The stream has exhausted the last possible ID, unable to add more items".to_string());
+    
+    client.cmd(&["XADD", "stream", "18446744073709551615-18446744073709551615", "f", "v"]);
+    assert_eq!(client.cmd(&["XADD", "stream", "*", "f", "v"]), exhausted);
+    assert_eq!(client.cmd(&["XLEN", "stream"]), Resp::Integer(1));
+    
+    // The largest millisecond still has sequence numbers to give out
+    client.cmd(&["XADD", "other", "18446744073709551615-5", "f", "v"]);
+    assert_eq!(client.cmd(&["XADD", "other", "*", "f", "v"]), bulk("18446744073709551615-6"));
+}
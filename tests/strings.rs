@@ -0,0 +1,47 @@
+mod common;
+
+use common::{Resp, Server};
+
+#[test]
+fn numeric_commands_check_the_type_first() {
+    let server = Server::start();
+    let mut client = server.client();
+    let wrong_type = Resp::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string());
+    client.cmd(&["RPUSH", "list", "1"]);
+    client.cmd(&["HSET", "hash", "field", "1"]);
+    client.cmd(&["SADD", "set", "1"]);
+    client.cmd(&["ZADD", "zset", "1", "member"]);
+    
+    for key in ["list", "hash", "set", "zset"] {
+        assert_eq!(client.cmd(&["INCR", key]), wrong_type, "INCR {}", key);
+        assert_eq!(client.cmd(&["DECR", key]), wrong_type, "DECR {}", key);
+        assert_eq!(client.cmd(&["INCRBY", key, "2"]), wrong_type, "INCRBY {}", key);
+        assert_eq!(client.cmd(&["DECRBY", key, "2"]), wrong_type, "DECRBY {}", key);
+        assert_eq!(client.cmd(&["INCRBYFLOAT", key, "1.5"]), wrong_type, "INCRBYFLOAT {}", key);
+    }
+    client.cmd(&["SET", "string", "1"]);
+    assert_eq!(client.cmd(&["HINCRBY", "string", "field", "1"]), wrong_type);
+    
+    // A string that isn't a number gets the parse error instead
+    client.cmd(&["SET", "word", "abc"]);
+    assert_eq!(client.cmd(&["INCR", "word"]), Resp::Error("ERR value is not an integer or out of range".to_string()));
+}
+
+#[test]
+fn append_keeps_the_expiry() {
+    let server = Server::start();
+    let mut client = server.client();
+    client.cmd(&["SET", "key", "value", "EX", "100"]);
+    assert_eq!(client.cmd(&["APPEND", "key", "-more"]), Resp::Integer(10));
+    match client.cmd(&["TTL", "key"]) {
+        Resp::Integer(ttl) => assert!((1..=100).contains(&ttl), "TTL is {}", ttl),
+        other => panic!("TTL replied {:?}", other),
+    }
+    
+    // And it still counts down to the key expiring
+    client.cmd(&["SET", "short", "value", "EX", "1"]);
+    assert_eq!(client.cmd(&["APPEND", "short", "-more"]), Resp::Integer(10));
+    std::thread::sleep(std::time::Duration::from_millis(1500));
+    assert_eq!(client.cmd(&["GET", "short"]), Resp::Nil);
+    assert_eq!(client.cmd(&["TTL", "short"]), Resp::Integer(-2));
+}
@@ -0,0 +1,102 @@
+mod common;
+
+use common::{bulk, ok, Client, Resp, Server};
+use std::time::Duration;
+
+// Runs `SET out 1` in a transaction watching `key`, with `meanwhile` done
+// between WATCH and MULTI; true if EXEC ran it
+fn watched_exec(client: &mut Client, key: &str, meanwhile: impl FnOnce()) -> bool {
+    assert_eq!(client.cmd(&["WATCH", key]), ok());
+    meanwhile();
+    client.cmd(&["MULTI"]);
+    client.cmd(&["SET", "out", "1"]);
+    match client.cmd(&["EXEC"]) {
+        Resp::Array(replies) => replies == [ok()],
+        Resp::Nil => false,
+        other => panic!("EXEC replied {:?}", other),
+    }
+}
+
+#[test]
+fn any_write_to_a_watched_key_aborts() {
+    let server = Server::start();
+    let (mut client, mut other) = (server.client(), server.client());
+    other.cmd(&["SET", "k", "v"]);
+    
+    assert!(watched_exec(&mut client, "k", || {}));
+    assert!(!watched_exec(&mut client, "k", || {
+        other.cmd(&["SET", "k", "v"]);
+    }));
+    // Changed and changed back still counts
+    assert!(!watched_exec(&mut client, "k", || {
+        other.cmd(&["SET", "k", "changed"]);
+        other.cmd(&["SET", "k", "v"]);
+    }));
+    assert!(!watched_exec(&mut client, "k", || {
+        other.cmd(&["APPEND", "k", ""]);
+    }));
+}
+
+#[test]
+fn a_watched_key_created_and_deleted_aborts() {
+    let server = Server::start();
+    let (mut client, mut other) = (server.client(), server.client());
+    
+    assert!(!watched_exec(&mut client, "k", || {
+        other.cmd(&["SET", "k", "v"]);
+        other.cmd(&["DEL", "k"]);
+    }));
+    assert!(watched_exec(&mut client, "k", || {}));
+}
+
+#[test]
+fn a_watched_key_expiring_aborts() {
+    let server = Server::start();
+    let (mut client, mut other) = (server.client(), server.client());
+    
+    other.cmd(&["SET", "k", "v", "PX", "100"]);
+    assert!(!watched_exec(&mut client, "k", || std::thread::sleep(Duration::from_millis(300))));
+    
+    // Also when nothing deleted it yet
+    other.cmd(&["DEBUG", "SET-ACTIVE-EXPIRE", "0"]);
+    other.cmd(&["SET", "k", "v", "PX", "100"]);
+    assert!(!watched_exec(&mut client, "k", || std::thread::sleep(Duration::from_millis(300))));
+}
+
+fn exec_get(client: &mut Client) -> Resp {
+    client.cmd(&["MULTI"]);
+    client.cmd(&["GET", "k"]);
+    client.cmd(&["EXEC"])
+}
+
+#[test]
+fn unwatch_and_finished_transactions_forget_watches() {
+    let server = Server::start();
+    let (mut client, mut other) = (server.client(), server.client());
+    client.cmd(&["WATCH", "k"]);
+    other.cmd(&["SET", "k", "1"]);
+    client.cmd(&["UNWATCH"]);
+    assert_eq!(exec_get(&mut client), Resp::Array(vec![bulk("1")]));
+    
+    // An aborted EXEC
+    client.cmd(&["WATCH", "k"]);
+    other.cmd(&["SET", "k", "2"]);
+    assert_eq!(exec_get(&mut client), Resp::Nil);
+    other.cmd(&["SET", "k", "3"]);
+    assert_eq!(exec_get(&mut client), Resp::Array(vec![bulk("3")]));
+    
+    // An EXEC refused for a bad queued command
+    client.cmd(&["WATCH", "k"]);
+    client.cmd(&["MULTI"]);
+    client.cmd(&["NOPE"]);
+    assert!(matches!(client.cmd(&["EXEC"]), Resp::Error(e) if e.starts_with("EXECABORT")));
+    other.cmd(&["SET", "k", "4"]);
+    assert_eq!(exec_get(&mut client), Resp::Array(vec![bulk("4")]));
+    
+    // DISCARD
+    client.cmd(&["WATCH", "k"]);
+    client.cmd(&["MULTI"]);
+    client.cmd(&["DISCARD"]);
+    other.cmd(&["SET", "k", "5"]);
+    assert_eq!(exec_get(&mut client), Resp::Array(vec![bulk("5")]));
+}